@@ -0,0 +1,507 @@
+use std::{
+    io, mem,
+    net::IpAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    extract::State,
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    routing::{get, post},
+    Json, Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::{
+    crypto::ring::default_provider,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    ClientConfig, RootCertStore, ServerConfig,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::{net::TcpListener, task::spawn_blocking};
+use anyhow::{anyhow, bail};
+
+use crate::{
+    logging::*, res::parse_allow_range, AgentOpts, Backend, Error,
+    InstalledRule, LocalIps, Result, Rule,
+};
+
+pub const AGENT_STATE_PATH: &str = "/v1/state";
+pub const AGENT_RULES_PATH: &str = "/v1/rules";
+const AGENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What the agent reports about the host it manages.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AgentState {
+    pub config_hash: String,
+    pub local_ips: LocalIps,
+    pub rules: Vec<InstalledRule>,
+}
+
+/// A set of rule changes the agent applies in a single commit.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RuleSetUpdate {
+    pub additions: Vec<Rule>,
+    pub deletions: Vec<String>,
+    pub teardown: bool,
+}
+
+impl RuleSetUpdate {
+    fn is_empty(&self) -> bool {
+        self.additions.is_empty()
+            && self.deletions.is_empty()
+            && !self.teardown
+    }
+
+    /// Checks every added rule before any of it reaches a ruleset.
+    fn validate(&self) -> anyhow::Result<()> {
+        self.additions.iter().try_for_each(validate_rule)
+    }
+}
+
+/// Checks the fields of a rule a client could otherwise smuggle lines into
+/// the iptables-restore input through.
+fn validate_rule(rule: &Rule) -> anyhow::Result<()> {
+    rule.dest_addr
+        .parse::<IpAddr>()
+        .map_err(|_| anyhow!("invalid destination: {}", rule.dest_addr))?;
+    let name = &rule.interface.name;
+    let name_char = |c: char| c.is_ascii_alphanumeric() || "-_.@+".contains(c);
+    if name.is_empty()
+        || name.len() > 15
+        || name.starts_with('-')
+        || !name.chars().all(name_char)
+    {
+        bail!("invalid interface name: {name}");
+    }
+    if let Some(ranges) = &rule.allow_range {
+        parse_allow_range(ranges)?;
+    }
+    let hash_char = |c: char| c.is_ascii_alphanumeric() || c == ':';
+    if !rule.rule_hash.chars().all(hash_char) {
+        bail!("invalid rule hash: {}", rule.rule_hash);
+    }
+    let comment = rule.comment.as_deref().unwrap_or_default();
+    if comment.chars().any(char::is_control) {
+        bail!("control character in the comment: {comment:?}");
+    }
+    Ok(())
+}
+
+/// Talks to an `epok-agent` on the host, which applies the rules itself:
+/// changes are staged locally and sent as one update on commit.
+pub struct AgentBackend {
+    url: String,
+    token: String,
+    http: ureq::Agent,
+    state: AgentState,
+    pending: RuleSetUpdate,
+}
+
+impl Backend for AgentBackend {
+    fn read_state(&mut self) -> Result<Vec<InstalledRule>> {
+        let request = self.request("GET", AGENT_STATE_PATH);
+        self.state = fetch(request.call())?;
+        Ok(self.state.rules.clone())
+    }
+
+    fn apply_rules(
+        &mut self,
+        rules: impl IntoIterator<Item = Rule>,
+    ) -> Result<()> {
+        self.pending.additions.extend(rules);
+        Ok(())
+    }
+
+    fn delete_rules(
+        &mut self,
+        rule_ids: impl IntoIterator<Item = String>,
+    ) -> Result<()> {
+        self.pending.deletions.extend(rule_ids);
+        Ok(())
+    }
+
+    fn teardown(&mut self) -> Result<()> {
+        self.pending.teardown = true;
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let update = mem::take(&mut self.pending);
+        let request = self.request("POST", AGENT_RULES_PATH);
+        self.state = fetch(request.send_json(update))
+            .map_err(|e| Error::BackendError(Box::new(e)))?;
+        Ok(())
+    }
+
+    fn can_install(&self, rule: &Rule) -> bool {
+        rule.interface.name != "lo"
+            || self.state.local_ips.get(rule.family).is_some()
+    }
+
+    fn config_hash(&self) -> String { self.state.config_hash.to_owned() }
+}
+
+impl AgentBackend {
+    pub fn new(agent_opts: AgentOpts) -> Result<Self> {
+        let mut http = ureq::AgentBuilder::new().timeout(AGENT_TIMEOUT);
+        if let Some(ca) = &agent_opts.agent_ca {
+            let tls =
+                client_tls(ca).map_err(|e| Error::AgentTlsError(e.into()))?;
+            http = http.tls_config(Arc::new(tls));
+        }
+        Ok(Self {
+            url: agent_opts
+                .agent_url
+                .expect("the agent backend requires an agent URL")
+                .trim_end_matches('/')
+                .to_owned(),
+            token: agent_opts
+                .agent_token
+                .expect("the agent backend requires an agent token"),
+            http: http.build(),
+            state: AgentState::default(),
+            pending: RuleSetUpdate::default(),
+        })
+    }
+
+    /// The addresses of the host, as of the last answer of the agent.
+    pub fn local_ips(&self) -> &LocalIps { &self.state.local_ips }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        self.http
+            .request(method, &format!("{}{path}", self.url))
+            .set(AUTHORIZATION.as_str(), &format!("Bearer {}", self.token))
+    }
+}
+
+/// A TLS client that only trusts the CA certificates in the PEM file at
+/// `ca`.
+fn client_tls(ca: &Path) -> anyhow::Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(ca)? {
+        roots.add(cert?)?;
+    }
+    Ok(ClientConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+/// The TLS setup of the agent API, from the PEM files holding its
+/// certificate chain and private key.
+pub fn server_tls(cert: &Path, key: &Path) -> anyhow::Result<RustlsConfig> {
+    let certs = CertificateDer::pem_file_iter(cert)?
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(key)?;
+    let config =
+        ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+    Ok(RustlsConfig::from_config(Arc::new(config)))
+}
+
+/// Serves `router` on `listener`, over TLS when given a `tls` setup.
+pub async fn serve(
+    listener: std::net::TcpListener,
+    router: Router,
+    tls: Option<RustlsConfig>,
+) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    match tls {
+        Some(tls) => {
+            axum_server::from_tcp_rustls(listener, tls)
+                .serve(router.into_make_service())
+                .await
+        }
+        None => axum::serve(TcpListener::from_std(listener)?, router).await,
+    }
+}
+
+fn fetch<T: DeserializeOwned>(
+    response: std::result::Result<ureq::Response, ureq::Error>,
+) -> Result<T> {
+    let json = match response {
+        Ok(response) => response.into_json().map_err(ureq::Error::from),
+        Err(e) => Err(e),
+    };
+    json.map_err(|e| Error::AgentError(Box::new(e)))
+}
+
+/// The agent side: serves the state of `backend` and applies the updates
+/// sent by an [`AgentBackend`], for clients presenting `token`.
+pub fn router<B>(backend: B, local_ips: LocalIps, token: String) -> Router
+where
+    B: Backend + Send + 'static,
+{
+    let backend = Mutex::new(backend);
+    let agent = Arc::new(Agent { backend, local_ips, token });
+    Router::new()
+        .route(AGENT_STATE_PATH, get(get_state::<B>))
+        .route(AGENT_RULES_PATH, post(post_rules::<B>))
+        .with_state(agent)
+}
+
+struct Agent<B> {
+    backend: Mutex<B>,
+    local_ips: LocalIps,
+    token: String,
+}
+
+impl<B: Backend> Agent<B> {
+    /// Compares the bearer token in constant time.
+    fn authorize(&self, headers: &HeaderMap) -> Result<(), StatusCode> {
+        let token = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        let mismatch = token.len() != self.token.len()
+            || token
+                .bytes()
+                .zip(self.token.bytes())
+                .fold(0, |acc, (a, b)| acc | (a ^ b))
+                != 0;
+        match mismatch {
+            true => Err(StatusCode::UNAUTHORIZED),
+            false => Ok(()),
+        }
+    }
+
+    fn state(&self, backend: &mut B) -> Result<AgentState> {
+        Ok(AgentState {
+            rules: backend.read_state()?,
+            config_hash: backend.config_hash(),
+            local_ips: self.local_ips.clone(),
+        })
+    }
+
+    fn update(&self, update: RuleSetUpdate) -> Result<AgentState> {
+        let mut backend = self.backend.lock().expect("agent lock poisoned");
+        info!(
+            "applying {} addition(s) and {} deletion(s)",
+            update.additions.len(),
+            update.deletions.len()
+        );
+        backend.read_state()?;
+        // the operator only sees the rules, not what they rely on
+        backend.repair_setup();
+        backend.apply_rules(update.additions)?;
+        backend.delete_rules(update.deletions)?;
+        if update.teardown {
+            backend.teardown()?;
+        }
+        backend.commit()?;
+        self.state(&mut backend)
+    }
+}
+
+async fn get_state<B: Backend + Send + 'static>(
+    State(agent): State<Arc<Agent<B>>>,
+    headers: HeaderMap,
+) -> Result<Json<AgentState>, StatusCode> {
+    agent.authorize(&headers)?;
+    let state = spawn_blocking(move || {
+        let mut backend = agent.backend.lock().expect("agent lock poisoned");
+        agent.state(&mut backend)
+    });
+    match state.await {
+        Ok(Ok(state)) => Ok(Json(state)),
+        Ok(Err(e)) => {
+            warn!("{e}");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+async fn post_rules<B: Backend + Send + 'static>(
+    State(agent): State<Arc<Agent<B>>>,
+    headers: HeaderMap,
+    Json(update): Json<RuleSetUpdate>,
+) -> Result<Json<AgentState>, (StatusCode, String)> {
+    agent.authorize(&headers).map_err(|status| (status, String::new()))?;
+    if let Err(e) = update.validate() {
+        warn!("rejecting the update: {e}");
+        return Err((StatusCode::BAD_REQUEST, e.to_string()));
+    }
+    match spawn_blocking(move || agent.update(update)).await {
+        Ok(Ok(state)) => Ok(Json(state)),
+        Ok(Err(e)) => {
+            warn!("{e}");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+        }
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use tempfile::TempDir;
+    use tokio::runtime::Runtime;
+
+    use super::*;
+    use crate::{
+        FileBackend, FileOpts, Interface, IpFamily, LocalExecutor, PortSpec,
+    };
+
+    fn serve(
+        runtime: &Runtime,
+        router: Router,
+        tls: Option<RustlsConfig>,
+    ) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")
+            .expect("should bind a loopback port");
+        let scheme = if tls.is_some() { "https" } else { "http" };
+        let url = format!("{scheme}://{}", listener.local_addr().unwrap());
+        runtime.spawn(super::serve(listener, router, tls));
+        url
+    }
+
+    /// An agent writing the rules to a file, along with the directory of
+    /// the file, its path and the URL the agent is served on.
+    fn agent(
+        runtime: &Runtime,
+        tls: Option<RustlsConfig>,
+    ) -> (TempDir, PathBuf, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("epok.rules");
+        let host = FileBackend::new(
+            LocalExecutor,
+            FileOpts { output_path: Some(path.clone()), ..Default::default() },
+            LocalIps::default(),
+            None,
+        );
+        let router = router(host, LocalIps::default(), "s3cr3t".into());
+        (dir, path, serve(runtime, router, tls))
+    }
+
+    fn client(url: &str, token: &str) -> AgentBackend {
+        AgentBackend::new(AgentOpts {
+            agent_url: Some(url.to_owned()),
+            agent_token: Some(token.to_owned()),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn rule(interface: &str) -> Rule {
+        Rule {
+            dest_addr: "10.0.0.1".to_owned(),
+            family: IpFamily::V4,
+            allow_range: None,
+            port_spec: PortSpec::new_tcp(80, 30080),
+            interface: Interface::new(interface),
+            nth: 0,
+            out_of: 1,
+            comment: Some("service: default/foo".to_owned()),
+            rule_hash: format!("service::{interface}"),
+        }
+    }
+
+    #[test]
+    fn it_applies_rules_through_the_agent() {
+        let runtime = Runtime::new().unwrap();
+        let (_dir, path, url) = agent(&runtime, None);
+
+        let mut backend = client(&url, "s3cr3t");
+        assert!(backend.read_state().unwrap().is_empty());
+        assert!(!backend.can_install(&rule("lo")));
+
+        backend.apply_rules([rule("eth0")]).unwrap();
+        backend.commit().unwrap();
+
+        let installed = backend.read_state().unwrap();
+        assert_eq!(installed.len(), 1);
+        assert_eq!(
+            installed[0].rule_id,
+            rule("eth0").rule_id(&backend.config_hash())
+        );
+        assert!(fs::read_to_string(&path).unwrap().contains("-i eth0"));
+
+        backend.delete_rules([installed[0].rule_id.clone()]).unwrap();
+        backend.commit().unwrap();
+        assert!(backend.read_state().unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_talks_to_the_agent_over_tls() {
+        let runtime = Runtime::new().unwrap();
+        let certs = tempfile::tempdir().unwrap();
+        let (cert, key) =
+            (certs.path().join("tls.crt"), certs.path().join("tls.key"));
+        let issued =
+            rcgen::generate_simple_self_signed(["127.0.0.1".to_owned()])
+                .unwrap();
+        fs::write(&cert, issued.cert.pem()).unwrap();
+        fs::write(&key, issued.signing_key.serialize_pem()).unwrap();
+        let tls = server_tls(&cert, &key).unwrap();
+        let (_dir, path, url) = agent(&runtime, Some(tls));
+        assert!(url.starts_with("https://"));
+
+        let mut backend = AgentBackend::new(AgentOpts {
+            agent_url: Some(url.clone()),
+            agent_token: Some("s3cr3t".to_owned()),
+            agent_ca: Some(cert),
+        })
+        .unwrap();
+        backend.apply_rules([rule("eth0")]).unwrap();
+        backend.commit().unwrap();
+        assert!(fs::read_to_string(&path).unwrap().contains("-i eth0"));
+
+        // the certificate is no public one
+        let err = client(&url, "s3cr3t").read_state().unwrap_err();
+        assert!(matches!(err, Error::AgentError(_)), "{err}");
+    }
+
+    #[test]
+    fn it_rejects_unknown_tokens() {
+        let runtime = Runtime::new().unwrap();
+        let (_dir, path, url) = agent(&runtime, None);
+
+        let mut backend = client(&url, "guess");
+        backend.apply_rules([rule("eth0")]).unwrap();
+        let err = backend.commit().unwrap_err();
+        assert!(err.to_string().contains("401"), "{err}");
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn it_rejects_rules_that_would_inject_lines() {
+        let runtime = Runtime::new().unwrap();
+        let (_dir, path, url) = agent(&runtime, None);
+
+        let injections = [
+            Rule {
+                comment: Some("foo\nCOMMIT\n*filter\n-F".to_owned()),
+                ..rule("eth0")
+            },
+            Rule {
+                dest_addr: "10.0.0.1 -j ACCEPT".to_owned(),
+                ..rule("eth0")
+            },
+            Rule {
+                allow_range: Some("0.0.0.0/0\nCOMMIT".to_owned()),
+                ..rule("eth0")
+            },
+            Rule {
+                interface: Interface::new("eth0 -j ACCEPT"),
+                ..rule("eth0")
+            },
+        ];
+        for injection in injections {
+            let mut backend = client(&url, "s3cr3t");
+            backend.apply_rules([injection]).unwrap();
+            let err = backend.commit().unwrap_err();
+            assert!(err.to_string().contains("400"), "{err}");
+        }
+        assert!(!path.exists());
+    }
+}
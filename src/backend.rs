@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+
+use crate::{
+    get_ip, AgentBackend, AgentOpts, Backend, BackendKind, BatchOpts,
+    CommandOpts, Error, Executor, FileBackend, FileOpts, InstalledRule,
+    IpFamily, IptablesBackend, JumpPosition, LocalIps, NftablesBackend,
+    ProxyBackend, Result, Rule,
+};
+
+pub enum AnyBackend {
+    Iptables(IptablesBackend),
+    Nftables(NftablesBackend),
+    Proxy(ProxyBackend),
+    File(FileBackend),
+    Agent(AgentBackend),
+    Pending(PendingBackend),
+}
+
+/// A backend that could not be set up yet, e.g. as its host was down: it
+/// gets set up again whenever it is used, until that works.
+pub struct PendingBackend {
+    setup: Box<dyn FnMut() -> Result<AnyBackend> + Send>,
+}
+
+impl PendingBackend {
+    pub fn new(
+        setup: impl FnMut() -> Result<AnyBackend> + Send + 'static,
+    ) -> Self {
+        Self { setup: Box::new(setup) }
+    }
+}
+
+/// What the backends need to know about the host they manage.
+#[derive(Debug, Clone, Default)]
+pub struct HostConfig {
+    pub local_ips: LocalIps,
+    pub extra_ips: Option<String>,
+    pub jump_position: JumpPosition,
+    /// Addresses of the forwarded interfaces (proxy backend only)
+    pub interface_ips: HashMap<String, LocalIps>,
+    pub command_opts: CommandOpts,
+}
+
+impl LocalIps {
+    /// Looks up the addresses of the external interface, which the
+    /// extra internal IPs are added to.
+    pub fn detect(
+        external_interface: Option<&str>,
+        extra_ips: Option<&str>,
+        executor: &impl Executor,
+        command_opts: &CommandOpts,
+    ) -> Result<Self> {
+        let mut local_ips = match external_interface {
+            None => LocalIps::default(),
+            Some(iface) => {
+                let local_ips = LocalIps {
+                    v4: get_ip(iface, IpFamily::V4, executor, command_opts),
+                    v6: get_ip(iface, IpFamily::V6, executor, command_opts),
+                };
+                if local_ips.is_empty() {
+                    return Err(Error::NoInterfaceAddress(iface.to_owned()));
+                }
+                local_ips
+            }
+        };
+
+        for extra_ip in extra_ips.into_iter().flat_map(|ips| ips.split(',')) {
+            let local_ip = match IpFamily::of(extra_ip) {
+                IpFamily::V4 => &mut local_ips.v4,
+                IpFamily::V6 => &mut local_ips.v6,
+            };
+            if let Some(ip) = local_ip {
+                *ip = format!("{ip},{extra_ip}");
+            }
+        }
+        Ok(local_ips)
+    }
+}
+
+impl AnyBackend {
+    pub fn new(
+        kind: BackendKind,
+        executor: Box<dyn Executor>,
+        batch_opts: BatchOpts,
+        file_opts: FileOpts,
+        agent_opts: AgentOpts,
+        host: HostConfig,
+    ) -> Result<Self> {
+        Ok(match kind {
+            BackendKind::Iptables => Self::Iptables(IptablesBackend::new(
+                executor,
+                host.local_ips,
+                host.extra_ips,
+                host.jump_position,
+                host.command_opts,
+            )),
+            BackendKind::Nftables => Self::Nftables(NftablesBackend::new(
+                executor,
+                batch_opts,
+                host.local_ips,
+                host.extra_ips,
+                host.command_opts,
+            )),
+            BackendKind::Proxy => {
+                Self::Proxy(ProxyBackend::new(host.interface_ips))
+            }
+            BackendKind::File => Self::File(FileBackend::new(
+                executor,
+                file_opts,
+                host.local_ips,
+                host.extra_ips,
+            )),
+            BackendKind::Agent => Self::Agent(AgentBackend::new(agent_opts)?),
+        })
+    }
+
+    /// Tries to set a pending backend up, which then takes its place.
+    fn set_up(&mut self) -> Result<()> {
+        if let Self::Pending(pending) = self {
+            *self = (pending.setup)()?;
+        }
+        Ok(())
+    }
+}
+
+impl Backend for AnyBackend {
+    fn read_state(&mut self) -> Result<Vec<InstalledRule>> {
+        self.set_up()?;
+        match self {
+            Self::Iptables(b) => b.read_state(),
+            Self::Nftables(b) => b.read_state(),
+            Self::Proxy(b) => b.read_state(),
+            Self::File(b) => b.read_state(),
+            Self::Agent(b) => b.read_state(),
+            Self::Pending(_) => unreachable!("the backend is set up"),
+        }
+    }
+
+    fn apply_rules(
+        &mut self,
+        rules: impl IntoIterator<Item = Rule>,
+    ) -> Result<()> {
+        self.set_up()?;
+        match self {
+            Self::Iptables(b) => b.apply_rules(rules),
+            Self::Nftables(b) => b.apply_rules(rules),
+            Self::Proxy(b) => b.apply_rules(rules),
+            Self::File(b) => b.apply_rules(rules),
+            Self::Agent(b) => b.apply_rules(rules),
+            Self::Pending(_) => unreachable!("the backend is set up"),
+        }
+    }
+
+    fn delete_rules(
+        &mut self,
+        rule_ids: impl IntoIterator<Item = String>,
+    ) -> Result<()> {
+        self.set_up()?;
+        match self {
+            Self::Iptables(b) => b.delete_rules(rule_ids),
+            Self::Nftables(b) => b.delete_rules(rule_ids),
+            Self::Proxy(b) => b.delete_rules(rule_ids),
+            Self::File(b) => b.delete_rules(rule_ids),
+            Self::Agent(b) => b.delete_rules(rule_ids),
+            Self::Pending(_) => unreachable!("the backend is set up"),
+        }
+    }
+
+    fn config_hash(&self) -> String {
+        match self {
+            Self::Iptables(b) => b.config_hash(),
+            Self::Nftables(b) => b.config_hash(),
+            Self::Proxy(b) => b.config_hash(),
+            Self::File(b) => b.config_hash(),
+            Self::Agent(b) => b.config_hash(),
+            Self::Pending(_) => String::new(),
+        }
+    }
+
+    fn can_install(&self, rule: &Rule) -> bool {
+        match self {
+            Self::Iptables(b) => b.can_install(rule),
+            Self::Nftables(b) => b.can_install(rule),
+            Self::Proxy(b) => b.can_install(rule),
+            Self::File(b) => b.can_install(rule),
+            Self::Agent(b) => b.can_install(rule),
+            Self::Pending(_) => false,
+        }
+    }
+
+    fn repair_setup(&mut self) -> usize {
+        match self {
+            Self::Iptables(b) => b.repair_setup(),
+            Self::Nftables(b) => b.repair_setup(),
+            Self::Proxy(b) => b.repair_setup(),
+            Self::File(b) => b.repair_setup(),
+            Self::Agent(b) => b.repair_setup(),
+            Self::Pending(_) => 0,
+        }
+    }
+
+    fn teardown(&mut self) -> Result<()> {
+        self.set_up()?;
+        match self {
+            Self::Iptables(b) => b.teardown(),
+            Self::Nftables(b) => b.teardown(),
+            Self::Proxy(b) => b.teardown(),
+            Self::File(b) => b.teardown(),
+            Self::Agent(b) => b.teardown(),
+            Self::Pending(_) => unreachable!("the backend is set up"),
+        }
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        self.set_up()?;
+        match self {
+            Self::Iptables(b) => b.commit(),
+            Self::Nftables(b) => b.commit(),
+            Self::Proxy(b) => b.commit(),
+            Self::File(b) => b.commit(),
+            Self::Agent(b) => b.commit(),
+            Self::Pending(_) => unreachable!("the backend is set up"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_sets_up_pending_backends_once_it_works() {
+        let mut attempts = 0;
+        let mut backend =
+            AnyBackend::Pending(PendingBackend::new(move || {
+                attempts += 1;
+                match attempts {
+                    1 => Err(Error::NoInterfaceAddress("eth0".to_owned())),
+                    _ => Ok(AnyBackend::Proxy(ProxyBackend::new(
+                        HashMap::new(),
+                    ))),
+                }
+            }));
+
+        let err = backend.read_state().unwrap_err();
+        assert!(matches!(err, Error::NoInterfaceAddress(_)), "{err}");
+
+        assert!(backend.read_state().unwrap().is_empty());
+        backend.commit().unwrap();
+        assert!(matches!(backend, AnyBackend::Proxy(_)));
+    }
+}
@@ -0,0 +1,95 @@
+use std::{
+    net::{SocketAddr, TcpListener},
+    path::PathBuf,
+};
+
+use clap::{
+    crate_authors, crate_description, error::ErrorKind, CommandFactory, Parser,
+};
+use epok::*;
+
+#[derive(Parser, Debug)]
+#[clap(about = crate_description!(), author = crate_authors!("\n"))]
+pub struct Opts {
+    /// Address to serve the agent API on - anything but loopback requires
+    /// TLS
+    #[clap(long, env = "EPOK_AGENT_LISTEN", default_value = "127.0.0.1:7777")]
+    pub listen: SocketAddr,
+
+    /// PEM certificate chain to serve the agent API over TLS with
+    #[clap(long, env = "EPOK_AGENT_TLS_CERT", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the TLS certificate
+    #[clap(long, env = "EPOK_AGENT_TLS_KEY", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// Token the operator has to present
+    #[clap(long, env = "EPOK_AGENT_TOKEN", hide_env_values = true)]
+    pub token: String,
+
+    /// Internal services won't be reachable through this interface
+    #[clap(long, env = "EPOK_EXTERNAL_INTERFACE")]
+    pub external_interface: Option<String>,
+
+    /// Internal services will be reachable through these IPs
+    /// Format is: x.x.x.x/mask
+    #[clap(long, env = "EPOK_EXTRA_INTERNAL_IPS")]
+    pub extra_internal_ips: Option<String>,
+
+    /// Where to put the jumps into the EPOK-* chains
+    #[clap(
+        long,
+        value_enum,
+        env = "EPOK_JUMP_POSITION",
+        default_value_t = JumpPosition::Append
+    )]
+    pub jump_position: JumpPosition,
+
+    /// Print the firewall commands instead of executing them
+    #[clap(long, env = "EPOK_DRY_RUN")]
+    pub dry_run: bool,
+
+    #[clap(flatten)]
+    pub command_opts: CommandOpts,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    initialize_logging("EPOK_LOG_LEVEL");
+
+    let opts = Opts::parse();
+    // the token would travel in cleartext
+    let tls = match (&opts.tls_cert, &opts.tls_key) {
+        (Some(cert), Some(key)) => Some(agent::server_tls(cert, key)?),
+        _ if !opts.listen.ip().is_loopback() => Opts::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "--listen on anything but loopback requires --tls-cert and \
+                 --tls-key",
+            )
+            .exit(),
+        _ => None,
+    };
+    let executor = ExecutorOpts::Local.build(opts.dry_run, &opts.command_opts);
+    let local_ips = LocalIps::detect(
+        opts.external_interface.as_deref(),
+        opts.extra_internal_ips.as_deref(),
+        &executor,
+        &opts.command_opts,
+    )?;
+    let backend = IptablesBackend::new(
+        executor,
+        local_ips.clone(),
+        opts.extra_internal_ips,
+        opts.jump_position,
+        opts.command_opts,
+    );
+
+    let listener = TcpListener::bind(opts.listen)?;
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    info!("serving the agent API on {scheme}://{}", opts.listen);
+    let router = agent::router(backend, local_ips, opts.token);
+    agent::serve(listener, router, tls).await?;
+    Ok(())
+}
@@ -4,19 +4,48 @@ use epok::*;
 #[derive(Parser, Debug)]
 #[clap(about = crate_description!(), author = crate_authors!("\n"))]
 pub struct Opts {
+    /// Firewall backend whose rules should be removed
+    #[clap(
+        long,
+        value_enum,
+        env = "EPOK_BACKEND",
+        default_value_t = BackendKind::Iptables
+    )]
+    pub backend: BackendKind,
+
+    /// Print the firewall commands instead of executing them
+    #[clap(long, env = "EPOK_DRY_RUN")]
+    pub dry_run: bool,
+
     #[clap(flatten)]
     pub batch_opts: BatchOpts,
 
+    #[clap(flatten)]
+    pub file_opts: FileOpts,
+
+    #[clap(flatten)]
+    pub agent_opts: AgentOpts,
+
+    #[clap(flatten)]
+    pub command_opts: CommandOpts,
+
     #[clap(subcommand)]
-    pub executor: Executor,
+    pub executor: ExecutorOpts,
 }
 
 fn main() -> Result<()> {
     initialize_logging("EPOK_LOG_LEVEL");
 
     let opts = Opts::parse();
-    let backend =
-        IptablesBackend::new(opts.executor, opts.batch_opts, None, None);
+    let command_opts = opts.executor.command_opts(&opts.command_opts);
+    let backend = AnyBackend::new(
+        opts.backend,
+        opts.executor.build(opts.dry_run, &command_opts),
+        opts.batch_opts,
+        opts.file_opts,
+        opts.agent_opts,
+        HostConfig { command_opts, ..Default::default() },
+    )?;
     let operator = Operator::new(backend);
 
     warn!("deleting all rules");
@@ -1,6 +1,12 @@
-use clap::{Args, Parser};
+use std::path::PathBuf;
 
-use super::ARG_MAX;
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
+use serde::Deserialize;
+
+use super::{
+    ssh::SshSession, DryRun, Executor, LocalExecutor, Retrying, ARG_MAX,
+    LOCALHOST,
+};
 
 #[derive(Parser, Debug)]
 #[clap(
@@ -19,9 +25,23 @@ pub struct Opts {
         short = 'i',
         value_parser,
         env = "EPOK_INTERFACES",
-        display_order = 0
+        display_order = 0,
+        required_unless_present = "hosts"
+    )]
+    pub interfaces: Option<String>,
+
+    /// YAML file listing several hosts to forward on, each with its own
+    /// executor and interfaces (iptables and nftables backends)
+    #[clap(
+        long,
+        env = "EPOK_HOSTS",
+        conflicts_with_all = [
+            "interfaces",
+            "external_interface",
+            "extra_internal_ips"
+        ]
     )]
-    pub interfaces: String,
+    pub hosts: Option<PathBuf>,
 
     /// Internal services won't be reachable through this interface
     #[clap(long, env = "EPOK_EXTERNAL_INTERFACE")]
@@ -32,16 +52,242 @@ pub struct Opts {
     #[clap(long, env = "EPOK_EXTRA_INTERNAL_IPS")]
     pub extra_internal_ips: Option<String>,
 
+    /// Firewall backend used to install the forwarding rules
+    #[clap(
+        long,
+        value_enum,
+        env = "EPOK_BACKEND",
+        default_value_t = BackendKind::Iptables
+    )]
+    pub backend: BackendKind,
+
+    /// Where to put the jumps into the EPOK-* chains (iptables backend)
+    #[clap(
+        long,
+        value_enum,
+        env = "EPOK_JUMP_POSITION",
+        default_value_t = JumpPosition::Append
+    )]
+    pub jump_position: JumpPosition,
+
+    /// Print the firewall commands instead of executing them; read-only
+    /// commands still run
+    #[clap(long, env = "EPOK_DRY_RUN")]
+    pub dry_run: bool,
+
+    /// Comma-separated list of namespaces to watch services, pods and port
+    /// mappings in, all of them by default
+    #[clap(long, env = "EPOK_NAMESPACES", value_delimiter = ',')]
+    pub namespaces: Vec<String>,
+
+    /// Only watch the services and pods matching this label selector, e.g.
+    /// epok.getbetter.ro/enabled=true
+    #[clap(long, env = "EPOK_LABEL_SELECTOR")]
+    pub label_selector: Option<String>,
+
+    /// Claim the LoadBalancer services without a loadBalancerClass, or of
+    /// --load-balancer-class, forwarding their ports and reporting the
+    /// external interface addresses as their ingress
+    #[clap(long, env = "EPOK_LOAD_BALANCERS")]
+    pub load_balancers: bool,
+
+    /// The loadBalancerClass of the services to claim, e.g.
+    /// getbetter.ro/epok
+    #[clap(
+        long,
+        env = "EPOK_LOAD_BALANCER_CLASS",
+        requires = "load_balancers"
+    )]
+    pub load_balancer_class: Option<String>,
+
+    /// Also forward the ports of ExternalPortMapping resources - requires
+    /// their CRD to be installed
+    #[clap(long, env = "EPOK_PORT_MAPPINGS")]
+    pub port_mappings: bool,
+
+    /// Report the forwarded ports in the `epok.getbetter.ro/status`
+    /// annotation and in Events of the services and pods
+    #[clap(
+        long,
+        env = "EPOK_PUBLISH_STATUS",
+        action = ArgAction::Set,
+        default_value_t = true
+    )]
+    pub publish_status: bool,
+
+    /// Seconds between full resyncs that repair drifted rules, 0 disables
+    #[clap(long, env = "EPOK_RESYNC_INTERVAL", default_value = "60")]
+    pub resync_interval: u64,
+
+    /// Seconds between retries of a failed reconcile
+    #[clap(long, env = "EPOK_RETRY_INTERVAL", default_value = "10")]
+    pub retry_interval: u64,
+
     #[clap(flatten)]
     pub batch_opts: BatchOpts,
 
+    #[clap(flatten)]
+    pub file_opts: FileOpts,
+
+    #[clap(flatten)]
+    pub agent_opts: AgentOpts,
+
+    #[clap(flatten)]
+    pub command_opts: CommandOpts,
+
+    #[clap(flatten)]
+    pub leader_opts: LeaderOpts,
+
+    /// Required unless the executors come from `--hosts`
     #[clap(subcommand)]
-    pub executor: Executor,
+    pub command: Option<EpokCommand>,
 }
 
-#[derive(Parser, Debug)]
+#[derive(Subcommand, Debug)]
+pub enum EpokCommand {
+    #[clap(flatten)]
+    Run(ExecutorOpts),
+    /// Check the hosts and the cluster permissions, print a report and exit
+    Doctor {
+        #[clap(subcommand)]
+        executor: Option<ExecutorOpts>,
+    },
+}
+
+/// A host listed in the `--hosts` file.
+#[derive(Debug, Deserialize)]
+pub struct HostOpts {
+    pub name: String,
+    /// Interfaces to forward packets from
+    pub interfaces: Vec<String>,
+    /// Internal services won't be reachable through this interface
+    pub external_interface: Option<String>,
+    /// Internal services will be reachable through these IPs
+    pub extra_internal_ips: Option<String>,
+    /// `local`, or `ssh:`/`netns:` followed by the executor options
+    #[serde(with = "serde_yaml::with::singleton_map")]
+    pub executor: ExecutorOpts,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackendKind {
+    /// Append rules to the nat table through `iptables`
+    Iptables,
+    /// Manage a dedicated `ip epok` table through `nft`
+    Nftables,
+    /// Forward in userspace without touching netfilter - requires running
+    /// epok on the host itself
+    Proxy,
+    /// Write the ruleset to `--output-path` and leave applying it to the
+    /// host's configuration management
+    File,
+    /// Send the rules to an `epok-agent` running on the host
+    Agent,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum JumpPosition {
+    /// Insert the jump at the top of the built-in chain
+    Insert,
+    /// Append the jump to the end of the built-in chain
+    #[default]
+    Append,
+}
+
+/// How the firewall commands gain root privileges on the host.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Privilege {
+    /// Run the commands as they are, e.g. when epok runs as root
+    None,
+    #[default]
+    Sudo,
+    Doas,
+    /// Prefix the commands with `--privilege-command`
+    Custom,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct CommandOpts {
+    /// How the firewall commands gain root privileges
+    #[clap(
+        long,
+        value_enum,
+        env = "EPOK_PRIVILEGE",
+        default_value_t = Privilege::Sudo
+    )]
+    pub privilege: Privilege,
+
+    /// Command to prefix the firewall commands with (custom privilege)
+    #[clap(
+        long,
+        env = "EPOK_PRIVILEGE_COMMAND",
+        required_if_eq("privilege", "custom")
+    )]
+    pub privilege_command: Option<String>,
+
+    /// The iptables binary, e.g. /usr/sbin/iptables-legacy
+    #[clap(long, env = "EPOK_IPTABLES_BINARY", default_value = "iptables")]
+    pub iptables_binary: String,
+
+    /// The iptables-save binary [default: <IPTABLES_BINARY>-save]
+    #[clap(long, env = "EPOK_IPTABLES_SAVE_BINARY")]
+    pub iptables_save_binary: Option<String>,
+
+    /// The iptables-restore binary [default: <IPTABLES_BINARY>-restore]
+    #[clap(long, env = "EPOK_IPTABLES_RESTORE_BINARY")]
+    pub iptables_restore_binary: Option<String>,
+
+    /// The ip6tables binary, e.g. /usr/sbin/ip6tables-legacy
+    #[clap(long, env = "EPOK_IP6TABLES_BINARY", default_value = "ip6tables")]
+    pub ip6tables_binary: String,
+
+    /// The ip6tables-save binary [default: <IP6TABLES_BINARY>-save]
+    #[clap(long, env = "EPOK_IP6TABLES_SAVE_BINARY")]
+    pub ip6tables_save_binary: Option<String>,
+
+    /// The ip6tables-restore binary [default: <IP6TABLES_BINARY>-restore]
+    #[clap(long, env = "EPOK_IP6TABLES_RESTORE_BINARY")]
+    pub ip6tables_restore_binary: Option<String>,
+
+    /// The nft binary (nftables backend)
+    #[clap(long, env = "EPOK_NFT_BINARY", default_value = "nft")]
+    pub nft_binary: String,
+
+    /// The ip binary the interface addresses are looked up with
+    #[clap(long, env = "EPOK_IP_BINARY", default_value = "ip")]
+    pub ip_binary: String,
+
+    /// Seconds a command may run before it gets killed (0 to disable)
+    #[clap(long, env = "EPOK_COMMAND_TIMEOUT", default_value = "30")]
+    pub command_timeout: u64,
+
+    /// How many times to retry a command that failed transiently
+    #[clap(long, env = "EPOK_COMMAND_RETRIES", default_value = "3")]
+    pub command_retries: usize,
+}
+
+impl Default for CommandOpts {
+    fn default() -> Self {
+        Self {
+            privilege: Privilege::default(),
+            privilege_command: None,
+            iptables_binary: "iptables".to_owned(),
+            iptables_save_binary: None,
+            iptables_restore_binary: None,
+            ip6tables_binary: "ip6tables".to_owned(),
+            ip6tables_save_binary: None,
+            ip6tables_restore_binary: None,
+            nft_binary: "nft".to_owned(),
+            ip_binary: "ip".to_owned(),
+            command_timeout: 30,
+            command_retries: 3,
+        }
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
 pub struct BatchOpts {
-    /// Batch the execution of iptables commands
+    /// Batch the execution of firewall commands (nftables backend)
     #[clap(long, env = "EPOK_BATCH_COMMANDS", default_value = "true")]
     pub batch_commands: bool,
 
@@ -50,18 +296,149 @@ pub struct BatchOpts {
     pub batch_size: usize,
 }
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
+pub struct LeaderOpts {
+    /// Only reconcile while holding a coordination.k8s.io Lease, so that
+    /// several replicas can run side by side
+    #[clap(long, env = "EPOK_LEADER_ELECTION")]
+    pub leader_election: bool,
+
+    /// Name of the Lease (leader election)
+    #[clap(long, env = "EPOK_LEASE_NAME", default_value = "epok")]
+    pub lease_name: String,
+
+    /// Namespace of the Lease, the one of the service account by default
+    /// (leader election)
+    #[clap(long, env = "EPOK_LEASE_NAMESPACE")]
+    pub lease_namespace: Option<String>,
+
+    /// Who holds the Lease, the host name by default (leader election)
+    #[clap(long, env = "EPOK_LEASE_IDENTITY")]
+    pub lease_identity: Option<String>,
+
+    /// Seconds the Lease stays valid without being renewed (leader
+    /// election)
+    #[clap(long, env = "EPOK_LEASE_DURATION", default_value = "15")]
+    pub lease_duration: u64,
+
+    /// Seconds between renewals of the Lease, below --lease-duration
+    /// (leader election)
+    #[clap(long, env = "EPOK_LEASE_RENEW_INTERVAL", default_value = "5")]
+    pub lease_renew_interval: u64,
+}
+
+#[derive(Parser, Debug, Clone, Default)]
+pub struct FileOpts {
+    /// Where to write the ruleset (file backend)
+    #[clap(long, env = "EPOK_OUTPUT_PATH", required_if_eq("backend", "file"))]
+    pub output_path: Option<PathBuf>,
+
+    /// Format of the written ruleset (file backend)
+    #[clap(
+        long,
+        value_enum,
+        env = "EPOK_OUTPUT_FORMAT",
+        default_value_t = OutputFormat::IptablesRestore
+    )]
+    pub output_format: OutputFormat,
+
+    /// Command to run through the executor after the ruleset changed (file
+    /// backend)
+    #[clap(long, env = "EPOK_POST_WRITE_HOOK")]
+    pub post_write_hook: Option<String>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Rules for `iptables-restore --noflush`, IPv6 ones in `<path>.v6`
+    #[default]
+    IptablesRestore,
+    /// A script for `nft -f` replacing the epok tables
+    Nft,
+}
+
+#[derive(Parser, Debug, Clone, Default)]
+pub struct AgentOpts {
+    /// Base URL of the epok-agent on the host (agent backend)
+    #[clap(long, env = "EPOK_AGENT_URL", required_if_eq("backend", "agent"))]
+    pub agent_url: Option<String>,
+
+    /// Token shared with the epok-agent (agent backend)
+    #[clap(
+        long,
+        env = "EPOK_AGENT_TOKEN",
+        hide_env_values = true,
+        required_if_eq("backend", "agent")
+    )]
+    pub agent_token: Option<String>,
+
+    /// PEM certificate of the CA the TLS certificate of the epok-agent is
+    /// checked against, instead of the system roots (agent backend)
+    #[clap(long, env = "EPOK_AGENT_CA")]
+    pub agent_ca: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug, Deserialize)]
 #[clap(long_about = "En taro Adun")]
-pub enum Executor<Ssh: Args = SshHost> {
+#[serde(rename_all = "lowercase")]
+pub enum ExecutorOpts<Ssh: Args = SshHost> {
     /// Execute commands locally - use this executor when running epok directly
     /// on the host machine.
     Local,
     /// Execute commands through ssh - use this executor when running epok
     /// inside the Kubernetes cluster.
     Ssh(Ssh),
+    /// Execute commands inside a network namespace - use this executor when
+    /// running epok as a privileged pod on the host machine.
+    Netns(Netns),
 }
 
-#[derive(Parser, Debug)]
+impl ExecutorOpts {
+    /// Builds the executor, retrying commands as `command_opts` says and
+    /// wrapped in a dry-run one when `dry_run` is set.
+    pub fn build(
+        self,
+        dry_run: bool,
+        command_opts: &CommandOpts,
+    ) -> Box<dyn Executor> {
+        let inner: Box<dyn Executor> = match self {
+            Self::Local => Box::new(LocalExecutor),
+            Self::Ssh(ssh_host) => Box::new(ssh_host),
+            Self::Netns(netns) => Box::new(netns),
+        };
+        let executor = Box::new(Retrying {
+            inner,
+            timeout: command_opts.timeout(),
+            retries: command_opts.command_retries,
+        });
+        match dry_run {
+            true => Box::new(DryRun(executor)),
+            false => executor,
+        }
+    }
+
+    /// The command options as they apply to this executor: inside a
+    /// network namespace we are already privileged, and the privilege
+    /// command is seldom installed in the epok image.
+    pub fn command_opts(&self, command_opts: &CommandOpts) -> CommandOpts {
+        let privilege = match self {
+            Self::Netns(_) => Privilege::None,
+            _ => command_opts.privilege,
+        };
+        CommandOpts { privilege, ..command_opts.clone() }
+    }
+
+    /// What to call the host the commands run on.
+    pub fn host_name(&self) -> &str {
+        match self {
+            Self::Local => LOCALHOST,
+            Self::Ssh(ssh_host) => &ssh_host.host,
+            Self::Netns(netns) => &netns.netns,
+        }
+    }
+}
+
+#[derive(Parser, Debug, Deserialize)]
 pub struct SshHost {
     #[clap(short = 'H', long, value_parser, env = "EPOK_SSH_HOST")]
     pub host: String,
@@ -72,7 +449,125 @@ pub struct SshHost {
         env = "EPOK_SSH_PORT",
         default_value = "22"
     )]
+    #[serde(default = "default_port")]
     pub port: u16,
     #[clap(short = 'k', long = "key", value_parser, env = "EPOK_SSH_KEY")]
+    #[serde(rename = "key")]
     pub key_path: String,
+    /// Seconds between keepalive probes on the ssh connection
+    #[clap(long, env = "EPOK_SSH_KEEPALIVE", default_value = "15")]
+    #[serde(default = "default_keepalive")]
+    pub keepalive: u32,
+    /// The ssh client to open the connection with
+    #[clap(long, env = "EPOK_SSH_BINARY", default_value = "ssh")]
+    #[serde(default = "default_ssh_binary")]
+    pub ssh_binary: String,
+    /// known_hosts file to verify the host key against, e.g. mounted from
+    /// a Secret
+    #[clap(
+        long,
+        env = "EPOK_SSH_KNOWN_HOSTS",
+        conflicts_with = "host_key_fingerprint"
+    )]
+    pub known_hosts: Option<PathBuf>,
+    /// Pin the host key to this fingerprint, as printed by
+    /// `ssh-keygen -l` (SHA256:...)
+    #[clap(long, env = "EPOK_SSH_HOST_KEY_FINGERPRINT")]
+    pub host_key_fingerprint: Option<String>,
+    #[clap(skip)]
+    #[serde(skip)]
+    pub(crate) session: SshSession,
+}
+
+#[derive(Parser, Debug, Deserialize)]
+pub struct Netns {
+    /// The network namespace to enter: the PID of a process in it, the name
+    /// of an `ip netns` namespace, or the path of a namespace file
+    #[clap(long, env = "EPOK_NETNS", default_value = "1")]
+    #[serde(default = "default_netns")]
+    pub netns: String,
+    /// The nsenter binary to enter the namespace with
+    #[clap(long, env = "EPOK_NSENTER_BINARY", default_value = "nsenter")]
+    #[serde(default = "default_nsenter_binary")]
+    pub nsenter_binary: String,
+}
+
+// the --hosts file defaults, same as on the command line
+fn default_port() -> u16 { 22 }
+fn default_keepalive() -> u32 { 15 }
+fn default_ssh_binary() -> String { "ssh".to_owned() }
+fn default_netns() -> String { "1".to_owned() }
+fn default_nsenter_binary() -> String { "nsenter".to_owned() }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cmd;
+
+    #[test]
+    fn it_parses_a_hosts_file() {
+        let hosts: Vec<HostOpts> = serde_yaml::from_str(
+            r#"
+- name: hv1
+  interfaces: [eth0, eth1]
+  external_interface: eth0
+  executor:
+    ssh:
+      host: epok@hv1
+      key: /opt/secrets/private.key
+- name: hv2
+  interfaces: [eth0]
+  executor: local
+- name: hv3
+  interfaces: [eth0]
+  executor:
+    netns: {}
+"#,
+        )
+        .unwrap();
+
+        let ExecutorOpts::Ssh(ssh_host) = &hosts[0].executor else {
+            panic!("expected an ssh executor: {:?}", hosts[0].executor);
+        };
+        assert_eq!(ssh_host.host, "epok@hv1");
+        assert_eq!(ssh_host.port, 22);
+        assert_eq!(ssh_host.keepalive, 15);
+        assert_eq!(hosts[0].external_interface.as_deref(), Some("eth0"));
+        assert!(matches!(hosts[1].executor, ExecutorOpts::Local));
+        assert_eq!(hosts[2].executor.host_name(), "1");
+    }
+
+    #[test]
+    fn it_leaves_the_host_options_to_the_hosts_file() {
+        for option in [
+            "--interfaces=eth0",
+            "--external-interface=eth0",
+            "--extra-internal-ips=10.0.0.1",
+        ] {
+            let err =
+                Opts::try_parse_from(["epok", "--hosts=hosts.yaml", option])
+                    .unwrap_err();
+            assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+        }
+    }
+
+    #[test]
+    fn it_runs_netns_commands_unprivileged() {
+        let netns = ExecutorOpts::Netns(Netns {
+            netns: "1".to_owned(),
+            nsenter_binary: "nsenter".to_owned(),
+        });
+        for (privilege, privilege_command) in [
+            (Privilege::Sudo, None),
+            (Privilege::Doas, None),
+            (Privilege::Custom, Some("sudo -n".to_owned())),
+        ] {
+            let command_opts = CommandOpts::parse_from(["epok"]);
+            let command_opts =
+                CommandOpts { privilege, privilege_command, ..command_opts };
+            let cmd = Cmd::new("iptables-save").args(["-t", "nat"]);
+            let cmd = netns.command_opts(&command_opts).privileged(cmd);
+            assert_eq!(cmd.to_string(), "iptables-save -t nat");
+        }
+    }
 }
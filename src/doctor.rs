@@ -0,0 +1,461 @@
+use std::fmt::{self, Display};
+
+use k8s_openapi::api::authorization::v1::{
+    ResourceAttributes, SelfSubjectAccessReview, SelfSubjectAccessReviewSpec,
+};
+use kube::{api::PostParams, Api, Client};
+
+use crate::{
+    get_ip,
+    res::{PORT_MAPPING_GROUP, PORT_MAPPING_PLURAL},
+    BackendKind, BatchOpts, Cmd, CommandOpts, Executor, IpFamily,
+};
+
+/// The API groups and resources epok watches, and whether they live in
+/// namespaces.
+const WATCHED_RESOURCES: [(&str, &str, bool); 3] =
+    [("", "services", true), ("", "nodes", false), ("", "pods", true)];
+const WATCH_VERBS: [&str; 2] = ["list", "watch"];
+/// What publishing the status takes: the annotation and the Events.
+const STATUS_PERMISSIONS: [(&str, &str, &str); 4] = [
+    ("", "services", "patch"),
+    ("", "pods", "patch"),
+    ("events.k8s.io", "events", "create"),
+    ("events.k8s.io", "events", "patch"),
+];
+/// What reporting the ingress of load balancers takes.
+const INGRESS_PERMISSION: (&str, &str, &str) =
+    ("", "services/status", "patch");
+/// What leader election takes.
+const LEASE_PERMISSIONS: [(&str, &str, &str); 3] = [
+    ("coordination.k8s.io", "leases", "get"),
+    ("coordination.k8s.io", "leases", "create"),
+    ("coordination.k8s.io", "leases", "update"),
+];
+
+/// The outcome of one preflight check.
+#[derive(Debug)]
+pub struct Check {
+    /// The host the check ran on, or the cluster
+    pub target: String,
+    pub name: String,
+    /// What was found when the check passed, what is wrong otherwise
+    pub outcome: Result<String, String>,
+}
+
+/// The outcome of all preflight checks.
+#[derive(Debug, Default)]
+pub struct Report(pub Vec<Check>);
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.0.iter().all(|check| check.outcome.is_ok())
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = &Check> {
+        self.0.iter().filter(|check| check.outcome.is_err())
+    }
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (status, detail) = match &self.outcome {
+            Ok(detail) => ("PASS", detail),
+            Err(detail) => ("FAIL", detail),
+        };
+        write!(f, "{status}  {}: {}", self.target, self.name)?;
+        match detail.is_empty() {
+            true => Ok(()),
+            false => write!(f, " - {detail}"),
+        }
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.0 {
+            writeln!(f, "{check}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Everything the checks of a host need to know about it.
+pub struct HostChecks<'a, E> {
+    pub name: &'a str,
+    pub executor: &'a E,
+    pub interfaces: &'a [String],
+    pub external_interface: Option<&'a str>,
+    pub backend: BackendKind,
+    pub command_opts: &'a CommandOpts,
+    pub batch_opts: &'a BatchOpts,
+}
+
+impl<E: Executor> HostChecks<'_, E> {
+    /// Runs the checks that apply to the backend, stopping early when the
+    /// host can't be reached at all.
+    pub fn run(&self) -> Vec<Check> {
+        let mut checks = vec![self.check("executor reachable", || {
+            self.executor
+                .run(&Cmd::new("true"))
+                .map(|_| String::new())
+                .map_err(|e| e.to_string())
+        })];
+        if checks[0].outcome.is_err() {
+            return checks;
+        }
+
+        checks.extend(self.interfaces.iter().map(|iface| {
+            self.check(&format!("interface {iface} exists"), || {
+                let cmd = Cmd::new(&self.command_opts.ip_binary)
+                    .args(["-o", "link", "show", "dev", iface]);
+                self.executor
+                    .run(&cmd)
+                    .map(|_| String::new())
+                    .map_err(|e| e.to_string())
+            })
+        }));
+        if let Some(iface) = self.external_interface {
+            checks.push(
+                self.check(
+                    &format!("interface {iface} has an address"),
+                    || self.external_addresses(iface),
+                ),
+            );
+        }
+
+        let firewall = [BackendKind::Iptables, BackendKind::Nftables];
+        if !firewall.contains(&self.backend) {
+            return checks;
+        }
+        checks.push(self.check("privilege", || self.privilege()));
+        checks.push(self.check("firewall", || self.firewall()));
+        checks.push(self.check("net.ipv4.ip_forward", || self.ip_forward()));
+        checks.push(self.check("ARG_MAX", || self.arg_max()));
+        checks
+    }
+
+    fn check(
+        &self,
+        name: &str,
+        f: impl FnOnce() -> Result<String, String>,
+    ) -> Check {
+        Check {
+            target: self.name.to_owned(),
+            name: name.to_owned(),
+            outcome: f(),
+        }
+    }
+
+    fn external_addresses(&self, iface: &str) -> Result<String, String> {
+        let addrs = [IpFamily::V4, IpFamily::V6]
+            .into_iter()
+            .filter_map(|family| {
+                get_ip(iface, family, self.executor, self.command_opts)
+            })
+            .collect::<Vec<_>>();
+        match addrs.is_empty() {
+            true => Err("no IPv4 or global IPv6 address".to_owned()),
+            false => Ok(addrs.join(", ")),
+        }
+    }
+
+    fn privilege(&self) -> Result<String, String> {
+        let id = self.command_opts.privileged(Cmd::new("id").arg("-u"));
+        match self.executor.run(&id) {
+            Ok(uid) if uid == "0" => Ok(format!("`{id}` runs as root")),
+            Ok(uid) => Err(format!("`{id}` runs as uid {uid}, not root")),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    /// Which firewall tool the backend drives, and for iptables whether it
+    /// is the legacy or the nf_tables variant.
+    fn firewall(&self) -> Result<String, String> {
+        let binary = match self.backend {
+            BackendKind::Nftables => &self.command_opts.nft_binary,
+            _ => &self.command_opts.iptables_binary,
+        };
+        let version = self
+            .executor
+            .run(
+                &self
+                    .command_opts
+                    .privileged(Cmd::new(binary).arg("--version")),
+            )
+            .map_err(|e| e.to_string())?;
+        if self.backend == BackendKind::Iptables
+            && version.contains("nf_tables")
+        {
+            return Ok(format!(
+                "{version} - rules are visible through nft, not through \
+                 iptables-legacy"
+            ));
+        }
+        Ok(version)
+    }
+
+    fn ip_forward(&self) -> Result<String, String> {
+        let cmd = Cmd::new("cat").arg("/proc/sys/net/ipv4/ip_forward");
+        match self.executor.run(&cmd).map_err(|e| e.to_string())?.as_str() {
+            "1" => Ok("enabled".to_owned()),
+            _ => Err("disabled, forwarded traffic gets dropped".to_owned()),
+        }
+    }
+
+    fn arg_max(&self) -> Result<String, String> {
+        let arg_max = self
+            .executor
+            .run(&Cmd::new("getconf").arg("ARG_MAX"))
+            .map_err(|e| e.to_string())?
+            .parse::<usize>()
+            .map_err(|e| format!("unexpected getconf output: {e}"))?;
+        match self.batch_size_within(arg_max) {
+            true => Ok(format!("{arg_max}")),
+            false => Err(format!(
+                "{arg_max} is below the batch size {}, lower --batch-size",
+                self.batch_opts.batch_size
+            )),
+        }
+    }
+
+    fn batch_size_within(&self, arg_max: usize) -> bool {
+        !self.batch_opts.batch_commands
+            || self.batch_opts.batch_size <= arg_max
+    }
+}
+
+/// A permission the service account needs, in a namespace or in all of
+/// them.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Permission {
+    pub group: &'static str,
+    pub resource: &'static str,
+    pub verb: &'static str,
+    pub namespace: Option<String>,
+}
+
+impl Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.verb, self.resource)?;
+        match &self.namespace {
+            Some(namespace) => write!(f, " in {namespace}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Everything the checks of the cluster permissions need to know.
+pub struct ClusterChecks<'a> {
+    /// The namespaces watched, all of them when empty
+    pub namespaces: &'a [String],
+    pub port_mappings: bool,
+    pub publish_status: bool,
+    pub load_balancers: bool,
+    /// The namespace of the Lease, with leader election
+    pub lease_namespace: Option<&'a str>,
+}
+
+impl ClusterChecks<'_> {
+    /// What epok watches, port mappings included when they are enabled,
+    /// along with what publishing the status, reporting the ingress of load
+    /// balancers and holding the Lease take when these are enabled.
+    pub fn permissions(&self) -> Vec<Permission> {
+        let mapping_resource = (PORT_MAPPING_GROUP, PORT_MAPPING_PLURAL, true);
+        let watched = WATCHED_RESOURCES
+            .into_iter()
+            .chain(self.port_mappings.then_some(mapping_resource))
+            .flat_map(|(group, resource, namespaced)| {
+                WATCH_VERBS.map(|verb| (group, resource, verb, namespaced))
+            });
+        let status = STATUS_PERMISSIONS
+            .into_iter()
+            .filter(|_| self.publish_status)
+            .chain(self.load_balancers.then_some(INGRESS_PERMISSION))
+            .map(|(group, resource, verb)| (group, resource, verb, true));
+        let mut permissions = Vec::new();
+        for (group, resource, verb, namespaced) in watched.chain(status) {
+            let namespaces = match namespaced {
+                true => self.namespaces.iter().map(Some).collect(),
+                false => Vec::new(),
+            };
+            match namespaces.is_empty() {
+                true => permissions.push(Permission {
+                    group,
+                    resource,
+                    verb,
+                    namespace: None,
+                }),
+                false => permissions.extend(namespaces.into_iter().map(
+                    |namespace| Permission {
+                        group,
+                        resource,
+                        verb,
+                        namespace: namespace.cloned(),
+                    },
+                )),
+            }
+        }
+        if let Some(namespace) = self.lease_namespace {
+            permissions.extend(LEASE_PERMISSIONS.into_iter().map(
+                |(group, resource, verb)| Permission {
+                    group,
+                    resource,
+                    verb,
+                    namespace: Some(namespace.to_owned()),
+                },
+            ));
+        }
+        permissions
+    }
+
+    /// Asks the API server whether the service account has the
+    /// permissions.
+    pub async fn run(&self, client: Client) -> Vec<Check> {
+        let api = Api::<SelfSubjectAccessReview>::all(client);
+        let mut checks = Vec::new();
+        for permission in self.permissions() {
+            let (resource, subresource) =
+                permission.resource.split_once('/').unzip();
+            let review = SelfSubjectAccessReview {
+                spec: SelfSubjectAccessReviewSpec {
+                    resource_attributes: Some(ResourceAttributes {
+                        group: Some(permission.group.to_owned()),
+                        resource: Some(
+                            resource.unwrap_or(permission.resource).to_owned(),
+                        ),
+                        subresource: subresource.map(str::to_owned),
+                        verb: Some(permission.verb.to_owned()),
+                        namespace: permission.namespace.clone(),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            let outcome =
+                match api.create(&PostParams::default(), &review).await {
+                    Ok(review) => match review.status {
+                        Some(status) if status.allowed => Ok(String::new()),
+                        Some(status) => Err(status.reason.unwrap_or_default()),
+                        None => Err("no review status".to_owned()),
+                    },
+                    Err(e) => Err(e.to_string()),
+                };
+            checks.push(Check {
+                target: "cluster".to_owned(),
+                name: permission.to_string(),
+                outcome,
+            });
+        }
+        checks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Error, Result};
+
+    /// Answers commands from a script of command lines and outputs; the
+    /// other commands fail.
+    struct FakeHost(Vec<(&'static str, &'static str)>);
+
+    impl Executor for FakeHost {
+        fn run(&self, cmd: &Cmd) -> Result<String> {
+            let line = cmd.to_string();
+            match self.0.iter().find(|(cmd, _)| *cmd == line) {
+                Some((_, out)) => Ok(out.to_string()),
+                None => Err(Error::CommandFailed {
+                    host: "hv1".to_owned(),
+                    cmd: line,
+                    code: Some(1),
+                    stderr: String::new(),
+                }),
+            }
+        }
+
+        fn run_with_stdin(&self, cmd: &Cmd, _stdin: &str) -> Result<String> {
+            self.run(cmd)
+        }
+    }
+
+    fn checks(executor: &FakeHost) -> Vec<Check> {
+        HostChecks {
+            name: "hv1",
+            executor,
+            interfaces: &["eth0".to_owned(), "eth1".to_owned()],
+            external_interface: None,
+            backend: BackendKind::Iptables,
+            command_opts: &CommandOpts::default(),
+            batch_opts: &BatchOpts { batch_commands: true, batch_size: 4096 },
+        }
+        .run()
+    }
+
+    #[test]
+    fn it_reports_a_misconfigured_host() {
+        let executor = FakeHost(vec![
+            ("true", ""),
+            ("ip -o link show dev eth0", "2: eth0: <UP>"),
+            ("sudo id -u", "1000"),
+            ("sudo iptables --version", "iptables v1.8.7 (legacy)"),
+            ("cat /proc/sys/net/ipv4/ip_forward", "0"),
+            ("getconf ARG_MAX", "2097152"),
+        ]);
+        let report = Report(checks(&executor));
+        let failed =
+            report.failed().map(|c| c.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            failed,
+            ["interface eth1 exists", "privilege", "net.ipv4.ip_forward"]
+        );
+        assert!(!report.passed());
+        assert!(report
+            .to_string()
+            .contains("PASS  hv1: firewall - iptables v1.8.7 (legacy)\n"));
+    }
+
+    #[test]
+    fn it_stops_at_an_unreachable_host() {
+        let report = Report(checks(&FakeHost(Vec::new())));
+        assert_eq!(report.0.len(), 1);
+        assert!(report
+            .to_string()
+            .starts_with("FAIL  hv1: executor reachable"));
+    }
+
+    #[test]
+    fn it_checks_the_watched_namespaces_only() {
+        let namespaces = ["mail".to_owned(), "web".to_owned()];
+        let permissions = ClusterChecks {
+            namespaces: &namespaces,
+            port_mappings: false,
+            publish_status: false,
+            load_balancers: false,
+            lease_namespace: Some("epok"),
+        }
+        .permissions()
+        .iter()
+        .map(Permission::to_string)
+        .collect::<Vec<_>>();
+        assert_eq!(
+            permissions,
+            [
+                "list services in mail",
+                "list services in web",
+                "watch services in mail",
+                "watch services in web",
+                "list nodes",
+                "watch nodes",
+                "list pods in mail",
+                "list pods in web",
+                "watch pods in mail",
+                "watch pods in web",
+                "get leases in epok",
+                "create leases in epok",
+                "update leases in epok",
+            ]
+        );
+    }
+}
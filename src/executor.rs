@@ -1,42 +1,639 @@
-use cmd_lib::run_fun;
+use std::{
+    borrow::Cow,
+    fmt::{self, Display},
+    io::{self, Read, Write},
+    process::{Command, Output, Stdio},
+    slice,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
 
-use crate::{logging::*, Batch, BatchOpts, Error, Executor, Result};
+use backon::{BlockingRetryable, ExponentialBuilder};
+use itertools::Itertools;
 
-impl Executor {
-    pub fn run_fun<S: AsRef<str>>(&self, cmd: S) -> Result<String> {
-        fn inner(this: &Executor, cmd: &str) -> Result<String> {
-            debug!("running command: {cmd}");
-            match this {
-                Executor::Local => {
-                    Ok(run_fun!(sh -c "$cmd").map_err(Error::ExecutorError)?)
-                }
-                Executor::Ssh(ssh_host) => {
-                    let (host, port, key) =
-                        (&ssh_host.host, ssh_host.port, &ssh_host.key_path);
-                    Ok(run_fun!(ssh -p $port -i $key $host "$cmd")
-                        .map_err(Error::ExecutorError)?)
-                }
-            }
-        }
-        inner(self, cmd.as_ref())
+use crate::{
+    logging::*, Batch, BatchOpts, CommandOpts, Error, IpFamily, Privilege,
+    Result, COMMAND_POLL_INTERVAL, LOCALHOST,
+};
+
+/// A command as its argument vector. It runs without a shell where the
+/// transport allows it, and gets quoted for the ones that need a shell.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cmd {
+    argv: Vec<String>,
+    /// How long the command may run before it gets killed
+    timeout: Option<Duration>,
+}
+
+impl Cmd {
+    pub fn new(program: impl Into<String>) -> Self {
+        Self { argv: vec![program.into()], timeout: None }
+    }
+
+    /// Runs `script` through `sh -c`, for the commands that need a shell.
+    pub fn shell(script: impl Into<String>) -> Self {
+        Self::new("sh").arg("-c").arg(script)
+    }
+
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.argv.push(arg.into());
+        self
     }
 
-    pub fn run_commands(
+    pub fn args<S: Into<String>>(
+        mut self,
+        args: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.argv.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn with_timeout(self, timeout: Option<Duration>) -> Self {
+        Self { timeout, ..self }
+    }
+
+    pub fn argv(&self) -> &[String] { &self.argv }
+
+    pub fn timeout(&self) -> Option<Duration> { self.timeout }
+
+    /// Renders the command for a POSIX shell, quoting every argument that
+    /// the shell would otherwise interpret.
+    pub fn to_shell(&self) -> String {
+        self.argv.iter().map(|arg| quote(arg)).join(" ")
+    }
+
+    fn to_command(&self) -> Command {
+        let mut command = Command::new(&self.argv[0]);
+        command.args(&self.argv[1..]);
+        command
+    }
+}
+
+impl Display for Cmd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_shell())
+    }
+}
+
+/// Quotes `arg` for a POSIX shell, unless it is made of safe characters.
+fn quote(arg: &str) -> Cow<'_, str> {
+    let is_safe =
+        |c: char| c.is_ascii_alphanumeric() || "-_./=:,@%+".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        return Cow::Borrowed(arg);
+    }
+    Cow::Owned(format!("'{}'", arg.replace('\'', "'\\''")))
+}
+
+/// Runs commands on the host machine the rules are installed on.
+pub trait Executor: Send + Sync {
+    /// Runs `cmd` and returns its trimmed stdout.
+    fn run(&self, cmd: &Cmd) -> Result<String>;
+
+    /// Like `run`, feeding `stdin` to the command.
+    fn run_with_stdin(&self, cmd: &Cmd, stdin: &str) -> Result<String>;
+
+    /// Runs `commands` in order. When `batch_opts` asks for it they are
+    /// quoted and joined into shell scripts, saving round trips.
+    fn run_commands(
         &self,
-        commands: impl Iterator<Item = String>,
+        commands: &mut dyn Iterator<Item = Cmd>,
         batch_opts: &BatchOpts,
     ) -> Result<()> {
         if batch_opts.batch_commands {
+            let mut commands = commands.peekable();
+            let timeout = commands.peek().and_then(Cmd::timeout);
             let sep = "; ".to_owned();
-            let batch = Batch::new(commands, batch_opts.batch_size, &sep);
-            for command in batch {
-                self.run_fun(command)?;
+            let scripts = commands.map(|cmd| cmd.to_shell());
+            for script in Batch::new(scripts, batch_opts.batch_size, &sep) {
+                self.run(&Cmd::shell(script).with_timeout(timeout))?;
             }
         } else {
             for command in commands {
-                self.run_fun(command)?;
+                self.run(&command)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<E: Executor + ?Sized> Executor for Box<E> {
+    fn run(&self, cmd: &Cmd) -> Result<String> { (**self).run(cmd) }
+
+    fn run_with_stdin(&self, cmd: &Cmd, stdin: &str) -> Result<String> {
+        (**self).run_with_stdin(cmd, stdin)
+    }
+
+    fn run_commands(
+        &self,
+        commands: &mut dyn Iterator<Item = Cmd>,
+        batch_opts: &BatchOpts,
+    ) -> Result<()> {
+        (**self).run_commands(commands, batch_opts)
+    }
+}
+
+/// Runs commands on the machine epok itself runs on.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalExecutor;
+
+impl Executor for LocalExecutor {
+    fn run(&self, cmd: &Cmd) -> Result<String> {
+        debug!("running command: {cmd}");
+        stdout(LOCALHOST, cmd, output(cmd.to_command(), None, cmd.timeout)?)
+    }
+
+    fn run_with_stdin(&self, cmd: &Cmd, stdin: &str) -> Result<String> {
+        debug!("running command: {cmd} ({} bytes of stdin)", stdin.len());
+        let output = output(cmd.to_command(), Some(stdin), cmd.timeout)?;
+        stdout(LOCALHOST, cmd, output)
+    }
+}
+
+/// Runs read-only commands through the wrapped executor and prints the
+/// mutating ones instead - set up through `--dry-run`.
+#[derive(Debug)]
+pub struct DryRun<E>(pub E);
+
+impl<E: Executor> Executor for DryRun<E> {
+    fn run(&self, cmd: &Cmd) -> Result<String> {
+        match is_read_only(cmd) {
+            true => self.0.run(cmd),
+            false => {
+                println!("{cmd}");
+                Ok(String::new())
+            }
+        }
+    }
+
+    fn run_with_stdin(&self, cmd: &Cmd, stdin: &str) -> Result<String> {
+        match is_read_only(cmd) {
+            true => self.0.run_with_stdin(cmd, stdin),
+            false => {
+                println!("{cmd} <<'EOF'\n{stdin}\nEOF");
+                Ok(String::new())
+            }
+        }
+    }
+
+    /// Prints the commands one by one rather than as batches.
+    fn run_commands(
+        &self,
+        commands: &mut dyn Iterator<Item = Cmd>,
+        _batch_opts: &BatchOpts,
+    ) -> Result<()> {
+        for command in commands {
+            self.run(&command)?;
+        }
+        Ok(())
+    }
+}
+
+/// Gives each command a timeout and runs it again, with backoff, when that
+/// cannot apply anything twice: reads that failed in a way that may go away
+/// by itself - the ssh connection dropped or the command timed out - and
+/// changes turned away as a whole, e.g. by a held xtables lock. Changes
+/// that may have gone through fail instead, for the next reconcile to read
+/// the host afresh.
+#[derive(Debug)]
+pub struct Retrying<E> {
+    pub inner: E,
+    pub timeout: Option<Duration>,
+    pub retries: usize,
+}
+
+impl<E: Executor> Retrying<E> {
+    fn retry<T>(
+        &self,
+        commands: &[Cmd],
+        f: impl FnMut() -> Result<T>,
+    ) -> Result<T> {
+        let backoff = ExponentialBuilder::new()
+            .with_min_delay(Duration::from_millis(500))
+            .with_max_delay(Duration::from_secs(10))
+            .with_max_times(self.retries);
+        f.retry(backoff)
+            .when(|e| can_retry(commands, e))
+            .notify(|e, delay| warn!("{e}, retry in {delay:?}"))
+            .call()
+    }
+
+    fn run_chunk(&self, chunk: &[Cmd], batch_opts: &BatchOpts) -> Result<()> {
+        self.retry(chunk, || {
+            self.inner.run_commands(&mut chunk.iter().cloned(), batch_opts)
+        })
+    }
+}
+
+impl<E: Executor> Executor for Retrying<E> {
+    fn run(&self, cmd: &Cmd) -> Result<String> {
+        let cmd = cmd.clone().with_timeout(self.timeout);
+        self.retry(slice::from_ref(&cmd), || self.inner.run(&cmd))
+    }
+
+    fn run_with_stdin(&self, cmd: &Cmd, stdin: &str) -> Result<String> {
+        let cmd = cmd.clone().with_timeout(self.timeout);
+        self.retry(slice::from_ref(&cmd), || {
+            self.inner.run_with_stdin(&cmd, stdin)
+        })
+    }
+
+    /// Hands the commands to the wrapped executor a batch at a time, so
+    /// that a failure only repeats the batch it happened in.
+    fn run_commands(
+        &self,
+        commands: &mut dyn Iterator<Item = Cmd>,
+        batch_opts: &BatchOpts,
+    ) -> Result<()> {
+        let mut chunk: Vec<Cmd> = Vec::new();
+        let mut len = 0;
+        for cmd in commands.map(|cmd| cmd.with_timeout(self.timeout)) {
+            let cmd_len = cmd.to_shell().len();
+            if !chunk.is_empty()
+                && (!batch_opts.batch_commands
+                    || len + cmd_len >= batch_opts.batch_size)
+            {
+                self.run_chunk(&chunk, batch_opts)?;
+                chunk.clear();
+                len = 0;
             }
+            len += cmd_len;
+            chunk.push(cmd);
+        }
+        if !chunk.is_empty() {
+            self.run_chunk(&chunk, batch_opts)?;
         }
         Ok(())
     }
 }
+
+impl CommandOpts {
+    /// The command timeout, if there is one.
+    pub fn timeout(&self) -> Option<Duration> {
+        (self.command_timeout > 0)
+            .then(|| Duration::from_secs(self.command_timeout))
+    }
+
+    /// Prefixes `cmd` with the privilege wrapper, if any.
+    pub fn privileged(&self, cmd: Cmd) -> Cmd {
+        let wrapper = match self.privilege {
+            Privilege::None => return cmd,
+            Privilege::Sudo => vec!["sudo"],
+            Privilege::Doas => vec!["doas"],
+            Privilege::Custom => self
+                .privilege_command
+                .as_deref()
+                .unwrap_or_default()
+                .split_whitespace()
+                .collect(),
+        };
+        let argv = wrapper.into_iter().map(str::to_owned).chain(cmd.argv);
+        Cmd { argv: argv.collect(), timeout: cmd.timeout }
+    }
+
+    pub fn iptables_save(&self, family: IpFamily) -> String {
+        let (save, iptables) = match family {
+            IpFamily::V4 => {
+                (&self.iptables_save_binary, &self.iptables_binary)
+            }
+            IpFamily::V6 => {
+                (&self.ip6tables_save_binary, &self.ip6tables_binary)
+            }
+        };
+        save.clone().unwrap_or_else(|| format!("{iptables}-save"))
+    }
+
+    pub fn iptables_restore(&self, family: IpFamily) -> String {
+        let (restore, iptables) = match family {
+            IpFamily::V4 => {
+                (&self.iptables_restore_binary, &self.iptables_binary)
+            }
+            IpFamily::V6 => {
+                (&self.ip6tables_restore_binary, &self.ip6tables_binary)
+            }
+        };
+        restore.clone().unwrap_or_else(|| format!("{iptables}-restore"))
+    }
+}
+
+/// Runs `command` to completion, feeding it `stdin` if any. The command
+/// gets killed once it runs longer than `timeout`.
+pub(crate) fn output(
+    mut command: Command,
+    stdin: Option<&str>,
+    timeout: Option<Duration>,
+) -> Result<Output> {
+    let mut child = command
+        .stdin(match stdin {
+            Some(_) => Stdio::piped(),
+            None => Stdio::null(),
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(Error::ExecutorError)?;
+
+    let writer = stdin.map(|stdin| {
+        let mut child_stdin =
+            child.stdin.take().expect("child stdin should be piped");
+        let input = stdin.to_owned();
+        thread::spawn(move || child_stdin.write_all(input.as_bytes()))
+    });
+    let stdout = read_to_end(child.stdout.take());
+    let stderr = read_to_end(child.stderr.take());
+
+    let status = match timeout {
+        None => child.wait().map_err(Error::ExecutorError)?,
+        Some(timeout) => {
+            let deadline = Instant::now() + timeout;
+            loop {
+                if let Some(status) =
+                    child.try_wait().map_err(Error::ExecutorError)?
+                {
+                    break status;
+                }
+                if Instant::now() >= deadline {
+                    // whatever the command spawned may still hold on to
+                    // its output, so the readers are left behind
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    return Err(Error::CommandTimeout(
+                        format!("{command:?}"),
+                        timeout,
+                    ));
+                }
+                thread::sleep(COMMAND_POLL_INTERVAL);
+            }
+        }
+    };
+    let join = |reader: JoinHandle<io::Result<Vec<u8>>>| {
+        reader
+            .join()
+            .expect("output reader should not panic")
+            .map_err(Error::ExecutorError)
+    };
+    let (stdout, stderr) = (join(stdout)?, join(stderr)?);
+    if let Some(writer) = writer {
+        writer
+            .join()
+            .expect("stdin writer should not panic")
+            .map_err(Error::ExecutorError)?;
+    }
+    Ok(Output { status, stdout, stderr })
+}
+
+/// Collects everything written to `pipe` on a thread of its own.
+fn read_to_end(
+    pipe: Option<impl Read + Send + 'static>,
+) -> JoinHandle<io::Result<Vec<u8>>> {
+    let mut pipe = pipe.expect("child output should be piped");
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        pipe.read_to_end(&mut buf).map(|_| buf)
+    })
+}
+
+/// The trimmed stdout of `cmd` run on `host`, provided that it succeeded.
+pub(crate) fn stdout(
+    host: &str,
+    cmd: impl Display,
+    output: Output,
+) -> Result<String> {
+    let stderr = String::from_utf8_lossy(&output.stderr).trim_end().to_owned();
+    if !output.status.success() {
+        return Err(Error::CommandFailed {
+            host: host.to_owned(),
+            cmd: cmd.to_string(),
+            code: output.status.code(),
+            stderr,
+        });
+    }
+    if !stderr.is_empty() {
+        debug!("{cmd}: {stderr}");
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim_end().into())
+}
+
+/// Looks up the first address of `family` on `interface`.
+pub fn get_ip<I: AsRef<str>, E: Executor>(
+    interface: I,
+    family: IpFamily,
+    executor: &E,
+    command_opts: &CommandOpts,
+) -> Option<String> {
+    fn inner(
+        iface: &str,
+        family: IpFamily,
+        inner_executor: &dyn Executor,
+        ip: &str,
+    ) -> Option<String> {
+        let (flag, scope) = match family {
+            IpFamily::V4 => ("inet", &[][..]),
+            IpFamily::V6 => ("inet6", &["scope", "global"][..]),
+        };
+        let cmd = Cmd::new(ip)
+            .args(["-o", "-f", flag, "addr", "show", "dev", iface])
+            .args(scope.iter().copied());
+        let addrs = inner_executor.run(&cmd).ok()?;
+        parse_addr(flag, &addrs)
+    }
+
+    inner(interface.as_ref(), family, executor, &command_opts.ip_binary)
+}
+
+/// The first address in `ip -o addr show` output, without its prefix
+/// length.
+fn parse_addr(flag: &str, addrs: &str) -> Option<String> {
+    addrs.lines().find_map(|line| {
+        let mut words = line.split_whitespace().skip_while(|w| *w != flag);
+        let addr = words.nth(1)?;
+        Some(addr.split('/').next().unwrap_or(addr).to_owned())
+    })
+}
+
+/// Whether `commands` can run again after failing with `e` without applying
+/// anything twice: they only read, or they are a single transaction that
+/// was turned away before it changed anything. A timed out change may well
+/// have gone through on the host.
+fn can_retry(commands: &[Cmd], e: &Error) -> bool {
+    match commands {
+        _ if commands.iter().all(is_read_only) => e.is_transient(),
+        [_] => e.never_applied(),
+        _ => false,
+    }
+}
+
+/// Whether `cmd` only inspects the host, so it is safe to run even in
+/// dry-run mode.
+fn is_read_only(cmd: &Cmd) -> bool {
+    // the file backend reads back what it wrote, without privileges
+    if cmd.argv()[0] == "cat" {
+        return true;
+    }
+    // skip the privilege wrapper, whatever it is
+    let mut args = cmd
+        .argv()
+        .iter()
+        .map(|arg| arg.rsplit('/').next().unwrap_or(arg))
+        .skip_while(|arg| {
+            !arg.contains("tables") && !["nft", "ip"].contains(arg)
+        });
+    match args.next() {
+        Some(save) if save.ends_with("-save") => true,
+        Some("nft") => args.any(|arg| arg == "list"),
+        Some("ip") => args.any(|arg| arg == "show"),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SSH_CONNECTION_FAILED;
+
+    fn cmd(line: &str) -> Cmd {
+        let mut words = line.split_whitespace();
+        Cmd::new(words.next().unwrap()).args(words)
+    }
+
+    #[test]
+    fn it_only_runs_read_only_commands_in_dry_run() {
+        assert!(is_read_only(&cmd("sudo iptables-save -t nat")));
+        assert!(is_read_only(&cmd("sudo nft -a list table ip epok")));
+        assert!(is_read_only(&cmd("doas /sbin/iptables-legacy-save -t nat")));
+        assert!(is_read_only(&cmd("ip -o -f inet addr show dev eth0")));
+        assert!(is_read_only(&cmd("cat /etc/epok.rules")));
+        assert!(!is_read_only(&cmd("sudo iptables-restore -w --noflush")));
+        assert!(!is_read_only(&cmd("sudo nft add table ip epok")));
+        assert!(!is_read_only(&Cmd::shell("nft list ruleset; reboot")));
+        assert!(!is_read_only(&Cmd::shell("cat > x").arg("sh").arg("cat")));
+    }
+
+    #[test]
+    fn it_prints_mutating_commands_in_dry_run() {
+        let executor = DryRun(LocalExecutor);
+        assert_eq!(executor.run(&cmd("false")).unwrap(), "");
+        assert_eq!(executor.run_with_stdin(&cmd("false"), "x").unwrap(), "");
+    }
+
+    #[test]
+    fn it_runs_arguments_without_a_shell() {
+        let arg = "it's $(touch /tmp/pwned) \"quoted\"; `id`";
+        let out = LocalExecutor.run(&Cmd::new("echo").arg(arg)).unwrap();
+        assert_eq!(out, arg);
+
+        // the quoted form survives a shell just as well
+        let script = Cmd::new("echo").arg(arg).to_shell();
+        assert_eq!(LocalExecutor.run(&Cmd::shell(script)).unwrap(), arg);
+        assert_eq!(cmd("sudo nft -a list").to_shell(), "sudo nft -a list");
+    }
+
+    #[test]
+    fn it_captures_the_complaint_of_failed_commands() {
+        let script = Cmd::shell("echo 'Bad argument' >&2; exit 2");
+        match LocalExecutor.run(&script).unwrap_err() {
+            Error::CommandFailed { host, code, stderr, .. } => {
+                assert_eq!(host, LOCALHOST);
+                assert_eq!(code, Some(2));
+                assert_eq!(stderr, "Bad argument");
+            }
+            err => panic!("unexpected error: {err}"),
+        }
+    }
+
+    #[test]
+    fn it_parses_interface_addresses() {
+        let addrs = "2: eth0    inet 10.0.0.2/24 brd 10.0.0.255 \
+                     scope global eth0\\       valid_lft forever\n\
+                     2: eth0    inet 10.0.0.3/24 scope global secondary eth0";
+        assert_eq!(parse_addr("inet", addrs).as_deref(), Some("10.0.0.2"));
+        assert_eq!(parse_addr("inet6", addrs), None);
+    }
+
+    #[test]
+    fn it_wraps_commands_in_the_privilege_command() {
+        let command_opts = CommandOpts {
+            privilege: Privilege::Custom,
+            privilege_command: Some("sudo -n".to_owned()),
+            ..Default::default()
+        };
+        assert_eq!(
+            command_opts.privileged(cmd("nft list ruleset")).argv(),
+            ["sudo", "-n", "nft", "list", "ruleset"]
+        );
+    }
+
+    #[test]
+    fn it_kills_commands_that_time_out() {
+        let started = Instant::now();
+        let sleep =
+            cmd("sleep 5").with_timeout(Some(Duration::from_millis(50)));
+        let err = LocalExecutor.run(&sleep).unwrap_err();
+        assert!(matches!(err, Error::CommandTimeout(..)), "{err}");
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(err.is_transient());
+    }
+
+    /// Fails with the given exit codes and stderr, one per run, then
+    /// succeeds.
+    struct Flaky(std::sync::Mutex<Vec<(i32, &'static str)>>);
+
+    impl Executor for Flaky {
+        fn run(&self, cmd: &Cmd) -> Result<String> {
+            match self.0.lock().unwrap().pop() {
+                Some((code, stderr)) => Err(Error::CommandFailed {
+                    host: "hv1".to_owned(),
+                    cmd: cmd.to_string(),
+                    code: Some(code),
+                    stderr: stderr.to_owned(),
+                }),
+                None => Ok("ok".to_owned()),
+            }
+        }
+
+        fn run_with_stdin(&self, cmd: &Cmd, _stdin: &str) -> Result<String> {
+            self.run(cmd)
+        }
+    }
+
+    fn retrying(failures: Vec<(i32, &'static str)>) -> Retrying<Flaky> {
+        Retrying {
+            inner: Flaky(std::sync::Mutex::new(failures)),
+            timeout: None,
+            retries: 3,
+        }
+    }
+
+    #[test]
+    fn it_retries_transient_failures() {
+        let executor = retrying(vec![(SSH_CONNECTION_FAILED, "")]);
+        assert_eq!(executor.run(&cmd("iptables-save -t nat")).unwrap(), "ok");
+
+        let locked = "Another app is currently holding the xtables lock.";
+        let executor = retrying(vec![(4, locked)]);
+        let restore = cmd("iptables-restore -w --noflush");
+        assert_eq!(executor.run_with_stdin(&restore, "").unwrap(), "ok");
+
+        // a rule iptables rejects stays rejected
+        let executor = retrying(vec![(2, "")]);
+        let err = executor.run(&cmd("iptables-restore")).unwrap_err();
+        assert_eq!(err.to_string(), "hv1: `iptables-restore` exited with 2");
+        assert!(executor.inner.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_does_not_repeat_changes_that_may_have_gone_through() {
+        let executor = retrying(vec![(SSH_CONNECTION_FAILED, "")]);
+        let restore = cmd("iptables-restore -w --noflush");
+        assert!(executor.run_with_stdin(&restore, "").is_err());
+
+        // the batch may have lost the connection halfway through
+        let executor = retrying(vec![(SSH_CONNECTION_FAILED, "")]);
+        let batch_opts = BatchOpts { batch_commands: true, batch_size: 4096 };
+        let mut rules = ["nft add rule ip epok a", "nft add rule ip epok b"]
+            .into_iter()
+            .map(cmd);
+        assert!(executor.run_commands(&mut rules, &batch_opts).is_err());
+        assert!(executor.inner.0.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,426 @@
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use itertools::Itertools;
+use sha256::digest;
+
+use crate::{
+    iptables::{iptables_statement, EPOK_OUTPUT, EPOK_PREROUTING},
+    logging::*,
+    nftables::{nft_family, nft_statement, setup_statements, NFT_TABLE},
+    Backend, Cmd, Error, Executor, FileOpts, InstalledRule, IpFamilies,
+    IpFamily, LocalIps, OutputFormat, Result, Rule,
+};
+
+/// Renders the complete ruleset to a file instead of changing the host,
+/// for hosts whose firewall is owned by configuration management.
+pub struct FileBackend<E = Box<dyn Executor>> {
+    executor: E,
+    path: PathBuf,
+    format: OutputFormat,
+    post_write_hook: Option<String>,
+    /// Whether the hook still has to run for the ruleset last written, as
+    /// it failed
+    hook_pending: bool,
+    rules: BTreeMap<String, Rule>,
+    local_ips: LocalIps,
+    extra_ips: Option<String>,
+}
+
+impl<E: Executor> Backend for FileBackend<E> {
+    fn read_state(&mut self) -> Result<Vec<InstalledRule>> {
+        let config_hash = self.config_hash();
+        Ok(self
+            .rules
+            .values()
+            .map(|rule| InstalledRule::of(rule, "file", &config_hash))
+            .collect())
+    }
+
+    fn apply_rules(
+        &mut self,
+        rules: impl IntoIterator<Item = Rule>,
+    ) -> Result<()> {
+        let config_hash = self.config_hash();
+        for rule in rules {
+            if self.can_install(&rule) {
+                self.rules.insert(rule.rule_id(&config_hash), rule);
+            }
+        }
+        Ok(())
+    }
+
+    fn delete_rules(
+        &mut self,
+        rule_ids: impl IntoIterator<Item = String>,
+    ) -> Result<()> {
+        for rule_id in rule_ids {
+            self.rules.remove(&rule_id);
+        }
+        Ok(())
+    }
+
+    fn teardown(&mut self) -> Result<()> {
+        self.rules.clear();
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        for (path, contents) in self.render() {
+            // the output of `cat` comes back trimmed
+            let cat = Cmd::new("cat").arg(path.to_string_lossy());
+            if self.executor.run(&cat).is_ok_and(|c| c == contents.trim_end())
+            {
+                continue;
+            }
+            info!("writing ruleset to {}", path.display());
+            self.executor
+                .run_with_stdin(&write_atomically(&path), &contents)
+                .map_err(|e| Error::BackendError(Box::new(e)))?;
+            self.hook_pending = true;
+        }
+
+        let Some(hook) = self.post_write_hook.as_ref() else {
+            return Ok(());
+        };
+        if !self.hook_pending {
+            return Ok(());
+        }
+        self.executor
+            .run(&Cmd::shell(hook))
+            .map_err(|e| Error::BackendError(Box::new(e)))?;
+        self.hook_pending = false;
+        Ok(())
+    }
+
+    fn can_install(&self, rule: &Rule) -> bool {
+        rule.interface.name != "lo"
+            || self.local_ips.get(rule.family).is_some()
+    }
+
+    fn config_hash(&self) -> String {
+        let mut config_hash = digest(format!(
+            "{:?}::{:?}::{:?}",
+            self.local_ips, self.extra_ips, self.format
+        ));
+        config_hash.truncate(16);
+        config_hash
+    }
+}
+
+impl<E: Executor> FileBackend<E> {
+    pub fn new(
+        executor: E,
+        file_opts: FileOpts,
+        local_ips: LocalIps,
+        extra_ips: Option<String>,
+    ) -> Self {
+        Self {
+            executor,
+            path: file_opts
+                .output_path
+                .expect("the file backend requires an output path"),
+            format: file_opts.output_format,
+            post_write_hook: file_opts.post_write_hook,
+            hook_pending: false,
+            rules: BTreeMap::new(),
+            local_ips,
+            extra_ips,
+        }
+    }
+
+    /// The files to write along with their contents: a single nft script,
+    /// or one `iptables-restore` file per family (the IPv6 one gets a
+    /// `.v6` suffix).
+    fn render(&self) -> Vec<(PathBuf, String)> {
+        let config_hash = self.config_hash();
+        // statistic/numgen matches rely on the rule order
+        let rules = self
+            .rules
+            .values()
+            .sorted_by_key(|r| (r.family, Reverse(r.nth)))
+            .collect::<Vec<_>>();
+
+        match self.format {
+            OutputFormat::Nft => vec![(
+                self.path.to_owned(),
+                nft_script(&rules, &self.local_ips, &config_hash),
+            )],
+            OutputFormat::IptablesRestore => IpFamilies::Both
+                .iter()
+                .map(|family| {
+                    let path = match family {
+                        IpFamily::V4 => self.path.to_owned(),
+                        IpFamily::V6 => {
+                            let mut path = self.path.clone().into_os_string();
+                            path.push(".v6");
+                            path.into()
+                        }
+                    };
+                    let rules = rules.iter().filter(|r| r.family == family);
+                    let contents =
+                        restore_file(rules, &self.local_ips, &config_hash);
+                    (path, contents)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// An `iptables-restore` file meant to be loaded with `--noflush`: the
+/// chain declarations flush the epok chains and leave the rest of the nat
+/// table alone. The jumps into the chains are up to the host config.
+fn restore_file<'a>(
+    rules: impl Iterator<Item = &'a &'a Rule>,
+    local_ips: &LocalIps,
+    config_hash: &str,
+) -> String {
+    let mut lines = vec![
+        "# generated by epok, do not edit".to_owned(),
+        "*nat".to_owned(),
+        format!(":{EPOK_PREROUTING} - [0:0]"),
+        format!(":{EPOK_OUTPUT} - [0:0]"),
+    ];
+    lines.extend(rules.map(|rule| {
+        format!("-A {}", iptables_statement(rule, local_ips, config_hash))
+    }));
+    lines.push("COMMIT".to_owned());
+    lines.iter().map(|line| format!("{line}\n")).collect()
+}
+
+/// An `nft -f` script that atomically replaces the epok tables.
+fn nft_script(
+    rules: &[&Rule],
+    local_ips: &LocalIps,
+    config_hash: &str,
+) -> String {
+    let mut lines = vec!["#!/usr/sbin/nft -f".to_owned()];
+    for family in IpFamilies::Both.iter() {
+        // adding the table first makes the deletion safe on a clean host
+        let family_name = nft_family(family);
+        lines.push(format!("add table {family_name} {NFT_TABLE}"));
+        lines.push(format!("delete table {family_name} {NFT_TABLE}"));
+        lines.extend(setup_statements(family));
+        lines.extend(
+            rules
+                .iter()
+                .filter(|r| r.family == family)
+                .map(|rule| nft_statement(rule, local_ips, config_hash)),
+        );
+    }
+    lines.iter().map(|line| format!("{line}\n")).collect()
+}
+
+/// Writes its stdin to a temporary file next to `path` and renames it
+/// into place on the host, so readers never see a partially written
+/// ruleset.
+fn write_atomically(path: &Path) -> Cmd {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let tmp_path = path.with_file_name(format!(".{file_name}.tmp"));
+    Cmd::shell(r#"cat > "$1" && mv -f "$1" "$2""#)
+        .arg("sh")
+        .arg(tmp_path.to_string_lossy())
+        .arg(path.to_string_lossy())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Mutex};
+
+    use super::*;
+    use crate::{DryRun, Interface, LocalExecutor, PortSpec};
+
+    fn rule(dest_addr: &str, nth: usize) -> Rule {
+        Rule {
+            dest_addr: dest_addr.to_owned(),
+            family: IpFamily::of(dest_addr),
+            allow_range: None,
+            port_spec: PortSpec::new_tcp(80, 30080),
+            interface: Interface::new("eth0"),
+            nth,
+            out_of: 2,
+            comment: Some("service: default/foo".to_owned()),
+            rule_hash: dest_addr.to_owned(),
+        }
+    }
+
+    fn backend(
+        format: OutputFormat,
+        path: &Path,
+    ) -> FileBackend<LocalExecutor> {
+        FileBackend::new(
+            LocalExecutor,
+            FileOpts {
+                output_path: Some(path.to_owned()),
+                output_format: format,
+                post_write_hook: Some(format!(
+                    "touch {}",
+                    path.with_extension("hook").display()
+                )),
+            },
+            LocalIps::default(),
+            None,
+        )
+    }
+
+    #[test]
+    fn it_renders_restore_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("epok.rules");
+        let mut backend = backend(OutputFormat::IptablesRestore, &path);
+
+        backend
+            .apply_rules([rule("10.0.0.1", 0), rule("10.0.0.2", 1)])
+            .unwrap();
+        backend.commit().unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines = contents.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines[1..4],
+            ["*nat", ":EPOK-PREROUTING - [0:0]", ":EPOK-OUTPUT - [0:0]"]
+        );
+        // the balanced rule has to come first
+        assert!(lines[4].contains("--every 2"));
+        assert!(lines[5].ends_with("10.0.0.1:30080"));
+        assert_eq!(lines[6], "COMMIT");
+
+        let v6 = dir.path().join("epok.rules.v6");
+        let v6 = fs::read_to_string(v6).unwrap();
+        assert!(!v6.contains("-A "));
+        assert!(path.with_extension("hook").exists());
+    }
+
+    #[test]
+    fn it_renders_nft_scripts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("epok.nft");
+        let mut backend = backend(OutputFormat::Nft, &path);
+
+        backend
+            .apply_rules([rule("10.0.0.1", 0), rule("fd00::1", 0)])
+            .unwrap();
+        let rule_ids = backend
+            .read_state()
+            .unwrap()
+            .into_iter()
+            .filter(|r| r.family == IpFamily::V6)
+            .map(|r| r.rule_id);
+        backend.delete_rules(rule_ids).unwrap();
+        backend.commit().unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(contents.contains("delete table ip epok\n"));
+        assert!(contents.contains("delete table ip6 epok\n"));
+        assert_eq!(contents.matches("add rule").count(), 1);
+        assert!(contents.contains("dnat to 10.0.0.1:30080"));
+        assert!(!dir.path().join(".epok.nft.tmp").exists());
+    }
+
+    /// Records what runs on the host, where no ruleset was written yet.
+    #[derive(Default)]
+    struct Remote(Mutex<Vec<String>>);
+
+    impl Executor for Remote {
+        fn run(&self, cmd: &Cmd) -> Result<String> {
+            self.0.lock().unwrap().push(cmd.to_string());
+            match cmd.argv()[0].as_str() {
+                "cat" => Err(Error::CommandFailed {
+                    host: "hv1".to_owned(),
+                    cmd: cmd.to_string(),
+                    code: Some(1),
+                    stderr: "No such file or directory".to_owned(),
+                }),
+                _ => Ok(String::new()),
+            }
+        }
+
+        fn run_with_stdin(&self, cmd: &Cmd, stdin: &str) -> Result<String> {
+            self.0.lock().unwrap().push(format!("{cmd}\n{stdin}"));
+            Ok(String::new())
+        }
+    }
+
+    #[test]
+    fn it_writes_through_the_executor() {
+        let mut backend = FileBackend::new(
+            Remote::default(),
+            FileOpts {
+                output_path: Some("/etc/epok.nft".into()),
+                output_format: OutputFormat::Nft,
+                post_write_hook: Some("nft -f /etc/epok.nft".to_owned()),
+            },
+            LocalIps::default(),
+            None,
+        );
+        backend.apply_rules([rule("10.0.0.1", 0)]).unwrap();
+        backend.commit().unwrap();
+
+        let ran = backend.executor.0.into_inner().unwrap();
+        assert_eq!(ran.len(), 3);
+        assert_eq!(ran[0], "cat /etc/epok.nft");
+        assert!(ran[1].starts_with(
+            "sh -c 'cat > \"$1\" && mv -f \"$1\" \"$2\"' sh \
+             /etc/.epok.nft.tmp /etc/epok.nft\n#!/usr/sbin/nft -f\n"
+        ));
+        assert!(ran[1].contains("dnat to 10.0.0.1:30080"));
+        assert_eq!(ran[2], "sh -c 'nft -f /etc/epok.nft'");
+    }
+
+    #[test]
+    fn it_runs_a_failed_hook_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("epok.rules");
+        let ready = dir.path().join("ready");
+        let mut backend = FileBackend::new(
+            LocalExecutor,
+            FileOpts {
+                output_path: Some(path.clone()),
+                post_write_hook: Some(format!("test -e {}", ready.display())),
+                ..Default::default()
+            },
+            LocalIps::default(),
+            None,
+        );
+
+        backend.apply_rules([rule("10.0.0.1", 0)]).unwrap();
+        assert!(backend.commit().is_err());
+
+        // the ruleset did not change, the hook still has to run
+        fs::write(&ready, "").unwrap();
+        backend.commit().unwrap();
+
+        fs::remove_file(&ready).unwrap();
+        backend.commit().unwrap();
+    }
+
+    #[test]
+    fn it_writes_nothing_in_dry_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("epok.rules");
+        let mut backend = FileBackend::new(
+            DryRun(LocalExecutor),
+            FileOpts {
+                output_path: Some(path.clone()),
+                post_write_hook: Some(format!(
+                    "touch {}",
+                    path.with_extension("hook").display()
+                )),
+                ..Default::default()
+            },
+            LocalIps::default(),
+            None,
+        );
+
+        backend.apply_rules([rule("10.0.0.1", 0)]).unwrap();
+        backend.commit().unwrap();
+        assert!(!path.exists());
+        assert!(!path.with_extension("hook").exists());
+    }
+}
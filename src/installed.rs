@@ -0,0 +1,152 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{res::Proto, IpFamily, Rule, RULE_MARKER};
+
+/// A rule as it is currently installed on the host, parsed back from the
+/// backend's own listing (`iptables-save`, `nft list`, ...).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstalledRule {
+    pub family: IpFamily,
+    pub chain: String,
+    pub interface: Option<String>,
+    pub proto: Option<Proto>,
+    pub host_port: Option<u16>,
+    pub destination: Option<String>,
+    pub comment: String,
+    pub rule_id: String,
+}
+
+impl InstalledRule {
+    /// Describes `rule` the way a backend would report it once installed.
+    pub fn of(rule: &Rule, chain: &str, config_hash: &str) -> Self {
+        Self {
+            family: rule.family,
+            chain: chain.to_owned(),
+            interface: Some(rule.interface.name.to_owned()),
+            proto: Some(rule.port_spec.proto),
+            host_port: Some(rule.port_spec.host_port),
+            destination: Some(format!(
+                "{}:{}",
+                rule.dest_addr, rule.port_spec.dest_port
+            )),
+            comment: rule.comment.to_owned().unwrap_or_default(),
+            rule_id: rule.rule_id(config_hash),
+        }
+    }
+
+    /// Rule ids look like `{config_hash}::{kind}::{owner_hash}::{rule_hash}`
+    fn id_part(&self, index: usize) -> Option<&str> {
+        self.rule_id.split("::").nth(index)
+    }
+
+    pub fn config_hash(&self) -> Option<&str> { self.id_part(0) }
+
+    pub fn service_hash(&self) -> Option<&str> {
+        (self.id_part(1)? == "service").then(|| self.id_part(2)).flatten()
+    }
+
+    pub fn pod_hash(&self) -> Option<&str> {
+        (self.id_part(1)? == "pod").then(|| self.id_part(2)).flatten()
+    }
+
+    pub fn is_pod_rule(&self) -> bool { self.pod_hash().is_some() }
+}
+
+/// Renders the full comment of a rule, dropping the characters that would
+/// end the quoted string it is installed in.
+pub(crate) fn rule_comment(comment: Option<&str>, rule_id: &str) -> String {
+    let human = comment.unwrap_or_default().replace(['"', '\\'], "");
+    format!("{human}; {RULE_MARKER}: {rule_id}")
+}
+
+/// Splits a full rule comment into its human readable part and rule id.
+pub(crate) fn split_comment(comment: &str) -> Option<(String, String)> {
+    let (human, rule_id) = comment.split_once(&format!("{RULE_MARKER}: "))?;
+    Some((
+        human.trim().trim_end_matches(';').trim().to_owned(),
+        rule_id.trim().to_owned(),
+    ))
+}
+
+/// Splits a ruleset line on whitespace, keeping double-quoted strings
+/// (with backslash escapes) together as a single unquoted token.
+pub(crate) fn split_quoted(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let (mut quoted, mut escaped, mut started) = (false, false, false);
+
+    for c in line.chars() {
+        match c {
+            _ if escaped => {
+                token.push(c);
+                escaped = false;
+            }
+            '\\' if quoted => escaped = true,
+            '"' => {
+                quoted = !quoted;
+                started = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if started {
+                    tokens.push(std::mem::take(&mut token));
+                    started = false;
+                }
+            }
+            c => {
+                token.push(c);
+                started = true;
+            }
+        }
+    }
+    if started {
+        tokens.push(token);
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_keeps_comments_quotable() {
+        let comment = rule_comment(Some("service: a\"b\\/c"), "x::service::y");
+        assert_eq!(comment, "service: ab/c; epok_rule_id: x::service::y");
+        assert_eq!(
+            split_comment(&comment),
+            Some(("service: ab/c".to_owned(), "x::service::y".to_owned()))
+        );
+    }
+
+    #[test]
+    fn it_splits_quoted_tokens() {
+        assert_eq!(
+            split_quoted(r#"-A X --comment "a \"b\"; c" -j  DNAT"#),
+            vec!["-A", "X", "--comment", r#"a "b"; c"#, "-j", "DNAT"]
+        );
+    }
+
+    #[test]
+    fn it_parses_rule_ids() {
+        let (comment, rule_id) = split_comment(
+            "service: foo/bar; node: n0; epok_rule_id: cfg::service::svc::r",
+        )
+        .unwrap();
+        assert_eq!(comment, "service: foo/bar; node: n0");
+
+        let rule = InstalledRule {
+            family: IpFamily::V4,
+            chain: "EPOK-PREROUTING".to_owned(),
+            interface: None,
+            proto: None,
+            host_port: None,
+            destination: None,
+            comment,
+            rule_id,
+        };
+        assert_eq!(rule.config_hash(), Some("cfg"));
+        assert_eq!(rule.service_hash(), Some("svc"));
+        assert_eq!(rule.pod_hash(), None);
+        assert!(!rule.is_pod_rule());
+    }
+}
@@ -1,153 +1,757 @@
-use std::cmp::Reverse;
+use std::{cmp::Reverse, collections::HashSet};
 
 use itertools::Itertools;
 use sha256::digest;
 
 use crate::{
-    res::Proto, Backend, BatchOpts, Error, Executor, PortSpec, Result, Rule,
-    RULE_MARKER,
+    installed::{rule_comment, split_comment, split_quoted},
+    logging::*,
+    res::Proto,
+    Backend, Cmd, CommandOpts, Error, Executor, InstalledRule, IpFamilies,
+    IpFamily, JumpPosition, LocalIps, Result, Rule, RULE_MARKER,
+    SSH_CONNECTION_FAILED, XTABLES_LOCKED,
 };
 
-pub struct IptablesBackend {
-    executor: Executor,
-    batch_opts: BatchOpts,
-    rule_state: String,
-    local_ip: Option<String>,
+pub const EPOK_PREROUTING: &str = "EPOK-PREROUTING";
+pub const EPOK_OUTPUT: &str = "EPOK-OUTPUT";
+
+/// Built-in nat chains paired with the epok chain they jump to.
+const EPOK_CHAINS: [(&str, &str); 2] =
+    [("PREROUTING", EPOK_PREROUTING), ("OUTPUT", EPOK_OUTPUT)];
+
+/// Wraps a failed command in the error telling what went wrong.
+type Failure = fn(Box<Error>) -> Error;
+
+/// What iptables says on stderr for the failures the operator acts on.
+const IPTABLES_FAILURES: [(&str, Failure); 8] = [
+    (XTABLES_LOCKED, Error::XtablesLocked),
+    ("No chain/target/match by that name", Error::IptablesNoChain),
+    ("Couldn't load target", Error::IptablesNoChain),
+    ("Permission denied (you must be root)", Error::IptablesPermissionDenied),
+    ("Bad argument `", Error::IptablesBadRule),
+    ("Bad rule (does a matching rule exist", Error::IptablesBadRule),
+    ("Error occurred at line: ", Error::IptablesBadRule),
+    // `iptables-restore: line 3 failed`, from either family
+    ("tables-restore: line ", Error::IptablesBadRule),
+];
+
+pub struct IptablesBackend<E = Box<dyn Executor>> {
+    executor: E,
+    v4: NatTable,
+    v6: NatTable,
+    local_ips: LocalIps,
     extra_ips: Option<String>,
+    jump_position: JumpPosition,
+    command_opts: CommandOpts,
 }
 
-impl Backend for IptablesBackend {
-    fn read_state(&mut self) {
-        self.rule_state = self
-            .executor
-            .run_fun(format!("sudo iptables-save -t nat | grep {RULE_MARKER}"))
-            .unwrap_or_else(|_| "".to_owned());
+/// What we know about the nat table of one address family.
+#[derive(Default)]
+struct NatTable {
+    /// Installed epok rules along with the `iptables-save` line they were
+    /// parsed from, which is what `-D` needs to match them.
+    rules: Vec<(InstalledRule, String)>,
+    chain_state: String,
+    pending: Vec<String>,
+}
+
+impl<E: Executor> Backend for IptablesBackend<E> {
+    fn read_state(&mut self) -> Result<Vec<InstalledRule>> {
+        let mut installed = Vec::new();
+        for family in IpFamilies::Both.iter() {
+            let save = self.command_opts.privileged(
+                Cmd::new(self.command_opts.iptables_save(family))
+                    .args(["-t", "nat"]),
+            );
+            let nat_state = match self.executor.run(&save) {
+                Ok(nat_state) => nat_state,
+                Err(e)
+                    if self.local_ips.is_optional(family)
+                        && e.is_unsupported() =>
+                {
+                    debug!("no {family:?} nat table to read: {e}");
+                    String::new()
+                }
+                // an empty ruleset would have every rule installed again
+                Err(e) => {
+                    let e = iptables_error(e);
+                    return Err(Error::BackendError(Box::new(e)));
+                }
+            };
+            let table = self.table_mut(family);
+            table.rules = nat_state
+                .lines()
+                .filter_map(|line| {
+                    Some((parse_save_line(family, line)?, line.to_owned()))
+                })
+                .collect();
+            installed.extend(table.rules.iter().map(|(rule, _)| rule.clone()));
+            table.chain_state = nat_state
+                .lines()
+                .filter(|l| {
+                    EPOK_CHAINS.iter().any(|(_, chain)| {
+                        l.starts_with(&format!(":{chain} "))
+                            || l.ends_with(&format!("-j {chain}"))
+                    })
+                })
+                .join("\n");
+        }
+        Ok(installed)
     }
 
     fn apply_rules(
         &mut self,
         rules: impl IntoIterator<Item = Rule>,
     ) -> Result<()> {
-        self.executor
-            .run_commands(
-                rules
-                    .into_iter()
-                    .filter(|rule| {
-                        !self
-                            .rule_state
-                            .contains(&rule.rule_id(&self.config_hash()))
-                    })
-                    .sorted_unstable_by_key(|r| Reverse(r.nth))
-                    .map(|rule| {
-                        self.iptables_statement(
-                            &rule.port_spec,
-                            &rule,
-                            &self.local_ip,
-                        )
-                    })
-                    .map(|stmt| format!("sudo iptables -w -t nat -A {stmt}")),
-                &self.batch_opts,
-            )
-            .map_err(|e| Error::BackendError(Box::new(e)))?;
+        let config_hash = self.config_hash();
+        let additions = rules
+            .into_iter()
+            .filter(|rule| {
+                let rule_id = rule.rule_id(&config_hash);
+                !self.table(rule.family).is_installed(&rule_id)
+            })
+            .filter(|rule| self.can_install(rule))
+            .sorted_unstable_by_key(|r| Reverse(r.nth))
+            .map(|rule| {
+                let stmt =
+                    iptables_statement(&rule, &self.local_ips, &config_hash);
+                (rule.family, stmt)
+            })
+            .collect::<Vec<_>>();
+
+        for family in IpFamilies::Both.iter() {
+            let lines = additions
+                .iter()
+                .filter(|(f, _)| *f == family)
+                .map(|(_, stmt)| format!("-A {stmt}"))
+                .collect::<Vec<_>>();
+            if lines.is_empty() {
+                continue;
+            }
+            let jump_position = self.jump_position;
+            let table = self.table_mut(family);
+            table.stage_setup(jump_position);
+            table.stage(lines);
+        }
         Ok(())
     }
 
-    fn delete_rules<P>(&mut self, pred: P) -> Result<()>
-    where
-        P: FnMut(&&str) -> bool,
-    {
-        self.executor
-            .run_commands(
-                self.rule_state.lines().filter(pred).map(append_to_delete),
-                &self.batch_opts,
-            )
-            .map_err(|e| Error::BackendError(Box::new(e)))
+    fn delete_rules(
+        &mut self,
+        rule_ids: impl IntoIterator<Item = String>,
+    ) -> Result<()> {
+        let rule_ids = rule_ids.into_iter().collect::<HashSet<_>>();
+        for table in [&mut self.v4, &mut self.v6] {
+            let deletions = table
+                .rules
+                .iter()
+                .filter(|(rule, _)| rule_ids.contains(&rule.rule_id))
+                .map(|(_, line)| append_to_delete(line))
+                .collect::<Vec<_>>();
+            table.stage(deletions);
+        }
+        Ok(())
+    }
+
+    fn teardown(&mut self) -> Result<()> {
+        for table in [&mut self.v4, &mut self.v6] {
+            let mut lines = Vec::new();
+            for (builtin, chain) in EPOK_CHAINS {
+                if table.has_jump(chain) {
+                    lines.push(format!("-D {builtin} {}", jump_spec(chain)));
+                }
+                if table.has_chain(chain) {
+                    lines.push(format!("-F {chain}"));
+                    lines.push(format!("-X {chain}"));
+                }
+            }
+            table.stage(lines);
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        for family in IpFamilies::Both.iter() {
+            let table = self.table_mut(family);
+            if table.pending.is_empty() {
+                continue;
+            }
+            let payload = restore_payload(table.pending.drain(..));
+            self.executor
+                .run_with_stdin(
+                    &self.command_opts.privileged(
+                        Cmd::new(self.command_opts.iptables_restore(family))
+                            .args(["-w", "--noflush"]),
+                    ),
+                    &payload,
+                )
+                .map_err(|e| {
+                    Error::BackendError(Box::new(iptables_error(e)))
+                })?;
+        }
+        Ok(())
+    }
+
+    fn can_install(&self, rule: &Rule) -> bool {
+        rule.interface.name != "lo"
+            || self.local_ips.get(rule.family).is_some()
+    }
+
+    /// Restores the chains and jumps the installed rules rely on, e.g.
+    /// after someone flushed the built-in chains.
+    fn repair_setup(&mut self) -> usize {
+        let jump_position = self.jump_position;
+        let mut repairs = 0;
+        for table in [&mut self.v4, &mut self.v6] {
+            if table.rules.is_empty() {
+                continue;
+            }
+            for line in table.stage_setup(jump_position) {
+                warn!("drift: restoring `{line}`");
+                repairs += 1;
+            }
+        }
+        repairs
     }
 
     fn config_hash(&self) -> String {
-        let mut config_hash =
-            digest(format!("{:?}::{:?}", self.local_ip, self.extra_ips));
+        // the chain name is part of the hash so that rules left behind in
+        // the built-in chains by older versions get replaced
+        let mut config_hash = digest(format!(
+            "{:?}::{:?}::{EPOK_PREROUTING}",
+            self.local_ips, self.extra_ips
+        ));
         config_hash.truncate(16);
         config_hash
     }
 }
 
-impl IptablesBackend {
+impl<E: Executor> IptablesBackend<E> {
     pub fn new(
-        executor: Executor,
-        batch_opts: BatchOpts,
-        local_ip: Option<String>,
+        executor: E,
+        local_ips: LocalIps,
         extra_ips: Option<String>,
+        jump_position: JumpPosition,
+        command_opts: CommandOpts,
     ) -> Self {
         Self {
             executor,
-            batch_opts,
-            rule_state: Default::default(),
-            local_ip,
+            v4: NatTable::default(),
+            v6: NatTable::default(),
+            local_ips,
             extra_ips,
+            jump_position,
+            command_opts,
         }
     }
 
-    fn iptables_statement(
-        &self,
-        port_spec: &PortSpec,
-        rule: &Rule,
-        local_ip: &Option<String>,
-    ) -> String {
-        let (host_port, node_port) =
-            (port_spec.host_port, port_spec.dest_port);
-        let d_ip = match local_ip {
-            None => "".to_owned(),
-            Some(ip) => format!("-d {ip}"),
-        };
-        let s_range = match &rule.allow_range {
-            None => "".to_owned(),
-            Some(s) => format!("-s {s}"),
-        };
-        let (proto, state) = match &port_spec.proto {
-            Proto::Tcp => ("-p tcp", "-m state --state NEW"),
-            Proto::Udp => ("-p udp", ""),
-        };
+    fn table(&self, family: IpFamily) -> &NatTable {
+        match family {
+            IpFamily::V4 => &self.v4,
+            IpFamily::V6 => &self.v6,
+        }
+    }
+
+    fn table_mut(&mut self, family: IpFamily) -> &mut NatTable {
+        match family {
+            IpFamily::V4 => &mut self.v4,
+            IpFamily::V6 => &mut self.v6,
+        }
+    }
+}
+
+impl NatTable {
+    fn is_installed(&self, rule_id: &str) -> bool {
+        self.rules.iter().any(|(rule, _)| rule.rule_id == rule_id)
+    }
+
+    fn has_chain(&self, chain: &str) -> bool {
+        self.chain_state.lines().any(|l| l.starts_with(&format!(":{chain} ")))
+    }
+
+    fn has_jump(&self, chain: &str) -> bool {
+        self.chain_state.lines().any(|l| l.ends_with(&format!("-j {chain}")))
+    }
+
+    /// Creates the epok chains and the jumps into them when missing.
+    /// Chains are created with `-N` rather than declared, because
+    /// declaring an existing chain flushes it even under `--noflush`.
+    fn setup_lines(&self, jump_position: JumpPosition) -> Vec<String> {
+        let mut lines = Vec::new();
+        for (builtin, chain) in EPOK_CHAINS {
+            if !self.has_chain(chain) {
+                lines.push(format!("-N {chain}"));
+            }
+            if !self.has_jump(chain) {
+                lines.push(match jump_position {
+                    JumpPosition::Insert => {
+                        format!("-I {builtin} 1 {}", jump_spec(chain))
+                    }
+                    JumpPosition::Append => {
+                        format!("-A {builtin} {}", jump_spec(chain))
+                    }
+                });
+            }
+        }
+        lines
+    }
+
+    /// Stages the missing setup lines and records them in the chain state,
+    /// so that they are staged once per commit. Returns the lines.
+    fn stage_setup(&mut self, jump_position: JumpPosition) -> Vec<String> {
+        let lines = self.setup_lines(jump_position);
+        for line in &lines {
+            let state = match line.strip_prefix("-N ") {
+                Some(chain) => format!(":{chain} - [0:0]"),
+                None => line.to_owned(),
+            };
+            self.chain_state.push('\n');
+            self.chain_state.push_str(&state);
+        }
+        self.stage(lines.clone());
+        lines
+    }
 
-        let (chain, selector) = match rule.interface.name.as_str() {
-            "lo" => (
-                "OUTPUT",
-                format!(
-                    "-o lo -d {local_ip} {proto} --dport {host_port} {state}",
-                    local_ip = local_ip
-                        .as_ref()
-                        .expect("should not have a local rule without local IP")
-                ),
+    /// Queues restore lines for the next commit, skipping the ones that
+    /// are already queued since `iptables-restore` fails the whole
+    /// transaction on a duplicate deletion.
+    fn stage(&mut self, lines: Vec<String>) {
+        for line in lines {
+            if !self.pending.contains(&line) {
+                self.pending.push(line);
+            }
+        }
+    }
+}
+
+/// Renders `rule` as an `iptables-restore` line without the leading `-A`.
+pub(crate) fn iptables_statement(
+    rule: &Rule,
+    local_ips: &LocalIps,
+    config_hash: &str,
+) -> String {
+    let port_spec = &rule.port_spec;
+    let local_ip = local_ips.get(rule.family);
+    let (host_port, node_port) = (port_spec.host_port, port_spec.dest_port);
+    let d_ip = match local_ip {
+        None => "".to_owned(),
+        Some(ip) => format!("-d {ip}"),
+    };
+    let s_range = match &rule.allow_range {
+        None => "".to_owned(),
+        Some(s) => format!("-s {s}"),
+    };
+    let (proto, state) = match &port_spec.proto {
+        Proto::Tcp => ("-p tcp", "-m state --state NEW"),
+        Proto::Udp => ("-p udp", ""),
+    };
+
+    let (chain, selector) = match rule.interface.name.as_str() {
+        "lo" => (
+            EPOK_OUTPUT,
+            format!(
+                "-o lo -d {local_ip} {proto} --dport {host_port} {state}",
+                local_ip = local_ip
+                    .expect("should not have a local rule without local IP")
             ),
-            _ => (
-                "PREROUTING",
-                format!(
-                    "-i {interface} {s_range} {d_ip} {proto} --dport {host_port} {state}",
-                    interface = rule.interface.name,
-                ),
+        ),
+        _ => (
+            EPOK_PREROUTING,
+            format!(
+                "-i {interface} {s_range} {d_ip} {proto} --dport {host_port} {state}",
+                interface = rule.interface.name,
             ),
-        };
-        let balance = match rule.nth {
-            0 => "".to_owned(),
-            i => {
-                format!("-m statistic --mode nth --every {} --packet 0", i + 1)
+        ),
+    };
+    let balance = match rule.nth {
+        0 => "".to_owned(),
+        i => {
+            format!("-m statistic --mode nth --every {} --packet 0", i + 1)
+        }
+    };
+    let comment = format!(
+        "-m comment --comment \"{}\"",
+        rule_comment(rule.comment.as_deref(), &rule.rule_id(config_hash)),
+    );
+    let jump = format!(
+        "-j DNAT --to-destination {node_addr}:{node_port}",
+        node_addr = match rule.family {
+            IpFamily::V4 => rule.dest_addr.to_owned(),
+            IpFamily::V6 => format!("[{}]", rule.dest_addr),
+        },
+    );
+    format!("{chain} {selector} {balance} {comment} {jump}")
+}
+
+fn jump_spec(chain: &str) -> String {
+    format!("-m comment --comment epok -j {chain}")
+}
+
+/// Parses an epok rule out of an `iptables-save` line.
+fn parse_save_line(family: IpFamily, line: &str) -> Option<InstalledRule> {
+    if !line.contains(RULE_MARKER) {
+        return None;
+    }
+    let tokens = split_quoted(line);
+    let mut rule = InstalledRule {
+        family,
+        chain: String::new(),
+        interface: None,
+        proto: None,
+        host_port: None,
+        destination: None,
+        comment: String::new(),
+        rule_id: String::new(),
+    };
+    for (flag, value) in tokens.iter().tuple_windows() {
+        match flag.as_str() {
+            "-A" => rule.chain = value.to_owned(),
+            "-i" | "-o" => rule.interface = Some(value.to_owned()),
+            "-p" => {
+                rule.proto = match value.as_str() {
+                    "tcp" => Some(Proto::Tcp),
+                    "udp" => Some(Proto::Udp),
+                    _ => None,
+                }
             }
-        };
-        let comment = format!(
-            "-m comment --comment '{}; {RULE_MARKER}: {}'",
-            rule.comment.as_ref().unwrap_or(&"".to_owned()),
-            rule.rule_id(&self.config_hash()),
-        );
-        let jump = format!(
-            "-j DNAT --to-destination {node_addr}:{node_port}",
-            node_addr = rule.dest_addr,
-        );
-        format!("{chain} {selector} {balance} {comment} {jump}")
+            "--dport" => rule.host_port = value.parse().ok(),
+            "--to-destination" => rule.destination = Some(value.to_owned()),
+            "--comment" => {
+                (rule.comment, rule.rule_id) = split_comment(value)?;
+            }
+            _ => {}
+        }
     }
+    (!rule.rule_id.is_empty()).then_some(rule)
 }
 
 fn append_to_delete(rule: &str) -> String {
     let mut rule_parts = rule.split(' ').collect::<Vec<_>>();
     rule_parts.remove(0);
-    format!("sudo iptables -w -t nat -D {}", rule_parts.join(" "))
+    format!("-D {}", rule_parts.join(" "))
+}
+
+/// Wraps rule changes in a single nat table transaction for
+/// `iptables-restore --noflush`.
+fn restore_payload(lines: impl Iterator<Item = String>) -> String {
+    format!("*nat\n{}\nCOMMIT\n", lines.format("\n"))
+}
+
+/// Tells the well-known iptables failures apart by what iptables said,
+/// leaving alone the ssh connection failures, which may go away.
+fn iptables_error(e: Error) -> Error {
+    let Error::CommandFailed { code, stderr, .. } = &e else { return e };
+    if *code == Some(SSH_CONNECTION_FAILED) {
+        return e;
+    }
+    match IPTABLES_FAILURES.iter().find(|(text, _)| stderr.contains(text)) {
+        Some((_, variant)) => variant(Box::new(e)),
+        None => e,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{Interface, PortSpec, Privilege};
+
+    /// Answers `iptables-save` with `saved` and records everything else.
+    #[derive(Default)]
+    struct FakeExecutor {
+        saved: String,
+        ran: Mutex<Vec<String>>,
+        unreachable: bool,
+        /// Whether the host has IPv6 turned off
+        ipv4_only: bool,
+    }
+
+    impl Executor for FakeExecutor {
+        fn run(&self, cmd: &Cmd) -> Result<String> {
+            if self.unreachable {
+                return Err(Error::CommandFailed {
+                    host: "hv1".to_owned(),
+                    cmd: cmd.to_string(),
+                    code: Some(SSH_CONNECTION_FAILED),
+                    stderr: "Connection refused".to_owned(),
+                });
+            }
+            match cmd.to_string().as_str() {
+                "doas /sbin/iptables-legacy-save -t nat" => {
+                    Ok(self.saved.clone())
+                }
+                "doas ip6tables-save -t nat" if self.ipv4_only => {
+                    Err(Error::CommandFailed {
+                        host: "hv1".to_owned(),
+                        cmd: cmd.to_string(),
+                        code: Some(1),
+                        stderr: "ip6tables-save v1.8.7 (legacy): can't \
+                                 initialize ip6tables table `nat': Address \
+                                 family not supported by protocol"
+                            .to_owned(),
+                    })
+                }
+                _ => Ok(String::new()),
+            }
+        }
+
+        fn run_with_stdin(&self, cmd: &Cmd, stdin: &str) -> Result<String> {
+            self.ran.lock().unwrap().push(format!("{cmd}\n{stdin}"));
+            Ok(String::new())
+        }
+    }
+
+    fn backend(executor: FakeExecutor) -> IptablesBackend<FakeExecutor> {
+        IptablesBackend::new(
+            executor,
+            LocalIps::default(),
+            None,
+            JumpPosition::Append,
+            CommandOpts {
+                privilege: Privilege::Doas,
+                iptables_binary: "/sbin/iptables-legacy".to_owned(),
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn it_sets_up_missing_chains() {
+        let table = NatTable {
+            chain_state: ":EPOK-OUTPUT - [0:0]".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(
+            table.setup_lines(JumpPosition::Insert),
+            vec![
+                "-N EPOK-PREROUTING",
+                "-I PREROUTING 1 -m comment --comment epok -j EPOK-PREROUTING",
+                "-I OUTPUT 1 -m comment --comment epok -j EPOK-OUTPUT",
+            ]
+        );
+    }
+
+    #[test]
+    fn it_tears_down_chains() {
+        let mut backend = backend(FakeExecutor::default());
+        backend.v4.chain_state = [
+            ":EPOK-PREROUTING - [0:0]",
+            ":EPOK-OUTPUT - [0:0]",
+            "-A PREROUTING -m comment --comment epok -j EPOK-PREROUTING",
+        ]
+        .join("\n");
+        backend.teardown().unwrap();
+        assert!(backend.v6.pending.is_empty());
+        assert_eq!(
+            backend.v4.pending,
+            vec![
+                "-D PREROUTING -m comment --comment epok -j EPOK-PREROUTING",
+                "-F EPOK-PREROUTING",
+                "-X EPOK-PREROUTING",
+                "-F EPOK-OUTPUT",
+                "-X EPOK-OUTPUT",
+            ]
+        );
+    }
+
+    #[test]
+    fn it_brackets_ipv6_destinations() {
+        let local_ips = LocalIps { v4: None, v6: Some("fd00::1".to_owned()) };
+        let stmt = iptables_statement(
+            &Rule {
+                dest_addr: "fd00::10".to_owned(),
+                family: IpFamily::V6,
+                allow_range: None,
+                port_spec: PortSpec::new_tcp(80, 30080),
+                interface: crate::Interface::new("eth0"),
+                nth: 0,
+                out_of: 1,
+                comment: None,
+                rule_hash: "x".to_owned(),
+            },
+            &local_ips,
+            "cfg",
+        );
+        assert!(stmt.starts_with("EPOK-PREROUTING -i eth0"));
+        assert!(stmt.contains("-d fd00::1 "));
+        assert!(stmt.ends_with("--to-destination [fd00::10]:30080"));
+    }
+
+    #[test]
+    fn it_parses_save_lines() {
+        let line = "-A EPOK-PREROUTING -i eth0 -p tcp -m tcp --dport 80 \
+                    -m state --state NEW \
+                    -m comment --comment \"service: foo/bar; node: n0; \
+                    epok_rule_id: cfg::service::svc::r\" \
+                    -j DNAT --to-destination 10.0.0.1:30080";
+        assert_eq!(
+            parse_save_line(IpFamily::V4, line),
+            Some(InstalledRule {
+                family: IpFamily::V4,
+                chain: "EPOK-PREROUTING".to_owned(),
+                interface: Some("eth0".to_owned()),
+                proto: Some(Proto::Tcp),
+                host_port: Some(80),
+                destination: Some("10.0.0.1:30080".to_owned()),
+                comment: "service: foo/bar; node: n0".to_owned(),
+                rule_id: "cfg::service::svc::r".to_owned(),
+            })
+        );
+        assert_eq!(
+            parse_save_line(IpFamily::V4, "-A PREROUTING -j DOCKER"),
+            None
+        );
+    }
+
+    #[test]
+    fn it_builds_a_single_transaction() {
+        let saved = "-A PREROUTING -i eth0 -p tcp -m tcp --dport 80 \
+                     -m comment --comment \"foo; epok_rule_id: x\" \
+                     -j DNAT --to-destination 10.0.0.1:30080";
+        let payload = restore_payload(
+            [append_to_delete(saved), "-A OUTPUT -o lo".to_owned()]
+                .into_iter(),
+        );
+        assert_eq!(
+            payload,
+            "*nat\n\
+             -D PREROUTING -i eth0 -p tcp -m tcp --dport 80 \
+             -m comment --comment \"foo; epok_rule_id: x\" \
+             -j DNAT --to-destination 10.0.0.1:30080\n\
+             -A OUTPUT -o lo\n\
+             COMMIT\n"
+        );
+    }
+
+    #[test]
+    fn it_commits_through_the_executor() {
+        let mut backend = backend(FakeExecutor {
+            saved: ":EPOK-PREROUTING - [0:0]\n\
+                    -A PREROUTING -m comment --comment epok \
+                    -j EPOK-PREROUTING"
+                .to_owned(),
+            ..Default::default()
+        });
+        assert!(backend.read_state().unwrap().is_empty());
+
+        let rule = Rule {
+            dest_addr: "10.0.0.1".to_owned(),
+            family: IpFamily::V4,
+            allow_range: None,
+            port_spec: PortSpec::new_tcp(80, 30080),
+            interface: Interface::new("eth0"),
+            nth: 0,
+            out_of: 1,
+            comment: None,
+            rule_hash: "hash".to_owned(),
+        };
+        backend.apply_rules([rule]).unwrap();
+        backend.commit().unwrap();
+
+        let ran = backend.executor.ran.into_inner().unwrap();
+        assert_eq!(ran.len(), 1);
+        let lines = ran[0].lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines[..4],
+            [
+                "doas /sbin/iptables-legacy-restore -w --noflush",
+                "*nat",
+                "-N EPOK-OUTPUT",
+                "-A OUTPUT -m comment --comment epok -j EPOK-OUTPUT",
+            ]
+        );
+        assert!(lines[4].starts_with("-A EPOK-PREROUTING -i eth0"));
+        assert_eq!(lines[5], "COMMIT");
+    }
+
+    #[test]
+    fn it_restores_flushed_jumps() {
+        let mut backend = backend(FakeExecutor {
+            saved: ":EPOK-PREROUTING - [0:0]\n\
+                    :EPOK-OUTPUT - [0:0]\n\
+                    -A EPOK-PREROUTING -i eth0 -p tcp -m tcp --dport 80 \
+                    -m comment --comment \"service: foo/bar; \
+                    epok_rule_id: cfg::service::svc::r\" \
+                    -j DNAT --to-destination 10.0.0.1:30080"
+                .to_owned(),
+            ..Default::default()
+        });
+        assert_eq!(backend.read_state().unwrap().len(), 1);
+        assert_eq!(backend.repair_setup(), 2);
+        backend.commit().unwrap();
+
+        let ran = backend.executor.ran.into_inner().unwrap();
+        assert_eq!(
+            ran,
+            ["doas /sbin/iptables-legacy-restore -w --noflush\n\
+              *nat\n\
+              -A PREROUTING -m comment --comment epok -j EPOK-PREROUTING\n\
+              -A OUTPUT -m comment --comment epok -j EPOK-OUTPUT\n\
+              COMMIT\n"]
+        );
+    }
+
+    #[test]
+    fn it_fails_to_read_an_unreachable_ruleset() {
+        let mut backend =
+            backend(FakeExecutor { unreachable: true, ..Default::default() });
+        let err = backend.read_state().unwrap_err();
+        assert!(err.is_transient(), "{err}");
+    }
+
+    #[test]
+    fn it_reads_an_unsupported_ipv6_table_as_empty() {
+        let mut backend =
+            backend(FakeExecutor { ipv4_only: true, ..Default::default() });
+        assert!(backend.read_state().unwrap().is_empty());
+
+        // unless there is an IPv6 address to forward from
+        backend.local_ips.v6 = Some("fd00::1".to_owned());
+        let err = backend.read_state().unwrap_err();
+        assert!(!err.is_transient(), "{err}");
+    }
+
+    #[test]
+    fn it_tells_iptables_failures_apart() {
+        let exited = |code: i32, stderr: &str| Error::CommandFailed {
+            host: "localhost".to_owned(),
+            cmd: "sudo iptables-restore -w --noflush".to_owned(),
+            code: Some(code),
+            stderr: stderr.to_owned(),
+        };
+        let failed = |stderr: &str| exited(4, stderr);
+        let locked = iptables_error(failed(
+            "Another app is currently holding the xtables lock. \
+             Stopped waiting after 5s.",
+        ));
+        assert!(matches!(locked, Error::XtablesLocked(_)));
+        assert!(locked.is_transient());
+        assert!(locked.to_string().ends_with("Stopped waiting after 5s."));
+
+        let bad_rule = iptables_error(failed(
+            "iptables-restore v1.8.7 (legacy): Bad argument `30080'\n\
+             Error occurred at line: 2",
+        ));
+        assert!(matches!(bad_rule, Error::IptablesBadRule(_)));
+        assert!(!bad_rule.is_transient());
+        let other = iptables_error(failed("oops"));
+        assert!(matches!(other, Error::CommandFailed { .. }));
+
+        let denied = iptables_error(failed(
+            "iptables v1.8.7 (legacy): can't initialize iptables table \
+             `nat': Permission denied (you must be root)",
+        ));
+        assert!(matches!(denied, Error::IptablesPermissionDenied(_)));
+
+        // ssh failing is no iptables failure, whatever it said
+        for stderr in [
+            "user@host: Permission denied (publickey).",
+            "mux_client_request_session: session request failed",
+        ] {
+            let ssh = iptables_error(exited(SSH_CONNECTION_FAILED, stderr));
+            assert!(matches!(ssh, Error::CommandFailed { .. }));
+            assert!(ssh.is_transient());
+        }
+    }
 }
@@ -0,0 +1,295 @@
+use k8s_openapi::{
+    api::coordination::v1::{Lease, LeaseSpec},
+    apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta},
+    chrono::{DateTime, TimeDelta, Utc},
+};
+use kube::{api::PostParams, Api, Client};
+use tokio::{
+    sync::{oneshot, watch},
+    task::JoinHandle,
+    time::{interval, Duration, Instant, MissedTickBehavior},
+};
+
+use crate::{logging::*, LeaderOpts, LOCALHOST};
+
+/// Leader election through a `coordination.k8s.io` Lease: only the replica
+/// holding the Lease reconciles, the others just keep their state warm.
+pub struct LeaderElection {
+    api: Api<Lease>,
+    name: String,
+    identity: String,
+    duration: Duration,
+    /// When the Lease was last acquired or renewed, while it is ours
+    renewed: Option<Instant>,
+}
+
+impl LeaderElection {
+    pub fn new(client: Client, opts: &LeaderOpts) -> Self {
+        let api = match &opts.lease_namespace {
+            Some(namespace) => Api::namespaced(client, namespace),
+            None => Api::default_namespaced(client),
+        };
+        // in a pod, the host name is the pod name
+        let identity = opts
+            .lease_identity
+            .clone()
+            .or_else(|| std::env::var("HOSTNAME").ok())
+            .unwrap_or_else(|| format!("{LOCALHOST}-{}", std::process::id()));
+        Self {
+            api,
+            name: opts.lease_name.to_owned(),
+            identity,
+            duration: Duration::from_secs(opts.lease_duration.max(1)),
+            renewed: None,
+        }
+    }
+
+    pub fn identity(&self) -> &str { &self.identity }
+
+    /// Acquires or renews the Lease every `renew_interval` on a task of
+    /// its own, so that a slow reconcile can't hold up the renewals.
+    pub fn spawn(mut self, renew_interval: Duration) -> Leadership {
+        let (valid_until, leadership) = watch::channel(None);
+        let (release, mut released) = oneshot::channel();
+        let task = tokio::spawn(async move {
+            let mut renew = interval(renew_interval);
+            renew.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    _ = renew.tick() => {
+                        self.elect().await;
+                        valid_until.send_replace(
+                            self.renewed.map(|at| at + self.duration),
+                        );
+                    }
+                    _ = &mut released => break,
+                }
+            }
+            self.release().await;
+        });
+        Leadership { valid_until: leadership, release: Some(release), task }
+    }
+
+    /// Acquires or renews the Lease, returning whether this replica leads.
+    /// A replica keeps leading through failed renewals for as long as its
+    /// Lease would stay valid.
+    async fn elect(&mut self) -> bool {
+        // counting from before the round trip, this replica never thinks
+        // the Lease valid for longer than the others do
+        let now = Instant::now();
+        match self.try_elect().await {
+            Ok(true) => {
+                self.renewed = Some(now);
+                true
+            }
+            Ok(false) => {
+                self.renewed = None;
+                false
+            }
+            Err(e) => {
+                warn!("could not renew the lease {}: {e}", self.name);
+                if self.renewed.is_some_and(|at| at.elapsed() < self.duration)
+                {
+                    return true;
+                }
+                self.renewed = None;
+                false
+            }
+        }
+    }
+
+    /// Gives the Lease up if this replica holds it, so that another one
+    /// can take over without waiting for it to expire.
+    async fn release(&mut self) {
+        if self.renewed.take().is_none() {
+            return;
+        }
+        match self.try_release().await {
+            Ok(()) => info!("released the lease {}", self.name),
+            Err(e) => warn!("could not release the lease {}: {e}", self.name),
+        }
+    }
+
+    async fn try_release(&self) -> kube::Result<()> {
+        let lease = self.api.get(&self.name).await?;
+        let spec = lease.spec.clone().unwrap_or_default();
+        if spec.holder_identity.as_deref() != Some(&self.identity) {
+            return Ok(());
+        }
+        let spec = LeaseSpec { holder_identity: None, ..spec };
+        let lease = Lease { spec: Some(spec), ..lease };
+        self.api
+            .replace(&self.name, &PostParams::default(), &lease)
+            .await
+            .map(drop)
+    }
+
+    async fn try_elect(&self) -> kube::Result<bool> {
+        let now = Utc::now();
+        let params = PostParams::default();
+        let Some(lease) = self.api.get_opt(&self.name).await? else {
+            let lease = Lease {
+                metadata: ObjectMeta {
+                    name: Some(self.name.to_owned()),
+                    ..Default::default()
+                },
+                spec: claim(None, &self.identity, now, self.duration),
+            };
+            return won(self.api.create(&params, &lease).await);
+        };
+        let Some(spec) =
+            claim(lease.spec.as_ref(), &self.identity, now, self.duration)
+        else {
+            return Ok(false);
+        };
+        // the resource version makes the update fail when another replica
+        // updated the Lease in the meantime
+        let lease = Lease { spec: Some(spec), ..lease };
+        won(self.api.replace(&self.name, &params, &lease).await)
+    }
+}
+
+/// Whether this replica leads, as the leader election task last found out.
+pub struct Leadership {
+    /// Until when the Lease this replica holds stays valid
+    valid_until: watch::Receiver<Option<Instant>>,
+    release: Option<oneshot::Sender<()>>,
+    task: JoinHandle<()>,
+}
+
+impl Leadership {
+    /// Whether this replica holds a Lease that is still valid: a replica
+    /// whose renewals keep failing stops reconciling before another one
+    /// may take over.
+    pub fn is_leading(&self) -> bool {
+        self.valid_until
+            .borrow()
+            .is_some_and(|valid_until| Instant::now() < valid_until)
+    }
+
+    /// Waits for the next election, returning whether this replica leads.
+    pub async fn elected(&mut self) -> bool {
+        if self.valid_until.changed().await.is_err() {
+            // the task is gone, and so are the renewals
+            return std::future::pending().await;
+        }
+        self.is_leading()
+    }
+
+    /// Stops renewing the Lease and releases it.
+    pub async fn release(mut self) {
+        if let Some(release) = self.release.take() {
+            let _ = release.send(());
+        }
+        if let Err(e) = self.task.await {
+            warn!("the leader election task failed: {e}");
+        }
+    }
+}
+
+/// Whether writing the Lease went through, rather than losing the race
+/// against another replica.
+fn won(result: kube::Result<Lease>) -> kube::Result<bool> {
+    match result {
+        Ok(_) => Ok(true),
+        Err(kube::Error::Api(response)) if response.code == 409 => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// The Lease as `identity` holds it from `now` on, unless another holder's
+/// Lease is still valid.
+fn claim(
+    spec: Option<&LeaseSpec>,
+    identity: &str,
+    now: DateTime<Utc>,
+    duration: Duration,
+) -> Option<LeaseSpec> {
+    let spec = spec.cloned().unwrap_or_default();
+    let held = spec.holder_identity.as_deref() == Some(identity);
+    let expired = match (&spec.renew_time, spec.lease_duration_seconds) {
+        (Some(MicroTime(renewed)), Some(seconds)) => {
+            *renewed + TimeDelta::seconds(seconds.into()) < now
+        }
+        _ => true,
+    };
+    if !held && !expired && spec.holder_identity.is_some() {
+        return None;
+    }
+
+    let (acquire_time, lease_transitions) = match (held, &spec.holder_identity)
+    {
+        (true, _) => (spec.acquire_time, spec.lease_transitions),
+        (false, None) => (Some(MicroTime(now)), spec.lease_transitions),
+        (false, Some(_)) => (
+            Some(MicroTime(now)),
+            Some(spec.lease_transitions.unwrap_or_default() + 1),
+        ),
+    };
+    Some(LeaseSpec {
+        holder_identity: Some(identity.to_owned()),
+        lease_duration_seconds: Some(
+            duration.as_secs().try_into().unwrap_or(i32::MAX),
+        ),
+        acquire_time,
+        renew_time: Some(MicroTime(now)),
+        lease_transitions,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DURATION: Duration = Duration::from_secs(15);
+
+    fn held_by(holder: &str, renewed: DateTime<Utc>) -> LeaseSpec {
+        LeaseSpec {
+            holder_identity: Some(holder.to_owned()),
+            lease_duration_seconds: Some(15),
+            acquire_time: Some(MicroTime(renewed)),
+            renew_time: Some(MicroTime(renewed)),
+            lease_transitions: Some(2),
+        }
+    }
+
+    #[test]
+    fn it_claims_and_renews_its_lease() {
+        let now = Utc::now();
+        let lease = claim(None, "epok-a", now, DURATION).unwrap();
+        assert_eq!(lease.holder_identity.as_deref(), Some("epok-a"));
+        assert_eq!(lease.lease_duration_seconds, Some(15));
+        assert_eq!(lease.lease_transitions, None);
+
+        let later = now + TimeDelta::seconds(5);
+        let renewed = claim(Some(&lease), "epok-a", later, DURATION).unwrap();
+        assert_eq!(renewed.acquire_time, Some(MicroTime(now)));
+        assert_eq!(renewed.renew_time, Some(MicroTime(later)));
+    }
+
+    #[test]
+    fn it_waits_for_the_lease_of_another_replica_to_expire() {
+        let renewed = Utc::now();
+        let lease = held_by("epok-a", renewed);
+
+        let valid = renewed + TimeDelta::seconds(10);
+        assert!(claim(Some(&lease), "epok-b", valid, DURATION).is_none());
+
+        let expired = renewed + TimeDelta::seconds(20);
+        let taken = claim(Some(&lease), "epok-b", expired, DURATION).unwrap();
+        assert_eq!(taken.holder_identity.as_deref(), Some("epok-b"));
+        assert_eq!(taken.acquire_time, Some(MicroTime(expired)));
+        assert_eq!(taken.lease_transitions, Some(3));
+    }
+
+    #[test]
+    fn it_takes_over_a_released_lease_right_away() {
+        let renewed = Utc::now();
+        let released =
+            LeaseSpec { holder_identity: None, ..held_by("epok-a", renewed) };
+
+        let valid = renewed + TimeDelta::seconds(1);
+        let taken = claim(Some(&released), "epok-b", valid, DURATION).unwrap();
+        assert_eq!(taken.holder_identity.as_deref(), Some("epok-b"));
+    }
+}
@@ -1,17 +1,30 @@
 use cmd_lib::run_fun;
+use itertools::Itertools;
 use lazy_static::lazy_static;
 use tokio::time::Duration;
 use thiserror::Error;
 
+pub mod agent;
+pub mod backend;
 pub mod batch;
 pub mod cli;
 pub mod debounce;
+pub mod doctor;
 pub mod executor;
+pub mod file;
+pub mod installed;
 pub mod iptables;
+pub mod leader;
+pub mod load_balancer;
 pub mod logging;
+pub mod netns;
+pub mod nftables;
 pub mod operator;
+pub mod proxy;
 pub mod res;
+pub mod ssh;
 pub mod state;
+pub mod status;
 pub mod watcher;
 
 lazy_static! {
@@ -23,32 +36,61 @@ lazy_static! {
     };
 }
 
+pub use agent::AgentBackend;
+pub use backend::{AnyBackend, HostConfig, PendingBackend};
 pub use batch::Batch;
-pub use cli::{BatchOpts, Executor, Opts, SshHost};
+pub use cli::{
+    AgentOpts, BackendKind, BatchOpts, CommandOpts, EpokCommand, ExecutorOpts,
+    FileOpts, HostOpts, JumpPosition, LeaderOpts, Netns, Opts, OutputFormat,
+    Privilege, SshHost,
+};
 pub use debounce::Debounce;
+pub use doctor::{Check, ClusterChecks, HostChecks, Permission, Report};
+pub use executor::{get_ip, Cmd, DryRun, Executor, LocalExecutor, Retrying};
+pub use file::FileBackend;
+pub use installed::InstalledRule;
 pub use iptables::IptablesBackend;
+pub use leader::{LeaderElection, Leadership};
+pub use load_balancer::{ingress_ips, IngressPublisher, LoadBalancerClaim};
 pub use k8s_openapi::api::core::v1::{
     Node as CoreNode, Pod as CorePod, Service as CoreService,
 };
 pub use logging::*;
-pub use operator::{Backend, Operator, Rule};
+pub use nftables::NftablesBackend;
+pub use operator::{Backend, Host, LocalIps, Operator, Rule};
+pub use proxy::ProxyBackend;
 pub use res::{
-    ExternalPorts, Interface, Node, Pod, PortSpec, Proto, Resource,
-    ResourceLike, Service,
+    ExternalPortMapping, ExternalPorts, Interface, IpFamilies, IpFamily, Node,
+    Pod, PortMapping, PortSpec, Proto, Resource, ResourceLike, Service,
 };
 pub use state::{apply, Op, Ops, State};
-pub use watcher::watch;
+pub use status::{Rejection, StatusPublisher};
+pub use watcher::{
+    watch, watch_namespaced, watch_pods, watch_port_mappings, watch_services,
+};
 
 pub const ANNOTATION: &str = "epok.getbetter.ro/externalports";
 pub const INTERNAL_ANNOTATION: &str = "epok.getbetter.ro/internal";
 pub const EXTERNAL_ANNOTATION: &str = "epok.getbetter.ro/external";
 pub const ALLOW_RANGE_ANNOTATION: &str = "epok.getbetter.ro/allow-range";
+pub const FAMILIES_ANNOTATION: &str = "epok.getbetter.ro/ip-families";
+pub const STATUS_ANNOTATION: &str = "epok.getbetter.ro/status";
 pub const NODE_EXCLUDE_ANNOTATION: &str = "epok.getbetter.ro/exclude";
 pub const NODE_EXCLUDE_LABEL: &str = "epok_exclude";
 pub const OP_DEBOUNCE_TIMEOUT: Duration = Duration::from_millis(100);
 pub const OP_CHANNEL_SIZE: usize = 64;
 pub const OP_DEBOUNCE_CAPACITY: usize = 128;
 pub const RULE_MARKER: &str = "epok_rule_id";
+pub const PROXY_UDP_TIMEOUT: Duration = Duration::from_secs(60);
+pub const COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// Exit code of ssh when the connection itself failed.
+pub const SSH_CONNECTION_FAILED: i32 = 255;
+/// What iptables says when it gave up waiting for the xtables lock.
+pub const XTABLES_LOCKED: &str = "holding the xtables lock";
+/// How many times a reconcile is retried while the firewall is locked.
+pub const LOCK_RETRIES: usize = 5;
+/// What the machine epok runs on is called in logs and errors.
+pub const LOCALHOST: &str = "localhost";
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
@@ -60,6 +102,123 @@ pub enum Error {
     OperatorError(#[source] Box<Error>),
     #[error("command execution failed: {0}")]
     ExecutorError(#[source] std::io::Error),
-    #[error("could not apply iptables rules: {0}")]
+    #[error("{host}: `{cmd}` {}", failure(*code, stderr))]
+    CommandFailed {
+        host: String,
+        cmd: String,
+        code: Option<i32>,
+        stderr: String,
+    },
+    #[error("command `{0}` timed out after {1:?}")]
+    CommandTimeout(String, Duration),
+    #[error("could not apply firewall rules: {0}")]
     BackendError(#[source] Box<Error>),
+    #[error("proxy listener failed: {0}")]
+    ProxyError(#[source] std::io::Error),
+    #[error("epok-agent request failed: {0}")]
+    AgentError(#[source] Box<ureq::Error>),
+    #[error("could not set up TLS with the epok-agent: {0}")]
+    AgentTlsError(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("another process holds the xtables lock: {0}")]
+    XtablesLocked(#[source] Box<Error>),
+    #[error("iptables chain does not exist: {0}")]
+    IptablesNoChain(#[source] Box<Error>),
+    #[error("iptables rejected the rules: {0}")]
+    IptablesBadRule(#[source] Box<Error>),
+    #[error("iptables lacks root privileges: {0}")]
+    IptablesPermissionDenied(#[source] Box<Error>),
+    #[error("could not get an IP address of interface {0}")]
+    NoInterfaceAddress(String),
+    #[error("host key of {0} does not match {1}")]
+    HostKeyMismatch(String, String),
+    #[error("host {0}: {1}")]
+    HostError(String, #[source] Box<Error>),
+    #[error("{}", .0.iter().join("; "))]
+    HostsError(Vec<Error>),
+}
+
+/// What the firewall tools and the shell say when the host cannot do what
+/// was asked at all, e.g. IPv6 nat with IPv6 turned off.
+const UNSUPPORTED: [&str; 4] = [
+    "Address family not supported by protocol",
+    "Table does not exist",
+    "Protocol not supported",
+    "command not found",
+];
+
+/// Exit code of the shell when the command does not exist.
+const COMMAND_NOT_FOUND: i32 = 127;
+
+/// How a command failed, for `Error::CommandFailed`.
+fn failure(code: Option<i32>, stderr: &str) -> String {
+    let status = match code {
+        Some(code) => format!("exited with {code}"),
+        None => "was killed by a signal".to_owned(),
+    };
+    match stderr.is_empty() {
+        true => status,
+        false => format!("{status}: {stderr}"),
+    }
+}
+
+impl Error {
+    /// The error underneath the context the backends, hosts and the
+    /// operator wrap it in.
+    pub fn root(&self) -> &Error {
+        match self {
+            Error::OperatorError(e)
+            | Error::BackendError(e)
+            | Error::HostError(_, e) => e.root(),
+            e => e,
+        }
+    }
+
+    /// Whether the failure may go away by itself, so that running the same
+    /// command again is worth a try.
+    pub fn is_transient(&self) -> bool {
+        match self.root() {
+            Error::CommandTimeout(..) | Error::XtablesLocked(_) => true,
+            Error::CommandFailed { code, .. } => {
+                *code == Some(SSH_CONNECTION_FAILED)
+            }
+            Error::ExecutorError(e) => matches!(
+                e.kind(),
+                std::io::ErrorKind::TimedOut | std::io::ErrorKind::Interrupted
+            ),
+            _ => false,
+        }
+    }
+
+    /// Whether the command is known to have failed before it changed
+    /// anything, so that running it again cannot apply its changes twice.
+    pub fn never_applied(&self) -> bool {
+        match self.root() {
+            Error::XtablesLocked(_) => true,
+            Error::CommandFailed {
+                code: Some(SSH_CONNECTION_FAILED), ..
+            } => false,
+            Error::CommandFailed { stderr, .. } => {
+                stderr.contains(XTABLES_LOCKED)
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the host lacks the tool or the kernel support the command
+    /// needed, rather than the command failing on the way.
+    pub fn is_unsupported(&self) -> bool {
+        match self.root() {
+            Error::CommandFailed {
+                code: Some(SSH_CONNECTION_FAILED), ..
+            } => false,
+            Error::CommandFailed { code, stderr, .. } => {
+                *code == Some(COMMAND_NOT_FOUND)
+                    || UNSUPPORTED.iter().any(|text| stderr.contains(text))
+            }
+            Error::ExecutorError(e) => {
+                e.kind() == std::io::ErrorKind::NotFound
+            }
+            _ => false,
+        }
+    }
 }
@@ -0,0 +1,209 @@
+use std::collections::BTreeSet;
+
+use k8s_openapi::serde_json::{json, Value};
+use kube::{
+    api::{Patch, PatchParams},
+    Api, Client,
+};
+
+use crate::{
+    logging::*, status::is_not_found, CoreService, LocalIps, Op, Ops,
+    Resource, Service, State,
+};
+
+/// Which `LoadBalancer` services epok claims: those of its class, or those
+/// without a class when it has none.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LoadBalancerClaim {
+    pub class: Option<String>,
+}
+
+impl LoadBalancerClaim {
+    /// The service as epok forwards it: a claimed load balancer forwards
+    /// its ports unless annotated with others, the other services only
+    /// their annotated ports.
+    pub fn apply(claim: Option<&Self>, service: Service) -> Option<Service> {
+        let claimed = service.load_balancer.as_ref().is_some_and(|lb| {
+            claim.is_some_and(|claim| claim.class == lb.class)
+        });
+        let service = match (claimed, service.has_external_ports()) {
+            (true, true) => service,
+            (true, false) => Service {
+                external_ports: service
+                    .load_balancer
+                    .as_ref()
+                    .map(|lb| lb.ports.clone())
+                    .unwrap_or_default(),
+                ..service
+            },
+            (false, _) => Service { load_balancer: None, ..service },
+        };
+        service.has_external_ports().then_some(service)
+    }
+
+    /// Applies the claim to the services the ops add.
+    pub fn ops(claim: Option<&Self>, ops: Ops) -> Ops {
+        Ops(ops
+            .into_iter()
+            .filter_map(|op| match op {
+                Op::ResourceAdd(Resource::Service(service)) => {
+                    Self::apply(claim, service)
+                        .map(|service| Op::ResourceAdd(service.into()))
+                }
+                op => Some(op),
+            })
+            .collect())
+    }
+}
+
+/// The addresses reported as the ingress of the claimed load balancers:
+/// those of the external interfaces, without the extra internal IPs.
+pub fn ingress_ips<'a>(
+    local_ips: impl IntoIterator<Item = &'a LocalIps>,
+) -> Vec<String> {
+    local_ips
+        .into_iter()
+        .flat_map(|ips| [&ips.v4, &ips.v6])
+        .flatten()
+        .filter_map(|ips| ips.split(',').next())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Writes the ingress of the claimed load balancers into their status.
+pub struct IngressPublisher {
+    client: Client,
+    ips: Vec<String>,
+    /// The services whose ingress got written, by namespace and name
+    published: BTreeSet<(String, String)>,
+}
+
+impl IngressPublisher {
+    pub fn new(client: Client, ips: Vec<String>) -> Self {
+        if ips.is_empty() {
+            warn!(
+                "no external interface address to report as the ingress of \
+                 load balancers"
+            );
+        }
+        Self { client, ips, published: BTreeSet::new() }
+    }
+
+    /// Sets the ingress of the load balancers claimed since the last call
+    /// and clears it on the ones no longer claimed.
+    pub async fn publish(&mut self, state: &State) {
+        let claimed = state
+            .get::<Service>()
+            .into_iter()
+            .filter(|service| service.load_balancer.is_some())
+            .map(|service| (service.namespace, service.name))
+            .collect::<BTreeSet<_>>();
+        let ingress =
+            self.ips.iter().map(|ip| json!({ "ip": ip })).collect::<Vec<_>>();
+
+        let added = &claimed - &self.published;
+        for key in added {
+            if let Err(e) = self.write_ingress(&key, &ingress).await {
+                warn!("could not set the ingress of {}/{}: {e}", key.0, key.1);
+                continue;
+            }
+            self.published.insert(key);
+        }
+        let removed = &self.published - &claimed;
+        for key in removed {
+            match self.write_ingress(&key, &[]).await {
+                Err(e) if !is_not_found(&e) => {
+                    warn!(
+                        "could not clear the ingress of {}/{}: {e}",
+                        key.0, key.1
+                    );
+                    continue;
+                }
+                _ => {}
+            }
+            self.published.remove(&key);
+        }
+    }
+
+    async fn write_ingress(
+        &self,
+        (namespace, name): &(String, String),
+        ingress: &[Value],
+    ) -> kube::Result<CoreService> {
+        let patch = Patch::Merge(json!({
+            "status": { "loadBalancer": { "ingress": ingress } }
+        }));
+        Api::<CoreService>::namespaced(self.client.clone(), namespace)
+            .patch_status(name, &PatchParams::default(), &patch)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{res::LoadBalancer, ExternalPorts, PortSpec, Proto};
+
+    fn port(host_port: u16, dest_port: u16) -> ExternalPorts {
+        ExternalPorts {
+            specs: vec![PortSpec { host_port, dest_port, proto: Proto::Tcp }],
+        }
+    }
+
+    fn service(annotated: Option<u16>, class: Option<&str>) -> Service {
+        Service {
+            name: "web".to_owned(),
+            namespace: "default".to_owned(),
+            external_ports: annotated
+                .map(|host_port| port(host_port, 8080))
+                .unwrap_or_default(),
+            is_internal: false,
+            allow_range: None,
+            families: Default::default(),
+            interfaces: None,
+            load_balancer: Some(LoadBalancer {
+                class: class.map(str::to_owned),
+                ports: port(80, 30080),
+            }),
+        }
+    }
+
+    #[test]
+    fn it_forwards_the_ports_of_claimed_load_balancers() {
+        let claim = LoadBalancerClaim::default();
+        let claimed =
+            LoadBalancerClaim::apply(Some(&claim), service(None, None))
+                .unwrap();
+        assert_eq!(claimed.external_ports, port(80, 30080));
+        assert!(claimed.load_balancer.is_some());
+
+        // the annotation wins over the ports of the service
+        let annotated =
+            LoadBalancerClaim::apply(Some(&claim), service(Some(25), None))
+                .unwrap();
+        assert_eq!(annotated.external_ports, port(25, 8080));
+    }
+
+    #[test]
+    fn it_leaves_other_load_balancers_alone() {
+        let claim = LoadBalancerClaim { class: Some("epok".to_owned()) };
+        let other = service(None, Some("metallb"));
+        assert_eq!(LoadBalancerClaim::apply(Some(&claim), other), None);
+        assert_eq!(LoadBalancerClaim::apply(None, service(None, None)), None);
+
+        // annotated ones still get forwarded, without an ingress
+        let annotated =
+            LoadBalancerClaim::apply(None, service(Some(25), None)).unwrap();
+        assert_eq!(annotated.external_ports, port(25, 8080));
+        assert_eq!(annotated.load_balancer, None);
+    }
+
+    #[test]
+    fn it_reports_the_first_external_address_per_family() {
+        let ips = [
+            LocalIps { v4: Some("10.0.0.1,10.0.0.2".to_owned()), v6: None },
+            LocalIps { v4: None, v6: Some("fd00::1".to_owned()) },
+        ];
+        assert_eq!(ingress_ips(&ips), ["10.0.0.1", "fd00::1"]);
+    }
+}
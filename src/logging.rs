@@ -1,4 +1,4 @@
-pub use tracing::{debug, info, warn};
+pub use tracing::{debug, info, info_span, warn};
 use tracing_subscriber::{
     layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Registry,
 };
@@ -1,5 +1,15 @@
-use clap::Parser;
+use std::fs::File;
+
+use clap::{error::ErrorKind, CommandFactory, Parser};
 use kube::Client;
+use tokio::{
+    signal::{
+        ctrl_c,
+        unix::{signal, SignalKind},
+    },
+    task::block_in_place,
+    time::{interval_at, Duration, Instant, Interval, MissedTickBehavior},
+};
 use tokio_stream::StreamExt;
 use epok::*;
 
@@ -7,85 +17,527 @@ use epok::*;
 async fn main() -> anyhow::Result<()> {
     initialize_logging("EPOK_LOG_LEVEL");
 
-    let opts = Opts::parse();
+    let mut opts = Opts::parse();
     debug!("parsed options: {opts:?}");
 
-    let mut local_ip = opts
-        .external_interface
-        .as_ref()
-        .map(|iface| get_ip(iface, &opts.executor));
+    let (doctor, executor) = match opts.command.take() {
+        Some(EpokCommand::Doctor { executor }) => (true, executor),
+        Some(EpokCommand::Run(executor)) => (false, Some(executor)),
+        None => (false, None),
+    };
+    let hosts = match (&opts.hosts, executor) {
+        (Some(_), Some(_)) => usage_error(
+            ErrorKind::ArgumentConflict,
+            "--hosts already sets the executor of every host",
+        ),
+        (Some(_), None)
+            if !matches!(
+                opts.backend,
+                BackendKind::Iptables | BackendKind::Nftables
+            ) =>
+        {
+            usage_error(
+                ErrorKind::ArgumentConflict,
+                "--hosts requires the iptables or nftables backend",
+            )
+        }
+        (Some(path), None) => {
+            serde_yaml::from_reader::<_, Vec<HostOpts>>(File::open(path)?)?
+        }
+        (None, Some(executor)) => vec![HostOpts {
+            name: executor.host_name().to_owned(),
+            interfaces: opts
+                .interfaces
+                .iter()
+                .flat_map(|i| i.split(','))
+                .map(str::to_owned)
+                .collect(),
+            external_interface: opts.external_interface.clone(),
+            extra_internal_ips: opts.extra_internal_ips.clone(),
+            executor,
+        }],
+        (None, None) => usage_error(
+            ErrorKind::MissingSubcommand,
+            "an executor is required unless --hosts is given",
+        ),
+    };
+
+    if opts.backend == BackendKind::Proxy
+        && hosts
+            .iter()
+            .any(|host| !matches!(host.executor, ExecutorOpts::Local))
+    {
+        usage_error(
+            ErrorKind::ArgumentConflict,
+            "--backend proxy listens in the epok process, so it requires the \
+             local executor",
+        )
+    }
+
+    let leader_opts = &opts.leader_opts;
+    if leader_opts.leader_election
+        && leader_opts.lease_renew_interval >= leader_opts.lease_duration
+    {
+        usage_error(
+            ErrorKind::ValueValidation,
+            "--lease-renew-interval must be below --lease-duration",
+        )
+    }
+
+    if doctor {
+        let report = Report(
+            hosts
+                .into_iter()
+                .flat_map(|host_opts| {
+                    let command_opts =
+                        host_opts.executor.command_opts(&opts.command_opts);
+                    let executor =
+                        host_opts.executor.build(opts.dry_run, &command_opts);
+                    checks(
+                        &opts,
+                        &command_opts,
+                        &host_opts.name,
+                        &host_opts.interfaces,
+                        host_opts.external_interface.as_deref(),
+                        &executor,
+                    )
+                })
+                .chain(match Client::try_default().await {
+                    Ok(kube_client) => {
+                        cluster_checks(&opts, kube_client).await
+                    }
+                    Err(e) => vec![Check {
+                        target: "cluster".to_owned(),
+                        name: "kubernetes client".to_owned(),
+                        outcome: Err(e.to_string()),
+                    }],
+                })
+                .collect(),
+        );
+        print!("{report}");
+        std::process::exit(if report.passed() { 0 } else { 1 });
+    }
+
+    let mut external_ips = Vec::new();
+    let operator = Operator::with_hosts(hosts.into_iter().map(|host_opts| {
+        let (host, local_ips) = host(&opts, host_opts);
+        external_ips.push(local_ips);
+        host
+    }));
+    let mut state = State::default();
+
+    let kube_client = Client::try_default().await?;
+    preflight(&Report(cluster_checks(&opts, kube_client.clone()).await));
+    // a dry run leaves the rules, and thus the status, untouched
+    let mut publishers = Publishers {
+        status: (opts.publish_status && !opts.dry_run)
+            .then(|| StatusPublisher::new(kube_client.clone())),
+        ingress: (opts.load_balancers && !opts.dry_run).then(|| {
+            IngressPublisher::new(
+                kube_client.clone(),
+                ingress_ips(&external_ips),
+            )
+        }),
+    };
+    // without leader election, this replica always leads
+    let mut leadership = opts.leader_opts.leader_election.then(|| {
+        let leader =
+            LeaderElection::new(kube_client.clone(), &opts.leader_opts);
+        info!("electing the leader as {}", leader.identity());
+        leader.spawn(Duration::from_secs(
+            opts.leader_opts.lease_renew_interval.max(1),
+        ))
+    });
+    let mut leading = leadership.is_none();
+    let (services, nodes, pods, mappings) = (
+        watch_services(
+            kube_client.clone(),
+            &opts.namespaces,
+            opts.label_selector.as_deref(),
+            opts.load_balancers.then(|| LoadBalancerClaim {
+                class: opts.load_balancer_class.clone(),
+            }),
+        ),
+        watch::<CoreNode>(kube_client.clone()),
+        watch_pods(
+            kube_client.clone(),
+            &opts.namespaces,
+            opts.label_selector.as_deref(),
+            opts.port_mappings,
+        ),
+        watch_port_mappings(kube_client, opts.port_mappings, &opts.namespaces),
+    );
+
+    let mut debounced =
+        Debounce::boxed(services.merge(nodes).merge(pods).merge(mappings));
+
+    // the first tick fires right away, before we have seen any resources
+    let mut resync = (opts.resync_interval > 0).then(|| {
+        let period = Duration::from_secs(opts.resync_interval);
+        let mut resync = interval_at(Instant::now() + period, period);
+        resync.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        resync
+    });
+    let retry_period = Duration::from_secs(opts.retry_interval.max(1));
+    let mut retry = interval_at(Instant::now() + retry_period, retry_period);
+    retry.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // don't resync against a state the watchers have not filled in yet
+    let mut synced = false;
+    let shutdown = shutdown();
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            op_batch = debounced.next() => {
+                let Some(op_batch) = op_batch else { break };
+                let prev_state = state.clone();
+                let ops = op_batch
+                    .into_iter()
+                    .flat_map(|ops| {
+                        ops.unwrap_or_else(|e| {
+                            warn!("{e}");
+                            Ops(Vec::new())
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                let status =
+                    publishers.status.as_ref().filter(|_| leads(&leadership));
+                if let Some(status) = status {
+                    for op in &ops {
+                        if let Op::Reject(rejection) = op {
+                            status.reject(rejection).await;
+                        }
+                    }
+                }
+                apply(ops, &mut state);
+                synced = true;
+
+                // followers only keep their state warm
+                if !leads(&leadership) {
+                    continue;
+                }
+                let reconciled =
+                    block_in_place(|| operator.reconcile(&state, &prev_state));
+                match reconciled {
+                    Ok(()) => publishers.publish(&state).await,
+                    Err(e) => warn!("{e}"),
+                }
+            }
+            Some(elected) = elected(&mut leadership) => {
+                match (leading, elected) {
+                    (false, true) => {
+                        info!("took over as the leader");
+                        leading = true;
+                        // catch up on what the previous leader did, or left
+                        // undone, without waiting for a change
+                        if synced {
+                            match block_in_place(|| operator.resync(&state)) {
+                                Ok(n) => {
+                                    info!("takeover: repaired {n} rule(s)");
+                                    publishers.publish(&state).await;
+                                }
+                                Err(e) => warn!("takeover resync failed: {e}"),
+                            }
+                        }
+                    }
+                    (true, false) => {
+                        warn!("lost the lease, no longer reconciling");
+                        leading = false;
+                    }
+                    _ => {}
+                }
+            }
+            _ = retry.tick(), if operator.is_failing() => {
+                if !leads(&leadership) {
+                    continue;
+                }
+                info!("retrying the failed reconcile");
+                match block_in_place(|| operator.reconcile(&state, &state)) {
+                    Ok(()) => publishers.publish(&state).await,
+                    Err(e) => warn!("{e}"),
+                }
+            }
+            Some(_) = tick(&mut resync), if synced => {
+                if !leads(&leadership) {
+                    continue;
+                }
+                match block_in_place(|| operator.resync(&state)) {
+                    Ok(0) => debug!("resync: no drift"),
+                    Ok(n) => info!("resync: repaired {n} rule(s)"),
+                    Err(e) => {
+                        warn!("resync failed: {e}");
+                        continue;
+                    }
+                }
+                // catches up on a status a failed takeover left unpublished
+                publishers.publish(&state).await;
+            }
+            _ = &mut shutdown => {
+                info!("shutting down");
+                break;
+            }
+        }
+    }
+    // the other replicas can take over right away
+    if let Some(leadership) = leadership {
+        leadership.release().await;
+    }
+    Ok(())
+}
 
-    let mut interfaces = opts
+fn usage_error(kind: ErrorKind, message: &str) -> ! {
+    Opts::command().error(kind, message).exit()
+}
+
+/// Logs the failed preflight checks, which are likely to break forwarding
+/// later on.
+fn preflight(report: &Report) {
+    for check in report.failed() {
+        warn!("preflight: {check}");
+    }
+}
+
+/// The preflight checks of a host, run through its executor.
+fn checks(
+    opts: &Opts,
+    command_opts: &CommandOpts,
+    name: &str,
+    interfaces: &[String],
+    external_interface: Option<&str>,
+    executor: &impl Executor,
+) -> Vec<Check> {
+    HostChecks {
+        name,
+        executor,
+        interfaces,
+        external_interface,
+        backend: opts.backend,
+        command_opts,
+        batch_opts: &opts.batch_opts,
+    }
+    .run()
+}
+
+/// The checks of the cluster permissions the options call for.
+async fn cluster_checks(opts: &Opts, client: Client) -> Vec<Check> {
+    let leader_opts = &opts.leader_opts;
+    let lease_namespace = leader_opts.leader_election.then(|| {
+        leader_opts
+            .lease_namespace
+            .as_deref()
+            .unwrap_or(client.default_namespace())
+    });
+    ClusterChecks {
+        namespaces: &opts.namespaces,
+        port_mappings: opts.port_mappings,
+        // a dry run leaves the rules, and thus the status, untouched
+        publish_status: opts.publish_status && !opts.dry_run,
+        load_balancers: opts.load_balancers && !opts.dry_run,
+        lease_namespace,
+    }
+    .run(client.clone())
+    .await
+}
+
+/// Sets up a host, and its backend through its executor. Also returns the
+/// addresses of its external interface. A host whose addresses can't be
+/// looked up starts out failing, and gets set up again on the next retry.
+fn host(opts: &Opts, host_opts: HostOpts) -> (Host<AnyBackend>, LocalIps) {
+    let command_opts = host_opts.executor.command_opts(&opts.command_opts);
+    let executor = host_opts.executor.build(opts.dry_run, &command_opts);
+    preflight(&Report(checks(
+        opts,
+        &command_opts,
+        &host_opts.name,
+        &host_opts.interfaces,
+        host_opts.external_interface.as_deref(),
+        &executor,
+    )));
+
+    let mut interfaces = host_opts
         .interfaces
-        .split(',')
+        .iter()
         .map(|i_name| {
             let mut iface = Interface::new(i_name);
-            if let Some(ext_if) = &opts.external_interface {
-                if ext_if.as_str() == i_name {
-                    iface = iface.external();
-                }
+            if host_opts.external_interface.as_ref() == Some(i_name) {
+                iface = iface.external();
             }
             iface
         })
         .collect::<Vec<_>>();
 
-    info!("{interfaces:?}");
+    info!("{}: {interfaces:?}", host_opts.name);
 
-    if local_ip.is_some() {
+    // the local addresses are those of the external interface, and the
+    // agent knows them and skips the local rules it can't install
+    if host_opts.external_interface.is_some()
+        || opts.backend == BackendKind::Agent
+    {
         interfaces.push(Interface::new("lo"));
     }
 
-    if let Some(extra_ips) = opts.extra_internal_ips.to_owned() {
-        local_ip = local_ip.map(|ip| format!("{ip},{extra_ips}"))
-    }
+    let setup = BackendSetup {
+        kind: opts.backend,
+        batch_opts: opts.batch_opts.clone(),
+        file_opts: opts.file_opts.clone(),
+        agent_opts: opts.agent_opts.clone(),
+        external_interface: host_opts.external_interface,
+        extra_ips: host_opts.extra_internal_ips,
+        interfaces: interfaces.iter().map(|i| i.name.to_owned()).collect(),
+        jump_position: opts.jump_position,
+        command_opts,
+    };
+    let mut executor = Some(executor);
+    let (host, local_ips) = match setup.run(&mut executor) {
+        Ok((backend, local_ips)) => {
+            (Host::new(host_opts.name, backend), local_ips)
+        }
+        Err(e) => {
+            warn!("{}: {e}, setting it up on the next retry", host_opts.name);
+            let pending = PendingBackend::new(move || {
+                setup.run(&mut executor).map(|(backend, _)| backend)
+            });
+            let host = Host::new(host_opts.name, AnyBackend::Pending(pending));
+            (host.failing(&e), LocalIps::default())
+        }
+    };
+    (host.with_interfaces(interfaces), local_ips)
+}
 
-    let mut state = State::default().with(interfaces);
+/// What setting up the backend of a host takes, besides its executor.
+struct BackendSetup {
+    kind: BackendKind,
+    batch_opts: BatchOpts,
+    file_opts: FileOpts,
+    agent_opts: AgentOpts,
+    external_interface: Option<String>,
+    extra_ips: Option<String>,
+    interfaces: Vec<String>,
+    jump_position: JumpPosition,
+    command_opts: CommandOpts,
+}
 
-    let operator = Operator::new(IptablesBackend::new(
-        opts.executor,
-        opts.batch_opts,
-        local_ip,
-        opts.extra_internal_ips,
-    ));
+impl BackendSetup {
+    /// Looks up the addresses of the host and builds its backend, which
+    /// takes the executor once the lookup went through. The agent backend
+    /// gets the addresses from the agent instead.
+    fn run(
+        &self,
+        executor: &mut Option<Box<dyn Executor>>,
+    ) -> Result<(AnyBackend, LocalIps)> {
+        // the executor would look the addresses up where epok runs, while
+        // the agent reports those of its host
+        if self.kind == BackendKind::Agent {
+            let mut agent = AgentBackend::new(self.agent_opts.clone())?;
+            agent.read_state()?;
+            let local_ips = agent.local_ips().clone();
+            return Ok((AnyBackend::Agent(agent), local_ips));
+        }
 
-    let kube_client = Client::try_default().await?;
-    let (services, nodes, pods) = (
-        watch::<CoreService>(kube_client.clone()),
-        watch::<CoreNode>(kube_client.clone()),
-        watch::<CorePod>(kube_client),
-    );
+        let lookup = executor.as_ref().expect("the backend is set up once");
+        let local_ips = LocalIps::detect(
+            self.external_interface.as_deref(),
+            self.extra_ips.as_deref(),
+            lookup,
+            &self.command_opts,
+        )?;
 
-    let mut debounced = Debounce::boxed(services.merge(nodes).merge(pods));
+        // the proxy backend listens on the interface addresses themselves
+        let interface_ips = match self.kind {
+            BackendKind::Proxy => self
+                .interfaces
+                .iter()
+                .map(|iface| {
+                    let ips = LocalIps {
+                        v4: get_ip(
+                            iface,
+                            IpFamily::V4,
+                            lookup,
+                            &self.command_opts,
+                        ),
+                        v6: get_ip(
+                            iface,
+                            IpFamily::V6,
+                            lookup,
+                            &self.command_opts,
+                        ),
+                    };
+                    (iface.to_owned(), ips)
+                })
+                .collect(),
+            _ => Default::default(),
+        };
 
-    while let Some(op_batch) = debounced.next().await {
-        let prev_state = state.clone();
-        let ops = op_batch.into_iter().flat_map(|ops| {
-            ops.unwrap_or_else(|e| {
-                warn!("{e}");
-                Ops(Vec::new())
-            })
-        });
-        apply(ops, &mut state);
+        let backend = AnyBackend::new(
+            self.kind,
+            executor.take().expect("the backend is set up once"),
+            self.batch_opts.clone(),
+            self.file_opts.clone(),
+            self.agent_opts.clone(),
+            HostConfig {
+                local_ips: local_ips.clone(),
+                extra_ips: self.extra_ips.clone(),
+                jump_position: self.jump_position,
+                interface_ips,
+                command_opts: self.command_opts.clone(),
+            },
+        )?;
+        Ok((backend, local_ips))
+    }
+}
+
+/// What gets written back to the cluster, unless disabled.
+struct Publishers {
+    status: Option<StatusPublisher>,
+    ingress: Option<IngressPublisher>,
+}
 
-        if let Err(e) = operator.reconcile(&state, &prev_state) {
-            warn!("{e}");
+impl Publishers {
+    /// Publishes the status of the services and pods, and the ingress of
+    /// the load balancers, once `state` got reconciled.
+    async fn publish(&mut self, state: &State) {
+        if let Some(status) = &mut self.status {
+            status.publish(state).await;
+        }
+        if let Some(ingress) = &mut self.ingress {
+            ingress.publish(state).await;
         }
     }
-    Ok(())
 }
 
-fn get_ip<I: AsRef<str>>(interface: I, executor: &Executor) -> String {
-    fn inner(iface: &str, inner_executor: &Executor) -> String {
-        let local_ip = inner_executor
-            .run_fun(format!(
-                "ip -f inet addr show {iface} | sed -En -e 's/.*inet ([0-9.]+).*/\\1/p'"
-            ))
-            .unwrap_or_else(|_| panic!("could not get IPv4 address of interface {iface}"));
-        if local_ip == *"" {
-            panic!("could not get IPv4 address of interface {iface}");
+/// Whether this replica leads with a Lease that is still valid, which is
+/// checked right before changing the rules: reconciles and resyncs run
+/// here while the Lease gets renewed on another task.
+fn leads(leadership: &Option<Leadership>) -> bool {
+    leadership.as_ref().is_none_or(Leadership::is_leading)
+}
+
+/// Whether this replica leads, after each election.
+async fn elected(leadership: &mut Option<Leadership>) -> Option<bool> {
+    match leadership {
+        Some(leadership) => Some(leadership.elected().await),
+        None => std::future::pending().await,
+    }
+}
+
+/// Resolves on SIGINT or SIGTERM.
+async fn shutdown() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            warn!("could not listen for SIGTERM: {e}");
+            return std::future::pending().await;
         }
-        local_ip
+    };
+    tokio::select! {
+        _ = ctrl_c() => {}
+        _ = terminate.recv() => {}
     }
+}
 
-    inner(interface.as_ref(), executor)
+async fn tick(interval: &mut Option<Interval>) -> Option<Instant> {
+    match interval {
+        Some(interval) => Some(interval.tick().await),
+        None => std::future::pending().await,
+    }
 }
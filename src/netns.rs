@@ -0,0 +1,118 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use crate::{
+    executor::{output, stdout},
+    logging::*,
+    BatchOpts, Cmd, Executor, Netns, Result,
+};
+
+/// Where `ip netns` keeps its named namespaces.
+const NAMED_NETNS_DIR: &str = "/run/netns";
+
+impl Executor for Netns {
+    fn run(&self, cmd: &Cmd) -> Result<String> {
+        debug!("running command in netns {}: {cmd}", self.netns);
+        let output = output(self.command(cmd), None, cmd.timeout())?;
+        stdout(&self.netns, cmd, output)
+    }
+
+    fn run_with_stdin(&self, cmd: &Cmd, stdin: &str) -> Result<String> {
+        debug!(
+            "running command in netns {}: {cmd} ({} bytes of stdin)",
+            self.netns,
+            stdin.len()
+        );
+        let output = output(self.command(cmd), Some(stdin), cmd.timeout())?;
+        stdout(&self.netns, cmd, output)
+    }
+
+    /// Runs the commands one by one: batches would need a shell, and
+    /// there are no round trips to save.
+    fn run_commands(
+        &self,
+        commands: &mut dyn Iterator<Item = Cmd>,
+        _batch_opts: &BatchOpts,
+    ) -> Result<()> {
+        for command in commands {
+            self.run(&command)?;
+        }
+        Ok(())
+    }
+}
+
+impl Netns {
+    /// The namespace file to enter.
+    fn path(&self) -> PathBuf {
+        if self.netns.contains('/') {
+            PathBuf::from(&self.netns)
+        } else if self.netns.parse::<u32>().is_ok() {
+            Path::new("/proc").join(&self.netns).join("ns/net")
+        } else {
+            Path::new(NAMED_NETNS_DIR).join(&self.netns)
+        }
+    }
+
+    /// Builds an `nsenter` command that runs `cmd` inside the namespace,
+    /// as is: see `ExecutorOpts::command_opts` for the privilege.
+    fn command(&self, cmd: &Cmd) -> Command {
+        let mut command = Command::new(&self.nsenter_binary);
+        command
+            .arg(format!("--net={}", self.path().display()))
+            .args(cmd.argv());
+        command
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use super::*;
+
+    /// Stands in for `nsenter`: logs the namespace it was asked to enter
+    /// and runs the command right here.
+    const NSENTER_STAND_IN: &str = r#"#!/bin/sh
+echo "$1" >> "$(dirname "$0")/entered"
+shift
+exec "$@"
+"#;
+
+    fn netns(dir: &Path, netns: &str) -> Netns {
+        let nsenter = dir.join("nsenter");
+        fs::write(&nsenter, NSENTER_STAND_IN).unwrap();
+        fs::set_permissions(&nsenter, fs::Permissions::from_mode(0o755))
+            .unwrap();
+        Netns {
+            netns: netns.to_owned(),
+            nsenter_binary: nsenter.display().to_string(),
+        }
+    }
+
+    #[test]
+    fn it_resolves_the_namespace_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |ns| netns(dir.path(), ns).path();
+        assert_eq!(path("1"), Path::new("/proc/1/ns/net"));
+        assert_eq!(path("epok-test"), Path::new("/run/netns/epok-test"));
+        assert_eq!(path("/var/run/ns/x"), Path::new("/var/run/ns/x"));
+    }
+
+    #[test]
+    fn it_runs_commands_in_the_namespace() {
+        let dir = tempfile::tempdir().unwrap();
+        let executor = netns(dir.path(), "epok-test");
+
+        let echo = Cmd::new("echo").arg("hi; there");
+        assert_eq!(executor.run(&echo).unwrap(), "hi; there");
+        let cat = Cmd::new("cat");
+        assert_eq!(executor.run_with_stdin(&cat, "hello").unwrap(), "hello");
+        assert!(executor.run(&Cmd::new("false")).is_err());
+        assert_eq!(
+            fs::read_to_string(dir.path().join("entered")).unwrap(),
+            "--net=/run/netns/epok-test\n".repeat(3)
+        );
+    }
+}
@@ -0,0 +1,477 @@
+use std::{cmp::Reverse, collections::HashSet};
+
+use itertools::Itertools;
+use sha256::digest;
+
+use crate::{
+    installed::{rule_comment, split_comment, split_quoted},
+    logging::*,
+    res::Proto,
+    Backend, BatchOpts, Cmd, CommandOpts, Error, Executor, InstalledRule,
+    IpFamilies, IpFamily, LocalIps, Result, Rule, RULE_MARKER,
+    SSH_CONNECTION_FAILED,
+};
+
+pub const NFT_TABLE: &str = "epok";
+
+pub struct NftablesBackend<E = Box<dyn Executor>> {
+    executor: E,
+    batch_opts: BatchOpts,
+    /// Installed epok rules along with the command that deletes them
+    rules: Vec<(InstalledRule, Cmd)>,
+    tables: Vec<IpFamily>,
+    local_ips: LocalIps,
+    extra_ips: Option<String>,
+    command_opts: CommandOpts,
+}
+
+impl<E: Executor> Backend for NftablesBackend<E> {
+    fn read_state(&mut self) -> Result<Vec<InstalledRule>> {
+        self.tables.clear();
+        self.rules.clear();
+        for family in IpFamilies::Both.iter() {
+            // an error taken for a missing table would have every rule
+            // added again, and `nft add rule` does not check for duplicates
+            if let Some(listing) = self.list_table(family)? {
+                self.tables.push(family);
+                let rules = parse_listing(family, &listing)
+                    .into_iter()
+                    .map(|(rule, delete)| {
+                        (rule, self.nft(delete.split_whitespace()))
+                    })
+                    .collect::<Vec<_>>();
+                self.rules.extend(rules);
+            }
+        }
+        Ok(self.rules.iter().map(|(rule, _)| rule.clone()).collect())
+    }
+
+    fn apply_rules(
+        &mut self,
+        rules: impl IntoIterator<Item = Rule>,
+    ) -> Result<()> {
+        let rules = rules
+            .into_iter()
+            .filter(|rule| {
+                let rule_id = rule.rule_id(&self.config_hash());
+                !self.rules.iter().any(|(r, _)| r.rule_id == rule_id)
+            })
+            .filter(|rule| self.can_install(rule))
+            .sorted_unstable_by_key(|r| Reverse(r.nth))
+            .collect::<Vec<_>>();
+
+        if rules.is_empty() {
+            return Ok(());
+        }
+
+        let config_hash = self.config_hash();
+        let families = rules.iter().map(|r| r.family).unique();
+        let statements =
+            families.flat_map(setup_statements).chain(rules.iter().map(
+                |rule| nft_statement(rule, &self.local_ips, &config_hash),
+            ));
+        self.executor
+            .run_commands(
+                &mut statements.map(|stmt| self.nft([stmt])),
+                &self.batch_opts,
+            )
+            .map_err(|e| Error::BackendError(Box::new(e)))
+    }
+
+    fn delete_rules(
+        &mut self,
+        rule_ids: impl IntoIterator<Item = String>,
+    ) -> Result<()> {
+        let rule_ids = rule_ids.into_iter().collect::<HashSet<_>>();
+        self.executor
+            .run_commands(
+                &mut self
+                    .rules
+                    .iter()
+                    .filter(|(rule, _)| rule_ids.contains(&rule.rule_id))
+                    .map(|(_, delete)| delete.to_owned()),
+                &self.batch_opts,
+            )
+            .map_err(|e| Error::BackendError(Box::new(e)))
+    }
+
+    fn teardown(&mut self) -> Result<()> {
+        self.executor
+            .run_commands(
+                &mut self.tables.iter().map(|family| {
+                    let family = nft_family(*family);
+                    self.nft(["delete", "table", family, NFT_TABLE])
+                }),
+                &self.batch_opts,
+            )
+            .map_err(|e| Error::BackendError(Box::new(e)))
+    }
+
+    fn can_install(&self, rule: &Rule) -> bool {
+        rule.interface.name != "lo"
+            || self.local_ips.get(rule.family).is_some()
+    }
+
+    fn config_hash(&self) -> String {
+        let mut config_hash =
+            digest(format!("{:?}::{:?}", self.local_ips, self.extra_ips));
+        config_hash.truncate(16);
+        config_hash
+    }
+}
+
+impl<E: Executor> NftablesBackend<E> {
+    pub fn new(
+        executor: E,
+        batch_opts: BatchOpts,
+        local_ips: LocalIps,
+        extra_ips: Option<String>,
+        command_opts: CommandOpts,
+    ) -> Self {
+        Self {
+            executor,
+            batch_opts,
+            rules: Vec::new(),
+            tables: Vec::new(),
+            local_ips,
+            extra_ips,
+            command_opts,
+        }
+    }
+
+    /// Lists the epok table of `family`, or `None` when there is none.
+    fn list_table(&self, family: IpFamily) -> Result<Option<String>> {
+        let list =
+            self.nft(["-a", "list", "table", nft_family(family), NFT_TABLE]);
+        match self.executor.run(&list) {
+            Ok(listing) => Ok(Some(listing)),
+            Err(e) if is_missing_table(&e) => Ok(None),
+            Err(e)
+                if self.local_ips.is_optional(family)
+                    && e.is_unsupported() =>
+            {
+                debug!("no {family:?} nat table to read: {e}");
+                Ok(None)
+            }
+            Err(e) => Err(Error::BackendError(Box::new(e))),
+        }
+    }
+
+    /// A privileged `nft` command.
+    fn nft<S: Into<String>>(&self, args: impl IntoIterator<Item = S>) -> Cmd {
+        let nft = Cmd::new(&self.command_opts.nft_binary).args(args);
+        self.command_opts.privileged(nft)
+    }
+}
+
+/// Renders `rule` as an `nft` statement, usable both on the command line
+/// and in an `nft -f` script.
+pub(crate) fn nft_statement(
+    rule: &Rule,
+    local_ips: &LocalIps,
+    config_hash: &str,
+) -> String {
+    let port_spec = &rule.port_spec;
+    let family = nft_family(rule.family);
+    let local_ip = local_ips.get(rule.family);
+    let (host_port, node_port) = (port_spec.host_port, port_spec.dest_port);
+    let d_ip = match local_ip {
+        None => "".to_owned(),
+        Some(ip) => format!("{family} daddr {}", addr_set(ip)),
+    };
+    let s_range = match &rule.allow_range {
+        None => "".to_owned(),
+        Some(s) => format!("{family} saddr {}", addr_set(s)),
+    };
+    let (proto, state) = match &port_spec.proto {
+        Proto::Tcp => ("tcp", "ct state new"),
+        Proto::Udp => ("udp", ""),
+    };
+
+    let (chain, selector) = match rule.interface.name.as_str() {
+        "lo" => (
+            "output",
+            format!(
+                "oifname \"lo\" {family} daddr {local_ip} {proto} dport {host_port} {state}",
+                local_ip = addr_set(local_ip.expect(
+                    "should not have a local rule without local IP"
+                ))
+            ),
+        ),
+        _ => (
+            "prerouting",
+            format!(
+                "iifname \"{interface}\" {s_range} {d_ip} {proto} dport {host_port} {state}",
+                interface = rule.interface.name,
+            ),
+        ),
+    };
+    let balance = match rule.nth {
+        0 => "".to_owned(),
+        i => format!("numgen inc mod {} 0", i + 1),
+    };
+    let jump = format!(
+        "dnat to {node_addr}:{node_port}",
+        node_addr = match rule.family {
+            IpFamily::V4 => rule.dest_addr.to_owned(),
+            IpFamily::V6 => format!("[{}]", rule.dest_addr),
+        },
+    );
+    // nft wants the comment as the very last part of the rule
+    let comment = format!(
+        "comment \"{}\"",
+        rule_comment(rule.comment.as_deref(), &rule.rule_id(config_hash)),
+    );
+    format!(
+        "add rule {family} {NFT_TABLE} {chain} {selector} {balance} {jump} {comment}"
+    )
+}
+
+pub(crate) fn nft_family(family: IpFamily) -> &'static str {
+    match family {
+        IpFamily::V4 => "ip",
+        IpFamily::V6 => "ip6",
+    }
+}
+
+/// Idempotently creates the epok table and its NAT chains.
+pub(crate) fn setup_statements(
+    family: IpFamily,
+) -> impl Iterator<Item = String> {
+    let family = nft_family(family);
+    [
+        format!("add table {family} {NFT_TABLE}"),
+        format!(
+            "add chain {family} {NFT_TABLE} prerouting {{ type nat hook prerouting priority -100; }}"
+        ),
+        format!(
+            "add chain {family} {NFT_TABLE} output {{ type nat hook output priority -100; }}"
+        ),
+    ]
+    .into_iter()
+}
+
+/// Whether `nft` failed because the table it was asked about does not
+/// exist, rather than because it could not be run at all.
+fn is_missing_table(e: &Error) -> bool {
+    let Error::CommandFailed { code, stderr, .. } = e else { return false };
+    *code != Some(SSH_CONNECTION_FAILED)
+        && stderr.starts_with("Error: No such file or directory")
+}
+
+/// Turns comma-separated addresses into an anonymous nft set.
+fn addr_set(addrs: &str) -> String {
+    if addrs.contains(',') {
+        format!("{{ {} }}", addrs.split(',').map(str::trim).join(", "))
+    } else {
+        addrs.to_owned()
+    }
+}
+
+/// Parses the epok rules out of `nft -a list table` output, along with
+/// the `nft` arguments that delete each of them by handle.
+fn parse_listing(
+    family: IpFamily,
+    listing: &str,
+) -> Vec<(InstalledRule, String)> {
+    let mut chain = "";
+    listing
+        .lines()
+        .map(str::trim)
+        .filter_map(|line| {
+            if let Some(name) = line.strip_prefix("chain ") {
+                chain = name.split(' ').next().unwrap_or_default();
+                return None;
+            }
+            let rule = parse_rule(family, chain, line)?;
+            let handle = line.rsplit_once("# handle ")?.1.trim();
+            let delete = format!(
+                "delete rule {} {NFT_TABLE} {chain} handle {handle}",
+                nft_family(family)
+            );
+            Some((rule, delete))
+        })
+        .collect()
+}
+
+fn parse_rule(
+    family: IpFamily,
+    chain: &str,
+    line: &str,
+) -> Option<InstalledRule> {
+    if !line.contains(RULE_MARKER) {
+        return None;
+    }
+    let tokens = split_quoted(line);
+    let mut rule = InstalledRule {
+        family,
+        chain: chain.to_owned(),
+        interface: None,
+        proto: None,
+        host_port: None,
+        destination: None,
+        comment: String::new(),
+        rule_id: String::new(),
+    };
+    for (first, second, third) in tokens.iter().tuple_windows() {
+        match (first.as_str(), second.as_str()) {
+            ("iifname" | "oifname", _) => {
+                rule.interface = Some(second.to_owned())
+            }
+            ("tcp", "dport") => {
+                rule.proto = Some(Proto::Tcp);
+                rule.host_port = third.parse().ok();
+            }
+            ("udp", "dport") => {
+                rule.proto = Some(Proto::Udp);
+                rule.host_port = third.parse().ok();
+            }
+            ("dnat", "to") => rule.destination = Some(third.to_owned()),
+            ("comment", _) => {
+                (rule.comment, rule.rule_id) = split_comment(second)?;
+            }
+            _ => {}
+        }
+    }
+    (!rule.rule_id.is_empty()).then_some(rule)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers every command with what the function makes of it.
+    struct FakeExecutor(fn(&str) -> Result<String>);
+
+    impl Executor for FakeExecutor {
+        fn run(&self, cmd: &Cmd) -> Result<String> {
+            (self.0)(&cmd.to_string())
+        }
+
+        fn run_with_stdin(&self, cmd: &Cmd, _stdin: &str) -> Result<String> {
+            self.run(cmd)
+        }
+    }
+
+    fn backend(executor: FakeExecutor) -> NftablesBackend<FakeExecutor> {
+        NftablesBackend::new(
+            executor,
+            BatchOpts { batch_commands: false, batch_size: 8192 },
+            LocalIps::default(),
+            None,
+            CommandOpts::default(),
+        )
+    }
+
+    fn failed(cmd: &str, code: i32, stderr: &str) -> Error {
+        Error::CommandFailed {
+            host: "hv1".to_owned(),
+            cmd: cmd.to_owned(),
+            code: Some(code),
+            stderr: stderr.to_owned(),
+        }
+    }
+
+    const LISTING: &str = r#"table ip epok { # handle 7
+	chain prerouting { # handle 1
+		type nat hook prerouting priority dstnat; policy accept;
+		iifname "eth0" tcp dport 80 ct state new dnat to 10.0.0.1:30080 comment "service: foo/bar; node: n0; epok_rule_id: abc::service::x" # handle 4
+	}
+	chain output { # handle 2
+		type nat hook output priority -100; policy accept;
+		oifname "lo" ip daddr 1.2.3.4 tcp dport 80 ct state new dnat to 10.0.0.1:30080 comment "service: foo/bar; node: n0; epok_rule_id: abc::service::y" # handle 5
+	}
+}"#;
+
+    #[test]
+    fn it_parses_listing() {
+        let rules = parse_listing(IpFamily::V4, LISTING)
+            .into_iter()
+            .map(|(rule, _)| rule)
+            .collect::<Vec<_>>();
+        assert_eq!(rules.len(), 2);
+        assert_eq!(
+            rules[0],
+            InstalledRule {
+                family: IpFamily::V4,
+                chain: "prerouting".to_owned(),
+                interface: Some("eth0".to_owned()),
+                proto: Some(Proto::Tcp),
+                host_port: Some(80),
+                destination: Some("10.0.0.1:30080".to_owned()),
+                comment: "service: foo/bar; node: n0".to_owned(),
+                rule_id: "abc::service::x".to_owned(),
+            }
+        );
+        assert_eq!(rules[1].chain, "output");
+        assert_eq!(rules[1].interface.as_deref(), Some("lo"));
+    }
+
+    #[test]
+    fn it_deletes_by_handle() {
+        let deletes = parse_listing(IpFamily::V4, LISTING)
+            .into_iter()
+            .map(|(_, delete)| delete)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            deletes,
+            vec![
+                "delete rule ip epok prerouting handle 4",
+                "delete rule ip epok output handle 5",
+            ]
+        );
+    }
+
+    #[test]
+    fn it_builds_address_sets() {
+        assert_eq!(addr_set("1.2.3.4"), "1.2.3.4");
+        assert_eq!(addr_set("1.2.3.4,10.0.0.0/8"), "{ 1.2.3.4, 10.0.0.0/8 }");
+    }
+
+    #[test]
+    fn it_reads_a_missing_table_as_empty() {
+        let mut backend = backend(FakeExecutor(|cmd| match cmd {
+            "sudo nft -a list table ip epok" => Ok(LISTING.to_owned()),
+            _ => Err(failed(
+                cmd,
+                1,
+                "Error: No such file or directory\n\
+                 list table ip6 epok\n           ^^^^",
+            )),
+        }));
+        assert_eq!(backend.read_state().unwrap().len(), 2);
+        assert_eq!(backend.tables, [IpFamily::V4]);
+    }
+
+    #[test]
+    fn it_reads_an_unsupported_ipv6_table_as_empty() {
+        let mut backend = backend(FakeExecutor(|cmd| match cmd {
+            "sudo nft -a list table ip epok" => Ok(LISTING.to_owned()),
+            _ => Err(failed(
+                cmd,
+                1,
+                "Error: Could not process rule: Address family not \
+                 supported by protocol",
+            )),
+        }));
+        assert_eq!(backend.read_state().unwrap().len(), 2);
+
+        // unless there is an IPv6 address to forward from
+        backend.local_ips.v6 = Some("fd00::1".to_owned());
+        assert!(backend.read_state().is_err());
+    }
+
+    #[test]
+    fn it_fails_to_read_an_unreachable_table() {
+        let mut unreachable = backend(FakeExecutor(|cmd| {
+            Err(failed(cmd, SSH_CONNECTION_FAILED, "Connection refused"))
+        }));
+        let err = unreachable.read_state().unwrap_err();
+        assert!(err.is_transient(), "{err}");
+
+        let mut denied = backend(FakeExecutor(|cmd| {
+            Err(failed(cmd, 1, "sudo: a password is required"))
+        }));
+        assert!(denied.read_state().is_err());
+    }
+}
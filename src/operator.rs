@@ -1,28 +1,52 @@
-use std::{cell::RefCell, collections::HashMap};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    panic::resume_unwind,
+    sync::{Mutex, MutexGuard},
+    thread,
+    time::Duration,
+};
 
-use itertools::iproduct;
+use backon::{BlockingRetryable, ExponentialBuilder};
+use itertools::{iproduct, Itertools};
+use serde::{Deserialize, Serialize};
 use sha256::digest;
 
 use crate::{
-    logging::*, Error, ExternalPorts, Interface, Node, Pod, PortSpec, Proto,
-    ResourceLike, Result, Service, State,
+    logging::*, Error, ExternalPorts, InstalledRule, Interface, IpFamilies,
+    IpFamily, Node, Pod, PortSpec, Proto, ResourceLike, Result, Service,
+    State, LOCALHOST, LOCK_RETRIES,
 };
 
 pub trait Backend {
-    fn read_state(&mut self);
+    fn read_state(&mut self) -> Result<Vec<InstalledRule>>;
     fn apply_rules(
         &mut self,
         rules: impl IntoIterator<Item = Rule>,
     ) -> Result<()>;
-    fn delete_rules<P>(&mut self, pred: P) -> Result<()>
-    where
-        P: FnMut(&&str) -> bool;
+    fn delete_rules(
+        &mut self,
+        rule_ids: impl IntoIterator<Item = String>,
+    ) -> Result<()>;
     fn config_hash(&self) -> String;
+    /// Whether the backend would install `rule` at all; rules it skips on
+    /// purpose are not reported as missing during a resync.
+    fn can_install(&self, _rule: &Rule) -> bool { true }
+    /// Stages whatever the installed rules rely on besides themselves that
+    /// went missing since, such as the jumps into dedicated chains, and
+    /// returns how many repairs that takes.
+    fn repair_setup(&mut self) -> usize { 0 }
+    /// Removes whatever the backend set up besides the rules themselves,
+    /// such as dedicated chains or tables.
+    fn teardown(&mut self) -> Result<()> { Ok(()) }
+    /// Flushes the changes staged by `apply_rules` and `delete_rules`.
+    /// Backends that apply changes eagerly can rely on the default.
+    fn commit(&mut self) -> Result<()> { Ok(()) }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub struct Rule {
     pub dest_addr: String,
+    pub family: IpFamily,
     pub allow_range: Option<String>,
     pub port_spec: PortSpec,
     pub interface: Interface,
@@ -38,24 +62,236 @@ impl Rule {
     }
 }
 
+/// Host addresses that forwarded traffic is destined to, per address family.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LocalIps {
+    pub v4: Option<String>,
+    pub v6: Option<String>,
+}
+
+impl LocalIps {
+    pub fn get(&self, family: IpFamily) -> Option<&String> {
+        match family {
+            IpFamily::V4 => self.v4.as_ref(),
+            IpFamily::V6 => self.v6.as_ref(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool { self.v4.is_none() && self.v6.is_none() }
+
+    /// Whether the host may lack `family` altogether, so that a firewall
+    /// table it cannot read counts as empty. That is IPv6, which IPv4-only
+    /// hosts may have turned off, unless there is a local IPv6 address.
+    pub fn is_optional(&self, family: IpFamily) -> bool {
+        family == IpFamily::V6 && self.v6.is_none()
+    }
+}
+
 pub struct Operator<B> {
-    backend: RefCell<B>,
+    hosts: Vec<Host<B>>,
 }
 
-impl<B: Backend> Operator<B> {
-    pub fn new(backend: B) -> Self { Self { backend: RefCell::new(backend) } }
+/// A host machine the rules get installed on, through its own backend.
+pub struct Host<B> {
+    name: String,
+    /// Interfaces to forward from, instead of the ones in the state
+    interfaces: Option<Vec<Interface>>,
+    backend: Mutex<B>,
+    /// Why the last reconcile or resync on this host failed, if it did
+    last_error: Mutex<Option<String>>,
+    /// The state last applied on this host in full
+    applied: Mutex<Option<State>>,
+}
+
+impl<B: Backend + Send> Operator<B> {
+    pub fn new(backend: B) -> Self {
+        Self::with_hosts([Host::new(LOCALHOST, backend)])
+    }
 
+    pub fn with_hosts(hosts: impl IntoIterator<Item = Host<B>>) -> Self {
+        Self { hosts: hosts.into_iter().collect() }
+    }
+
+    /// Applies the changes from `prev_state` to `state` on every host. A
+    /// host whose last reconcile failed diffs against the state it last
+    /// applied instead, so that it catches up on the changes it missed.
     pub fn reconcile(&self, state: &State, prev_state: &State) -> Result<()> {
+        let (state, prev_state) = (&state.resolved(), &prev_state.resolved());
         let (added, removed) = state.diff(prev_state);
-        if added.is_empty() && removed.is_empty() {
+        if added.is_empty() && removed.is_empty() && !self.is_failing() {
             return Ok(());
         }
 
         info!("added state: {added:?}");
         info!("removed state: {removed:?}");
 
-        let mut backend = self.backend.borrow_mut();
-        backend.read_state();
+        self.on_hosts(|host| {
+            let state = host.state(state);
+            let prev_state = host
+                .applied()
+                .get_or_insert_with(|| host.state(prev_state))
+                .clone();
+            while_contended(|| host.reconcile(&state, &prev_state))?;
+            *host.applied() = Some(state);
+            Ok(())
+        })
+        .map(drop)
+    }
+
+    /// Brings the installed rules back in line with `state` on every host,
+    /// regardless of what changed since the last reconcile. Returns the
+    /// number of repairs.
+    pub fn resync(&self, state: &State) -> Result<usize> {
+        let state = &state.resolved();
+        let repairs = self.on_hosts(|host| {
+            let state = host.state(state);
+            let repairs = while_contended(|| host.resync(&state))?;
+            *host.applied() = Some(state);
+            Ok(repairs)
+        })?;
+        Ok(repairs.into_iter().sum())
+    }
+
+    /// Whether the last reconcile or resync failed on some host, which
+    /// then needs another try.
+    pub fn is_failing(&self) -> bool {
+        self.hosts.iter().any(Host::is_failing)
+    }
+
+    pub fn cleanup(&self) -> Result<()> {
+        self.on_hosts(Host::cleanup).map(drop)
+    }
+
+    /// The hosts along with why their last reconcile failed, if it did.
+    pub fn host_errors(&self) -> Vec<(&str, Option<String>)> {
+        self.hosts
+            .iter()
+            .map(|host| (host.name.as_str(), host.last_error().clone()))
+            .collect()
+    }
+
+    /// Runs `f` on all hosts at once, so that a slow or unreachable host
+    /// does not hold up the others.
+    fn on_hosts<T: Send>(
+        &self,
+        f: impl Fn(&Host<B>) -> Result<T> + Sync,
+    ) -> Result<Vec<T>> {
+        let results = match self.hosts.as_slice() {
+            [host] => vec![host.track(f(host))],
+            hosts => thread::scope(|scope| {
+                let f = &f;
+                let handles = hosts
+                    .iter()
+                    .map(|host| {
+                        scope.spawn(move || {
+                            let _span =
+                                info_span!("host", name = host.name).entered();
+                            host.track(f(host))
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                handles
+                    .into_iter()
+                    .map(|handle| {
+                        handle.join().unwrap_or_else(|e| resume_unwind(e))
+                    })
+                    .collect()
+            }),
+        };
+        let (values, errors): (Vec<_>, Vec<_>) =
+            results.into_iter().partition_result();
+        match errors.is_empty() {
+            true => Ok(values),
+            false => Err(Error::HostsError(errors)),
+        }
+    }
+}
+
+impl<B> Host<B> {
+    pub fn new(name: impl Into<String>, backend: B) -> Self {
+        Self {
+            name: name.into(),
+            interfaces: None,
+            backend: Mutex::new(backend),
+            last_error: Mutex::new(None),
+            applied: Mutex::new(None),
+        }
+    }
+
+    /// Forwards from `interfaces` on this host, whatever the state says.
+    pub fn with_interfaces(mut self, interfaces: Vec<Interface>) -> Self {
+        self.interfaces = Some(interfaces);
+        self
+    }
+
+    /// Starts this host out failing, as it could not be set up, so that it
+    /// gets all of the state on the next retry.
+    pub fn failing(self, e: &Error) -> Self {
+        *self.last_error() = Some(e.to_string());
+        *self.applied() = Some(State::default());
+        self
+    }
+
+    pub fn name(&self) -> &str { &self.name }
+
+    fn state(&self, state: &State) -> State {
+        match &self.interfaces {
+            Some(interfaces) => state.clone().with(interfaces.to_owned()),
+            None => state.clone(),
+        }
+    }
+
+    fn backend(&self) -> MutexGuard<'_, B> {
+        self.backend.lock().expect("host backend poisoned")
+    }
+
+    fn last_error(&self) -> MutexGuard<'_, Option<String>> {
+        self.last_error.lock().expect("host error poisoned")
+    }
+
+    fn applied(&self) -> MutexGuard<'_, Option<State>> {
+        self.applied.lock().expect("host state poisoned")
+    }
+
+    fn is_failing(&self) -> bool { self.last_error().is_some() }
+
+    /// Records the outcome of an operation on this host.
+    fn track<T>(&self, result: Result<T>) -> Result<T> {
+        let mut last_error = self.last_error();
+        match result {
+            Ok(value) => {
+                if let Some(e) = last_error.take() {
+                    info!("host {} recovered from: {e}", self.name);
+                }
+                Ok(value)
+            }
+            Err(e) => {
+                *last_error = Some(e.to_string());
+                Err(Error::HostError(self.name.to_owned(), Box::new(e)))
+            }
+        }
+    }
+}
+
+impl<B: Backend> Host<B> {
+    fn reconcile(&self, state: &State, prev_state: &State) -> Result<()> {
+        let (added, removed) = state.diff(prev_state);
+        if added.is_empty() && removed.is_empty() {
+            return Ok(());
+        }
+
+        let mut backend = self.backend();
+        let installed = backend
+            .read_state()
+            .map_err(|e| Error::OperatorError(Box::new(e)))?;
+        let config_hash = backend.config_hash();
+        let installed_ids = installed
+            .iter()
+            .map(|rule| rule.rule_id.to_owned())
+            .collect::<HashSet<_>>();
+
+        let mut additions = Vec::new();
+        let mut deletions = Vec::new();
 
         // Case 1: nuclear option - node or interface changed => full cycle
         if state.get::<Node>() != prev_state.get::<Node>()
@@ -66,155 +302,341 @@ impl<B: Backend> Operator<B> {
 
             let new_rule_ids = new_rules
                 .iter()
-                .map(|r| r.rule_id(&backend.config_hash()))
-                .collect::<Vec<_>>();
+                .map(|r| r.rule_id(&config_hash))
+                .collect::<HashSet<_>>();
 
             info!("new rule ids: {new_rule_ids:?}");
-            info!("config hash: {}", backend.config_hash());
+            info!("config hash: {config_hash}");
+
+            additions.extend(new_rules);
+            deletions.extend(
+                installed
+                    .iter()
+                    .filter(|rule| !new_rule_ids.contains(&rule.rule_id)),
+            );
+        } else {
+            // Case 2: service changes
+            if state.get::<Service>() != prev_state.get::<Service>() {
+                let removed_service_ids = removed
+                    .get::<Service>()
+                    .iter()
+                    .map(|s| s.service_hash())
+                    .collect::<HashSet<_>>();
+
+                additions.extend(make_rules(
+                    &state.clone().with(added.get::<Service>()),
+                ));
+                deletions.extend(installed.iter().filter(|rule| {
+                    rule.service_hash().is_some_and(|service_id| {
+                        removed_service_ids.contains(service_id)
+                    })
+                }));
+            }
 
-            backend
-                .apply_rules(new_rules)
-                .map_err(|e| Error::OperatorError(Box::new(e)))?;
+            // Case 3: pod change => semi-nuclear because one pod might have
+            // different indexes in different (source_port, protocol)
+            // collections, so it's safer to re-create all rules
+            if state.get::<Pod>() != prev_state.get::<Pod>() {
+                let new_rules = make_pod_rules(state);
+
+                let new_rule_ids = new_rules
+                    .iter()
+                    .map(|r| r.rule_id(&config_hash))
+                    .collect::<HashSet<_>>();
+
+                additions.extend(new_rules);
+                deletions.extend(installed.iter().filter(|rule| {
+                    rule.is_pod_rule() && !new_rule_ids.contains(&rule.rule_id)
+                }));
+            }
+        }
 
-            return backend
-                .delete_rules(|&rule| {
-                    new_rule_ids
-                        .iter()
-                        .all(|new_rule_id| !rule.contains(new_rule_id))
-                })
-                .map_err(|e| Error::OperatorError(Box::new(e)));
+        let mut summary = Summary::default();
+        for rule in &additions {
+            if !installed_ids.contains(&rule.rule_id(&config_hash))
+                && backend.can_install(rule)
+            {
+                summary.add(rule.comment.as_deref().unwrap_or_default());
+            }
         }
+        for rule in &deletions {
+            summary.delete(&rule.comment);
+        }
+        summary.log();
 
-        // Case 2: service changes
-        if state.get::<Service>() != prev_state.get::<Service>() {
-            let removed_service_ids = removed
-                .get::<Service>()
-                .iter()
-                .map(|s| s.service_hash())
-                .collect::<Vec<_>>();
+        let deletions = deletions
+            .into_iter()
+            .map(|rule| rule.rule_id.to_owned())
+            .collect::<Vec<_>>();
+        backend
+            .apply_rules(additions)
+            .and_then(|_| backend.delete_rules(deletions))
+            .and_then(|_| backend.commit())
+            .map_err(|e| Error::OperatorError(Box::new(e)))
+    }
 
-            backend
-                .apply_rules(make_rules(
-                    &state.clone().with(added.get::<Service>()),
-                ))
-                .map_err(|e| Error::OperatorError(Box::new(e)))?;
-
-            backend
-                .delete_rules(|&rule| {
-                    removed_service_ids
-                        .iter()
-                        .any(|service_id| rule.contains(service_id))
-                })
-                .map_err(|e| Error::OperatorError(Box::new(e)))?;
-        }
+    fn resync(&self, state: &State) -> Result<usize> {
+        let mut backend = self.backend();
+        let installed = backend
+            .read_state()
+            .map_err(|e| Error::OperatorError(Box::new(e)))?;
+        let setup_repairs = backend.repair_setup();
+        let config_hash = backend.config_hash();
+
+        let desired = make_rules(state)
+            .into_iter()
+            .chain(make_pod_rules(state))
+            .filter(|rule| backend.can_install(rule))
+            .map(|rule| (rule.rule_id(&config_hash), rule))
+            .collect::<HashMap<_, _>>();
+        let installed_ids = installed
+            .iter()
+            .map(|rule| rule.rule_id.as_str())
+            .collect::<HashSet<_>>();
 
-        // Case 3: pod change => semi-nuclear because one pod might have
-        // different indexes in different (source_port, protocol) collections,
-        // so it's safer to re-create all rules
-        if state.get::<Pod>() != prev_state.get::<Pod>() {
-            let new_rules = make_pod_rules(state);
+        let missing = desired
+            .iter()
+            .filter(|(rule_id, _)| !installed_ids.contains(rule_id.as_str()))
+            .map(|(_, rule)| rule.to_owned())
+            .collect::<Vec<_>>();
+        let foreign = installed
+            .into_iter()
+            .filter(|rule| !desired.contains_key(&rule.rule_id))
+            .collect::<Vec<_>>();
+
+        for rule in &missing {
+            warn!(
+                "drift: reinstalling missing rule {} ({})",
+                rule.rule_id(&config_hash),
+                rule.comment.as_deref().unwrap_or_default()
+            );
+        }
+        for rule in &foreign {
+            warn!(
+                "drift: removing foreign rule {} ({})",
+                rule.rule_id, rule.comment
+            );
+        }
 
-            let new_rule_ids = new_rules
-                .iter()
-                .map(|r| r.rule_id(&backend.config_hash()))
-                .collect::<Vec<_>>();
-
-            backend
-                .apply_rules(new_rules)
-                .map_err(|e| Error::OperatorError(Box::new(e)))?;
-
-            backend
-                .delete_rules(|&rule| {
-                    rule.contains("pod::")
-                        && new_rule_ids
-                            .iter()
-                            .all(|new_rule_id| !rule.contains(new_rule_id))
-                })
-                .map_err(|e| Error::OperatorError(Box::new(e)))?;
+        let repairs = setup_repairs + missing.len() + foreign.len();
+        if repairs == 0 {
+            return Ok(0);
         }
 
-        Ok(())
+        backend
+            .apply_rules(missing)
+            .and_then(|_| {
+                backend
+                    .delete_rules(foreign.into_iter().map(|rule| rule.rule_id))
+            })
+            .and_then(|_| backend.commit())
+            .map_err(|e| Error::OperatorError(Box::new(e)))?;
+        Ok(repairs)
     }
 
-    pub fn cleanup(&self) -> Result<()> {
-        let mut backend = self.backend.borrow_mut();
-        backend.read_state();
+    fn cleanup(&self) -> Result<()> {
+        let mut backend = self.backend();
+        let installed = backend
+            .read_state()
+            .map_err(|e| Error::OperatorError(Box::new(e)))?;
         backend
-            .delete_rules(|_| true)
+            .delete_rules(installed.into_iter().map(|rule| rule.rule_id))
+            .and_then(|_| backend.teardown())
+            .and_then(|_| backend.commit())
             .map_err(|e| Error::OperatorError(Box::new(e)))
     }
 }
 
-fn make_rules(state: &State) -> Vec<Rule> {
-    let num_nodes = state.get::<Node>().len();
-    let mut rules = Vec::new();
+/// Runs `f` again, with backoff, while another process holds the firewall
+/// lock: the changes were rejected as a whole and can go through as is.
+fn while_contended<T>(f: impl FnMut() -> Result<T>) -> Result<T> {
+    let backoff = ExponentialBuilder::new()
+        .with_min_delay(Duration::from_millis(200))
+        .with_max_delay(Duration::from_secs(5))
+        .with_max_times(LOCK_RETRIES);
+    f.retry(backoff)
+        .when(|e| matches!(e.root(), Error::XtablesLocked(_)))
+        .notify(|e, delay| warn!("{e}, retry in {delay:?}"))
+        .call()
+}
 
-    iproduct!(
-        state.get::<Node>().iter().enumerate(),
-        &state.get::<Service>(),
-        &state.get::<Interface>()
-    )
-    .for_each(|((node_index, node), service, interface)| {
-        if interface.is_external && service.is_internal {
-            return;
-        }
+/// Rule additions and deletions of a reconcile, per service and pod.
+#[derive(Default)]
+struct Summary(BTreeMap<String, (usize, usize)>);
 
-        for spec in &service.external_ports.specs {
-            let dest_addr = node.addr.to_owned();
-            let nth = node_index;
-            let out_of = num_nodes;
-
-            let mut rule_hash = digest(format!(
-                "{}::{}::{}::{}::{}",
-                dest_addr, nth, out_of, interface.name, interface.is_external,
-            ));
-            rule_hash.truncate(16);
-            let rule_hash =
-                format!("service::{}::{}", service.service_hash(), rule_hash);
-
-            rules.push(Rule {
-                dest_addr,
-                allow_range: service.allow_range.to_owned(),
-                port_spec: spec.to_owned(),
-                interface: interface.to_owned(),
-                nth,
-                out_of,
-                comment: Some(format!(
-                    "service: {}; node: {}",
-                    service.fqn(),
-                    node.name
-                )),
-                rule_hash,
-            })
+impl Summary {
+    fn add(&mut self, comment: &str) {
+        self.0.entry(owner(comment)).or_default().0 += 1;
+    }
+
+    fn delete(&mut self, comment: &str) {
+        self.0.entry(owner(comment)).or_default().1 += 1;
+    }
+
+    fn log(&self) {
+        if self.0.is_empty() {
+            info!("no rule changes");
         }
-    });
+        for (owner, (additions, deletions)) in &self.0 {
+            info!("{owner}: +{additions} -{deletions} rule(s)");
+        }
+    }
+}
+
+/// Extracts the service or pod a rule belongs to from its comment.
+fn owner(comment: &str) -> String {
+    let mut fields = comment.split("; ").filter_map(|f| f.split_once(": "));
+    match fields.next() {
+        Some(("pod", pod)) => match fields.find(|(k, _)| *k == "namespace") {
+            Some((_, namespace)) => format!("pod {namespace}/{pod}"),
+            None => format!("pod {pod}"),
+        },
+        Some((kind, name)) => format!("{kind} {name}"),
+        None => "unknown".to_owned(),
+    }
+}
+
+/// Whether a service or pod restricted to `interfaces` forwards from
+/// `interface`.
+fn forwards_from(
+    interfaces: &Option<Vec<String>>,
+    interface: &Interface,
+) -> bool {
+    interfaces.as_ref().is_none_or(|names| names.contains(&interface.name))
+}
+
+/// The allowed ranges of `family` only, `None` when there are ranges but
+/// none of this family: the service or pod stays unreachable through it.
+fn family_ranges(
+    allow_range: &Option<String>,
+    family: IpFamily,
+) -> Option<Option<String>> {
+    let Some(ranges) = allow_range else {
+        return Some(None);
+    };
+    let ranges = ranges
+        .split(',')
+        .filter(|range| IpFamily::of(range) == family)
+        .join(",");
+    (!ranges.is_empty()).then_some(Some(ranges))
+}
+
+fn make_rules(state: &State) -> Vec<Rule> {
+    let mut rules = Vec::new();
+
+    for family in IpFamilies::Both.iter() {
+        let nodes = state
+            .get::<Node>()
+            .into_iter()
+            .filter_map(|node| Some((node.addr(family)?.to_owned(), node)))
+            .collect::<Vec<_>>();
+        let num_nodes = nodes.len();
+
+        iproduct!(
+            nodes.iter().enumerate(),
+            &state.get::<Service>(),
+            &state.get::<Interface>()
+        )
+        .for_each(
+            |((node_index, (node_addr, node)), service, interface)| {
+                if interface.is_external && service.is_internal {
+                    return;
+                }
+                if !service.families.contains(family)
+                    || !forwards_from(&service.interfaces, interface)
+                {
+                    return;
+                }
+                let Some(allow_range) =
+                    family_ranges(&service.allow_range, family)
+                else {
+                    return;
+                };
+
+                for spec in &service.external_ports.specs {
+                    let dest_addr = node_addr.to_owned();
+                    let nth = node_index;
+                    let out_of = num_nodes;
+
+                    let mut rule_hash = digest(format!(
+                        "{}::{}::{}::{}::{}",
+                        dest_addr,
+                        nth,
+                        out_of,
+                        interface.name,
+                        interface.is_external,
+                    ));
+                    rule_hash.truncate(16);
+                    let rule_hash = format!(
+                        "service::{}::{}",
+                        service.service_hash(),
+                        rule_hash
+                    );
+
+                    rules.push(Rule {
+                        dest_addr,
+                        family,
+                        allow_range: allow_range.to_owned(),
+                        port_spec: spec.to_owned(),
+                        interface: interface.to_owned(),
+                        nth,
+                        out_of,
+                        comment: Some(format!(
+                            "service: {}; node: {}",
+                            service.fqn(),
+                            node.name
+                        )),
+                        rule_hash,
+                    })
+                }
+            },
+        );
+    }
     rules
 }
 
 fn make_pod_rules(state: &State) -> Vec<Rule> {
-    let mut pod_map = HashMap::<(u16, Proto), Vec<Pod>>::new();
+    let mut pod_map =
+        HashMap::<(IpFamily, u16, Proto), Vec<(String, Pod)>>::new();
 
     state.get::<Pod>().iter().filter(|p| p.is_active()).for_each(|p| {
-        p.external_ports.specs.iter().for_each(|s| {
-            let mut new_p = p.clone();
-            new_p.external_ports = ExternalPorts { specs: vec![s.clone()] };
-            pod_map.entry((s.host_port, s.proto)).or_default().push(new_p)
+        p.families.iter().for_each(|family| {
+            let Some(addr) = p.addr(family) else {
+                return;
+            };
+            p.external_ports.specs.iter().for_each(|s| {
+                let mut new_p = p.clone();
+                new_p.external_ports =
+                    ExternalPorts { specs: vec![s.clone()] };
+                pod_map
+                    .entry((family, s.host_port, s.proto))
+                    .or_default()
+                    .push((addr.to_owned(), new_p))
+            })
         })
     });
 
     let mut rules = Vec::new();
 
     for interface in state.get::<Interface>() {
-        pod_map.values().for_each(|pods| {
+        pod_map.iter().for_each(|(&(family, _, _), pods)| {
             let out_of = pods.len();
-            pods.iter().enumerate().for_each(|(nth, pod)| {
+            pods.iter().enumerate().for_each(|(nth, (addr, pod))| {
                 if interface.is_external && pod.is_internal {
                     return;
                 }
                 if !interface.is_external && pod.is_external {
                     return;
                 }
-                let dest_addr = pod.addr.to_owned();
+                if !forwards_from(&pod.interfaces, &interface) {
+                    return;
+                }
+                let Some(allow_range) =
+                    family_ranges(&pod.allow_range, family)
+                else {
+                    return;
+                };
+                let dest_addr = addr.to_owned();
 
                 let mut rule_hash = digest(format!(
                     "{}::{}::{}::{}::{}",
@@ -230,7 +652,8 @@ fn make_pod_rules(state: &State) -> Vec<Rule> {
 
                 rules.push(Rule {
                     dest_addr,
-                    allow_range: None,
+                    family,
+                    allow_range,
                     port_spec: pod.external_ports.specs[0].to_owned(),
                     interface: interface.to_owned(),
                     nth,
@@ -250,44 +673,83 @@ fn make_pod_rules(state: &State) -> Vec<Rule> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{res::Proto, ExternalPorts, PortSpec};
+    use crate::{
+        res::{MappedPort, MappingTarget, Proto},
+        ExternalPorts, PortMapping, PortSpec,
+    };
 
     #[derive(Default)]
     struct TestBackend {
         rules: Vec<Rule>,
+        unreachable: bool,
+        /// How many more times the firewall reports being locked
+        locked: usize,
+        /// How many repairs the setup the rules rely on needs
+        broken_setup: usize,
     }
 
     impl Operator<TestBackend> {
-        fn get_rules(&self) -> Vec<Rule> {
-            self.backend.borrow().rules.clone()
+        fn get_rules(&self) -> Vec<Rule> { self.host_rules(0) }
+
+        fn host_rules(&self, host: usize) -> Vec<Rule> {
+            self.hosts[host].backend().rules.clone()
         }
     }
 
     impl Backend for TestBackend {
-        fn read_state(&mut self) {
-            // noop: we keep state in memory
+        fn read_state(&mut self) -> Result<Vec<InstalledRule>> {
+            let config_hash = self.config_hash();
+            Ok(self
+                .rules
+                .iter()
+                .map(|r| InstalledRule::of(r, "test", &config_hash))
+                .collect())
         }
 
         fn apply_rules(
             &mut self,
             rules: impl IntoIterator<Item = Rule>,
         ) -> Result<()> {
+            if self.unreachable {
+                return Err(Error::ExecutorError(std::io::Error::other(
+                    "host unreachable",
+                )));
+            }
+            if self.locked > 0 {
+                self.locked -= 1;
+                return Err(Error::BackendError(Box::new(
+                    Error::XtablesLocked(Box::new(Error::CommandFailed {
+                        host: LOCALHOST.to_owned(),
+                        cmd: "iptables-restore".to_owned(),
+                        code: Some(4),
+                        stderr: "Another app is currently holding the \
+                                 xtables lock."
+                            .to_owned(),
+                    })),
+                )));
+            }
             for rule in rules {
                 self.rules.push(rule);
             }
             Ok(())
         }
 
-        fn delete_rules<P>(&mut self, mut pred: P) -> Result<()>
-        where
-            P: FnMut(&&str) -> bool,
-        {
+        fn delete_rules(
+            &mut self,
+            rule_ids: impl IntoIterator<Item = String>,
+        ) -> Result<()> {
             let config_hash = self.config_hash().to_owned();
-            self.rules.retain(|r| !pred(&r.rule_id(&config_hash).as_str()));
+            let rule_ids = rule_ids.into_iter().collect::<HashSet<_>>();
+            self.rules
+                .retain(|r| !rule_ids.contains(&r.rule_id(&config_hash)));
             Ok(())
         }
 
         fn config_hash(&self) -> String { "<default>".to_owned() }
+
+        fn repair_setup(&mut self) -> usize {
+            std::mem::take(&mut self.broken_setup)
+        }
     }
 
     #[test]
@@ -302,6 +764,141 @@ mod tests {
         assert!(rules.is_empty());
     }
 
+    #[test]
+    fn it_resyncs_drifted_rules() {
+        let backend = TestBackend::default();
+        let operator = Operator::new(backend);
+
+        let state0 = empty_state();
+        let state1 = state0.clone().with([
+            single_port_service(123, 456),
+            single_port_service(789, 654),
+        ]);
+        operator.reconcile(&state1, &state0).unwrap();
+        assert_eq!(operator.resync(&state1).unwrap(), 0);
+
+        // someone flushed one of our rules and left a stale one behind
+        let stale = {
+            let mut backend = operator.hosts[0].backend();
+            let mut stale = backend.rules.remove(0);
+            stale.rule_hash = "stale".to_owned();
+            backend.rules.push(stale.clone());
+            stale
+        };
+
+        assert_eq!(operator.resync(&state1).unwrap(), 2);
+
+        let rules = operator.get_rules();
+        assert_eq!(rules.len(), 2);
+        assert!(!rules.contains(&stale));
+        assert_eq!(operator.resync(&state1).unwrap(), 0);
+    }
+
+    #[test]
+    fn it_repairs_the_setup_on_resync() {
+        let operator = Operator::new(TestBackend::default());
+        let state = empty_state().with([single_port_service(123, 456)]);
+        operator.reconcile(&state, &empty_state()).unwrap();
+
+        // someone flushed the jumps into our chains
+        operator.hosts[0].backend().broken_setup = 2;
+        assert_eq!(operator.resync(&state).unwrap(), 2);
+        assert_eq!(operator.resync(&state).unwrap(), 0);
+    }
+
+    #[test]
+    fn it_reconciles_hosts_independently() {
+        let operator = Operator::with_hosts([
+            Host::new("a", TestBackend::default()),
+            Host::new(
+                "b",
+                TestBackend { unreachable: true, ..Default::default() },
+            )
+            .with_interfaces(vec![Interface::new("eth1")]),
+        ]);
+
+        let state0 = empty_state();
+        let state1 = state0.clone().with([single_port_service(123, 456)]);
+        let err = operator.reconcile(&state1, &state0).unwrap_err();
+        assert!(err.to_string().starts_with("host b: "), "{err}");
+        assert_eq!(operator.host_rules(0).len(), 1);
+        assert!(operator.host_errors()[1].1.is_some());
+
+        // once back, the host catches up on the changes it missed
+        operator.hosts[1].backend().unreachable = false;
+        let state2 = state1.clone().with([
+            single_port_service(123, 456),
+            single_port_service(789, 654),
+        ]);
+        operator.reconcile(&state2, &state1).unwrap();
+        assert_eq!(operator.host_rules(0).len(), 2);
+        let rules = operator.host_rules(1);
+        assert_eq!(rules.len(), 2);
+        assert!(rules.iter().all(|r| r.interface.name == "eth1"));
+        assert!(operator.host_errors().iter().all(|(_, e)| e.is_none()));
+    }
+
+    #[test]
+    fn it_waits_for_the_firewall_lock() {
+        let operator =
+            Operator::new(TestBackend { locked: 2, ..Default::default() });
+        let state0 = empty_state();
+        let state1 = state0.clone().with([single_port_service(123, 456)]);
+        operator.reconcile(&state1, &state0).unwrap();
+        assert_eq!(operator.get_rules().len(), 1);
+        assert!(!operator.is_failing());
+    }
+
+    #[test]
+    fn it_retries_against_the_last_applied_state() {
+        let operator = Operator::new(TestBackend::default());
+        let state0 = empty_state();
+        let state1 = state0.clone().with([single_port_service(123, 456)]);
+        operator.reconcile(&state1, &state0).unwrap();
+
+        operator.hosts[0].backend().unreachable = true;
+        let state2 = state1.clone().with([
+            single_port_service(123, 456),
+            single_port_service(789, 654),
+        ]);
+        assert!(operator.reconcile(&state2, &state1).is_err());
+        assert!(operator.is_failing());
+
+        // a tick without any changes retries the failed diff
+        operator.hosts[0].backend().unreachable = false;
+        operator.reconcile(&state2, &state2).unwrap();
+        assert_eq!(operator.get_rules().len(), 2);
+        assert!(!operator.is_failing());
+    }
+
+    #[test]
+    fn it_sets_up_failing_hosts_on_retry() {
+        let setup_failed = Error::NoInterfaceAddress("eth0".to_owned());
+        let host = Host::new(LOCALHOST, TestBackend::default());
+        let operator = Operator::with_hosts([host.failing(&setup_failed)]);
+        assert!(operator.is_failing());
+
+        let state = empty_state().with([single_port_service(123, 456)]);
+        operator.reconcile(&state, &state).unwrap();
+        assert_eq!(operator.get_rules().len(), 1);
+        assert!(!operator.is_failing());
+    }
+
+    #[test]
+    fn it_summarizes_changes_per_owner() {
+        let mut summary = Summary::default();
+        summary.add("service: default/foo; node: n0");
+        summary.add("service: default/foo; node: n1");
+        summary.delete("pod: bar-0; namespace: default");
+        assert_eq!(
+            summary.0.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("pod default/bar-0".to_owned(), (0, 1)),
+                ("service default/foo".to_owned(), (2, 0)),
+            ]
+        );
+    }
+
     #[test]
     fn it_replaces_svc_on_port_change() {
         let backend = TestBackend::default();
@@ -390,12 +987,12 @@ mod tests {
             .with([
                 Node {
                     name: "foo".to_string(),
-                    addr: "bar".to_string(),
+                    addrs: vec!["bar".to_string()],
                     is_active: true,
                 },
                 Node {
                     name: "foo_two".to_string(),
-                    addr: "bar_two".to_string(),
+                    addrs: vec!["bar_two".to_string()],
                     is_active: true,
                 },
             ])
@@ -481,10 +1078,87 @@ mod tests {
         );
     }
 
+    #[test]
+    fn it_forwards_dual_stack_services() {
+        let backend = TestBackend::default();
+        let operator = Operator::new(backend);
+
+        let nodes = [
+            Node {
+                name: "foo".to_string(),
+                addrs: vec!["10.0.0.1".to_string(), "fd00::1".to_string()],
+                is_active: true,
+            },
+            Node {
+                name: "bar".to_string(),
+                addrs: vec!["10.0.0.2".to_string()],
+                is_active: true,
+            },
+        ];
+        let state0 = empty_state().with(nodes);
+
+        let svc = single_port_service(123, 456);
+        let state1 = state0.clone().with([svc.clone()]);
+        operator.reconcile(&state1, &state0).unwrap();
+
+        let rules = operator.get_rules();
+        assert_eq!(rules.len(), 2);
+        assert!(rules.iter().all(|r| r.family == IpFamily::V4));
+
+        let state2 = state1
+            .clone()
+            .with([Service { families: IpFamilies::Both, ..svc }]);
+        operator.reconcile(&state2, &state1).unwrap();
+
+        let rules = operator.get_rules();
+        assert_eq!(rules.len(), 3);
+        let v6_rules = rules
+            .iter()
+            .filter(|r| r.family == IpFamily::V6)
+            .collect::<Vec<_>>();
+        assert_eq!(v6_rules.len(), 1);
+        assert_eq!(v6_rules[0].dest_addr, "fd00::1");
+        assert_eq!(v6_rules[0].out_of, 1);
+    }
+
+    #[test]
+    fn it_forwards_mapped_ports_on_their_interfaces() {
+        let backend = TestBackend::default();
+        let operator = Operator::new(backend);
+
+        let state0 = empty_state()
+            .with([Interface::new("eth0"), Interface::new("eth1")]);
+        let mapping = PortMapping {
+            name: "foo-ports".to_string(),
+            namespace: "bar".to_string(),
+            target: MappingTarget::Service("foo".to_string()),
+            families: IpFamilies::V4,
+            ports: vec![MappedPort {
+                spec: single_port_spec(123, 456),
+                allow_range: Some("10.0.0.0/8".to_string()),
+                is_internal: false,
+                is_external: false,
+                interfaces: Some(vec!["eth1".to_string()]),
+            }],
+        };
+        let state1 = state0.clone().with([mapping]);
+        operator.reconcile(&state1, &state0).unwrap();
+
+        let rules = operator.get_rules();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].interface.name, "eth1");
+        assert_eq!(rules[0].port_spec, single_port_spec(123, 456));
+        assert_eq!(rules[0].allow_range.as_deref(), Some("10.0.0.0/8"));
+
+        let state2 = state1.clone().with(Vec::<PortMapping>::new());
+        operator.reconcile(&state2, &state1).unwrap();
+        assert!(operator.get_rules().is_empty());
+    }
+
     fn empty_state() -> State {
         State::default().with(vec![Interface::new("eth0")]).with([Node {
             name: "foo".to_string(),
-            addr: "bar".to_string(),
+            addrs: vec!["bar".to_string()],
             is_active: true,
         }])
     }
@@ -508,6 +1182,9 @@ mod tests {
             external_ports,
             is_internal: false,
             allow_range: None,
+            families: IpFamilies::default(),
+            interfaces: None,
+            load_balancer: None,
         }
     }
 }
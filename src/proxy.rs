@@ -0,0 +1,536 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
+
+use itertools::Itertools;
+use sha256::digest;
+use tokio::{
+    io::copy_bidirectional,
+    net::{TcpListener, TcpStream, UdpSocket},
+    task::JoinHandle,
+    time::timeout,
+};
+
+use crate::{
+    logging::*, res::Proto, Backend, Error, InstalledRule, IpFamily, LocalIps,
+    Result, Rule, PROXY_UDP_TIMEOUT,
+};
+
+/// Forwards traffic in userspace instead of through netfilter: every
+/// (address, port, protocol) combination gets a tokio listener that
+/// round-robins connections across the destinations of its rules.
+pub struct ProxyBackend {
+    interface_ips: HashMap<String, LocalIps>,
+    rules: BTreeMap<String, Rule>,
+    listeners: HashMap<ListenKey, Listener>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct ListenKey {
+    addr: SocketAddr,
+    proto: Proto,
+}
+
+struct Listener {
+    upstream: Arc<Upstream>,
+    task: JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct Upstream {
+    targets: RwLock<Vec<SocketAddr>>,
+    allow_range: RwLock<Option<String>>,
+    next: AtomicUsize,
+}
+
+impl Backend for ProxyBackend {
+    /// The rules whose traffic is forwarded, leaving out the ones whose
+    /// listener could not be bound so that the next resync tries again.
+    fn read_state(&mut self) -> Result<Vec<InstalledRule>> {
+        let config_hash = self.config_hash();
+        Ok(self
+            .rules
+            .values()
+            .filter(|rule| self.is_listening(rule))
+            .map(|rule| InstalledRule::of(rule, "proxy", &config_hash))
+            .collect())
+    }
+
+    fn apply_rules(
+        &mut self,
+        rules: impl IntoIterator<Item = Rule>,
+    ) -> Result<()> {
+        let config_hash = self.config_hash();
+        for rule in rules {
+            self.rules.insert(rule.rule_id(&config_hash), rule);
+        }
+        self.sync()
+    }
+
+    fn delete_rules(
+        &mut self,
+        rule_ids: impl IntoIterator<Item = String>,
+    ) -> Result<()> {
+        for rule_id in rule_ids {
+            self.rules.remove(&rule_id);
+        }
+        self.sync()
+    }
+
+    fn config_hash(&self) -> String {
+        let mut config_hash = digest(format!(
+            "{:?}",
+            self.interface_ips.iter().sorted_by_key(|(name, _)| *name)
+        ));
+        config_hash.truncate(16);
+        config_hash
+    }
+}
+
+impl ProxyBackend {
+    /// `interface_ips` holds the addresses to listen on for every
+    /// interface we forward packets from.
+    pub fn new(interface_ips: HashMap<String, LocalIps>) -> Self {
+        Self {
+            interface_ips,
+            rules: BTreeMap::new(),
+            listeners: HashMap::new(),
+        }
+    }
+
+    fn listen_addr(&self, rule: &Rule) -> Option<SocketAddr> {
+        // connections from the host itself reach the listeners bound on
+        // the interface addresses, so "lo" needs no listener of its own
+        if rule.interface.name == "lo" {
+            return None;
+        }
+        let ip = self
+            .interface_ips
+            .get(&rule.interface.name)?
+            .get(rule.family)?
+            .parse::<IpAddr>()
+            .ok()?;
+        Some(SocketAddr::new(ip, rule.port_spec.host_port))
+    }
+
+    /// Whether `rule` has its listener, provided that it needs one.
+    fn is_listening(&self, rule: &Rule) -> bool {
+        self.listen_addr(rule).is_none_or(|addr| {
+            let proto = rule.port_spec.proto;
+            self.listeners.contains_key(&ListenKey { addr, proto })
+        })
+    }
+
+    /// Brings the listeners in line with the installed rules. A listener
+    /// that cannot be bound fails the sync, but only once the others are
+    /// in place.
+    fn sync(&mut self) -> Result<()> {
+        let mut groups = HashMap::<ListenKey, Vec<&Rule>>::new();
+        for rule in self.rules.values() {
+            match self.listen_addr(rule) {
+                Some(addr) => groups
+                    .entry(ListenKey { addr, proto: rule.port_spec.proto })
+                    .or_default()
+                    .push(rule),
+                None => debug!("no listen address for rule {rule:?}"),
+            }
+        }
+
+        self.listeners.retain(|key, _| groups.contains_key(key));
+
+        let mut failure = None;
+        for (key, rules) in groups {
+            let targets = rules
+                .iter()
+                .sorted_by_key(|r| r.nth)
+                .filter_map(|r| match r.dest_addr.parse::<IpAddr>() {
+                    Ok(ip) => Some(SocketAddr::new(ip, r.port_spec.dest_port)),
+                    Err(_) => {
+                        warn!("skipping invalid destination {}", r.dest_addr);
+                        None
+                    }
+                })
+                .collect::<Vec<_>>();
+            let allow_range = rules[0].allow_range.to_owned();
+
+            match self.listeners.get(&key) {
+                Some(listener) => {
+                    listener.upstream.update(targets, allow_range)
+                }
+                None => {
+                    let upstream = Arc::new(Upstream::default());
+                    upstream.update(targets, allow_range);
+                    match Listener::bind(key, upstream) {
+                        Ok(listener) => {
+                            info!("listening on {:?} {}", key.proto, key.addr);
+                            self.listeners.insert(key, listener);
+                        }
+                        Err(e) => {
+                            warn!("could not listen on {}: {e}", key.addr);
+                            failure.get_or_insert(e);
+                        }
+                    }
+                }
+            }
+        }
+        match failure {
+            Some(e) => Err(Error::BackendError(Box::new(e))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Listener {
+    fn bind(key: ListenKey, upstream: Arc<Upstream>) -> Result<Self> {
+        let task = match key.proto {
+            Proto::Tcp => {
+                let listener = std::net::TcpListener::bind(key.addr)
+                    .and_then(|l| l.set_nonblocking(true).map(|_| l))
+                    .and_then(TcpListener::from_std)
+                    .map_err(Error::ProxyError)?;
+                tokio::spawn(serve_tcp(listener, upstream.clone()))
+            }
+            Proto::Udp => {
+                let socket = std::net::UdpSocket::bind(key.addr)
+                    .and_then(|s| s.set_nonblocking(true).map(|_| s))
+                    .and_then(UdpSocket::from_std)
+                    .map_err(Error::ProxyError)?;
+                tokio::spawn(serve_udp(socket, upstream.clone()))
+            }
+        };
+        Ok(Self { upstream, task })
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) { self.task.abort() }
+}
+
+impl Upstream {
+    fn update(&self, targets: Vec<SocketAddr>, allow_range: Option<String>) {
+        *self.targets.write().expect("poisoned targets lock") = targets;
+        *self.allow_range.write().expect("poisoned allow range lock") =
+            allow_range;
+    }
+
+    fn pick(&self) -> Option<SocketAddr> {
+        let targets = self.targets.read().expect("poisoned targets lock");
+        if targets.is_empty() {
+            return None;
+        }
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        Some(targets[next % targets.len()])
+    }
+
+    fn allows(&self, peer: IpAddr) -> bool {
+        match &*self.allow_range.read().expect("poisoned allow range lock") {
+            None => true,
+            Some(ranges) => ranges.split(',').any(|r| in_range(peer, r)),
+        }
+    }
+}
+
+async fn serve_tcp(listener: TcpListener, upstream: Arc<Upstream>) {
+    loop {
+        let (mut inbound, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("could not accept connection: {e}");
+                continue;
+            }
+        };
+        if !upstream.allows(peer.ip()) {
+            debug!("rejecting connection from {peer}");
+            continue;
+        }
+        let Some(target) = upstream.pick() else {
+            continue;
+        };
+        tokio::spawn(async move {
+            match TcpStream::connect(target).await {
+                Ok(mut outbound) => {
+                    let _ =
+                        copy_bidirectional(&mut inbound, &mut outbound).await;
+                }
+                Err(e) => debug!("could not connect to {target}: {e}"),
+            }
+        });
+    }
+}
+
+async fn serve_udp(socket: UdpSocket, upstream: Arc<Upstream>) {
+    let socket = Arc::new(socket);
+    let sessions = Arc::new(Mutex::new(HashMap::new()));
+    let mut buf = vec![0_u8; u16::MAX as usize];
+
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(datagram) => datagram,
+            Err(e) => {
+                warn!("could not receive datagram: {e}");
+                continue;
+            }
+        };
+        if !upstream.allows(peer.ip()) {
+            debug!("dropping datagram from {peer}");
+            continue;
+        }
+
+        let session = sessions
+            .lock()
+            .expect("poisoned udp sessions lock")
+            .get(&peer)
+            .cloned();
+        let session = match session {
+            Some(session) => session,
+            None => {
+                let Some(target) = upstream.pick() else {
+                    continue;
+                };
+                match udp_session(socket.clone(), peer, target, &sessions)
+                    .await
+                {
+                    Ok(session) => session,
+                    Err(e) => {
+                        debug!("could not open session to {target}: {e}");
+                        continue;
+                    }
+                }
+            }
+        };
+        if let Err(e) = session.send(&buf[..len]).await {
+            debug!("could not forward datagram from {peer}: {e}");
+        }
+    }
+}
+
+type UdpSessions = Arc<Mutex<HashMap<SocketAddr, Arc<UdpSocket>>>>;
+
+/// Opens an upstream socket for `peer` and relays the replies back until
+/// the session has been idle for `PROXY_UDP_TIMEOUT`.
+async fn udp_session(
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    target: SocketAddr,
+    sessions: &UdpSessions,
+) -> std::io::Result<Arc<UdpSocket>> {
+    let bind_addr = match IpFamily::of(target.ip().to_string()) {
+        IpFamily::V4 => "0.0.0.0:0",
+        IpFamily::V6 => "[::]:0",
+    };
+    let outbound = UdpSocket::bind(bind_addr).await?;
+    outbound.connect(target).await?;
+    let outbound = Arc::new(outbound);
+
+    sessions
+        .lock()
+        .expect("poisoned udp sessions lock")
+        .insert(peer, outbound.clone());
+
+    let (relay, sessions) = (outbound.clone(), sessions.clone());
+    tokio::spawn(async move {
+        let mut buf = vec![0_u8; u16::MAX as usize];
+        while let Ok(Ok(len)) =
+            timeout(PROXY_UDP_TIMEOUT, relay.recv(&mut buf)).await
+        {
+            if socket.send_to(&buf[..len], peer).await.is_err() {
+                break;
+            }
+        }
+        sessions.lock().expect("poisoned udp sessions lock").remove(&peer);
+    });
+    Ok(outbound)
+}
+
+/// Checks whether `ip` falls within `range`, given as an address or CIDR.
+fn in_range(ip: IpAddr, range: &str) -> bool {
+    let (net, len) = match range.trim().split_once('/') {
+        Some((net, len)) => (net, len.parse::<u32>().ok()),
+        None => (range.trim(), None),
+    };
+    match (ip, net.parse::<IpAddr>()) {
+        (IpAddr::V4(ip), Ok(IpAddr::V4(net))) => {
+            let len = len.unwrap_or(32).min(32);
+            let mask = u32::MAX.checked_shl(32 - len).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(net) & mask
+        }
+        (IpAddr::V6(ip), Ok(IpAddr::V6(net))) => {
+            let len = len.unwrap_or(128).min(128);
+            let mask = u128::MAX.checked_shl(128 - len).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(net) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::{Interface, PortSpec};
+
+    fn backend() -> ProxyBackend {
+        ProxyBackend::new(HashMap::from([(
+            "test0".to_owned(),
+            LocalIps { v4: Some("127.0.0.1".to_owned()), v6: None },
+        )]))
+    }
+
+    fn rule(
+        host_port: u16,
+        dest: SocketAddr,
+        proto: Proto,
+        nth: usize,
+    ) -> Rule {
+        Rule {
+            dest_addr: dest.ip().to_string(),
+            family: IpFamily::V4,
+            allow_range: None,
+            port_spec: PortSpec { host_port, dest_port: dest.port(), proto },
+            interface: Interface::new("test0"),
+            nth,
+            out_of: 2,
+            comment: None,
+            rule_hash: format!("service::{host_port}::{nth}"),
+        }
+    }
+
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|l| l.local_addr())
+            .map(|addr| addr.port())
+            .unwrap()
+    }
+
+    /// Answers every TCP connection with `reply`.
+    async fn tcp_server(reply: &'static str) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut conn, _)) = listener.accept().await {
+                let _ = conn.write_all(reply.as_bytes()).await;
+            }
+        });
+        addr
+    }
+
+    async fn tcp_request(port: u16) -> std::io::Result<String> {
+        let mut conn = TcpStream::connect(("127.0.0.1", port)).await?;
+        let mut reply = String::new();
+        conn.read_to_string(&mut reply).await?;
+        Ok(reply)
+    }
+
+    #[tokio::test]
+    async fn it_round_robins_tcp() {
+        let first = tcp_server("first").await;
+        let second = tcp_server("second").await;
+        let port = free_port();
+
+        let mut backend = backend();
+        backend
+            .apply_rules([
+                rule(port, first, Proto::Tcp, 0),
+                rule(port, second, Proto::Tcp, 1),
+            ])
+            .unwrap();
+
+        let mut replies = vec![
+            tcp_request(port).await.unwrap(),
+            tcp_request(port).await.unwrap(),
+        ];
+        replies.sort();
+        assert_eq!(replies, vec!["first", "second"]);
+    }
+
+    #[tokio::test]
+    async fn it_stops_listening_on_delete() {
+        let target = tcp_server("hello").await;
+        let port = free_port();
+
+        let mut backend = backend();
+        backend.apply_rules([rule(port, target, Proto::Tcp, 0)]).unwrap();
+        assert_eq!(tcp_request(port).await.unwrap(), "hello");
+
+        let installed = backend.read_state().unwrap();
+        backend
+            .delete_rules(installed.into_iter().map(|r| r.rule_id))
+            .unwrap();
+        tokio::task::yield_now().await;
+        assert!(tcp_request(port).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn it_leaves_rules_it_cannot_listen_for_to_the_next_resync() {
+        let target = tcp_server("hello").await;
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let taken_port = taken.local_addr().unwrap().port();
+        let port = free_port();
+
+        let mut backend = backend();
+        let err = backend
+            .apply_rules([
+                rule(taken_port, target, Proto::Tcp, 0),
+                rule(port, target, Proto::Tcp, 0),
+            ])
+            .unwrap_err();
+        assert!(matches!(err.root(), Error::ProxyError(_)), "{err}");
+        assert_eq!(tcp_request(port).await.unwrap(), "hello");
+        let installed = backend.read_state().unwrap();
+        assert_eq!(installed.len(), 1);
+        assert_eq!(installed[0].host_port, Some(port));
+
+        drop(taken);
+        backend
+            .apply_rules([rule(taken_port, target, Proto::Tcp, 0)])
+            .unwrap();
+        assert_eq!(backend.read_state().unwrap().len(), 2);
+        assert_eq!(tcp_request(taken_port).await.unwrap(), "hello");
+    }
+
+    #[tokio::test]
+    async fn it_forwards_udp() {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0_u8; 64];
+            while let Ok((len, peer)) = echo.recv_from(&mut buf).await {
+                let _ = echo.send_to(&buf[..len], peer).await;
+            }
+        });
+        let port = free_port();
+
+        let mut backend = backend();
+        backend.apply_rules([rule(port, target, Proto::Udp, 0)]).unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(("127.0.0.1", port)).await.unwrap();
+        client.send(b"ping").await.unwrap();
+        let mut buf = [0_u8; 64];
+        let len = timeout(Duration::from_secs(5), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..len], b"ping");
+    }
+
+    #[test]
+    fn it_matches_ranges() {
+        let ip = "10.1.2.3".parse().unwrap();
+        assert!(in_range(ip, "10.0.0.0/8"));
+        assert!(in_range(ip, "10.1.2.3"));
+        assert!(!in_range(ip, "192.168.0.0/16"));
+        assert!(!in_range(ip, "fd00::/8"));
+        assert!(in_range("fd00::1".parse().unwrap(), "fd00::/8"));
+        assert!(in_range(ip, "0.0.0.0/0"));
+    }
+}
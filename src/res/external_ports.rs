@@ -5,17 +5,31 @@ use std::{
 };
 
 use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
 
 use crate::ANNOTATION;
 use super::Error;
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+)]
 pub enum Proto {
     Tcp,
     Udp,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(
+    Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
 pub struct PortSpec {
     pub host_port: u16,
     pub dest_port: u16,
@@ -0,0 +1,121 @@
+use std::{collections::BTreeMap, str::FromStr};
+
+use anyhow::anyhow;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::Error;
+use crate::FAMILIES_ANNOTATION;
+
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+)]
+pub enum IpFamily {
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    pub fn of<A: AsRef<str>>(addr: A) -> Self {
+        if addr.as_ref().contains(':') {
+            Self::V6
+        } else {
+            Self::V4
+        }
+    }
+}
+
+/// Address families a service or pod should be forwarded on.
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+    JsonSchema,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamilies {
+    #[default]
+    V4,
+    V6,
+    Both,
+}
+
+impl IpFamilies {
+    pub fn iter(self) -> impl Iterator<Item = IpFamily> {
+        [IpFamily::V4, IpFamily::V6]
+            .into_iter()
+            .filter(move |family| self.contains(*family))
+    }
+
+    pub fn contains(self, family: IpFamily) -> bool {
+        matches!(
+            (self, family),
+            (Self::Both, _)
+                | (Self::V4, IpFamily::V4)
+                | (Self::V6, IpFamily::V6)
+        )
+    }
+}
+
+impl TryFrom<&BTreeMap<String, String>> for IpFamilies {
+    type Error = Error;
+
+    fn try_from(anno: &BTreeMap<String, String>) -> Result<Self, Self::Error> {
+        match anno.get(FAMILIES_ANNOTATION) {
+            Some(annotation) => {
+                annotation.parse().map_err(|e| Error::AnnotationParseError {
+                    inner: e,
+                    annotation: annotation.to_owned(),
+                })
+            }
+            None => Ok(Self::default()),
+        }
+    }
+}
+
+impl FromStr for IpFamilies {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "v4" => Ok(Self::V4),
+            "v6" => Ok(Self::V6),
+            "both" => Ok(Self::Both),
+            other => Err(anyhow!("unknown ip family: {other}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_families() {
+        assert_eq!("v4".parse::<IpFamilies>().unwrap(), IpFamilies::V4);
+        assert_eq!("both".parse::<IpFamilies>().unwrap(), IpFamilies::Both);
+        assert!("dual".parse::<IpFamilies>().is_err());
+        assert_eq!(
+            IpFamilies::Both.iter().collect::<Vec<_>>(),
+            vec![IpFamily::V4, IpFamily::V6]
+        );
+        assert_eq!(IpFamily::of("fd00::1"), IpFamily::V6);
+        assert_eq!(IpFamily::of("10.0.0.1"), IpFamily::V4);
+    }
+}
@@ -1,6 +1,10 @@
+use serde::{Deserialize, Serialize};
+
 use crate::ResourceLike;
 
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(
+    Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
 pub struct Interface {
     pub name: String,
     pub is_external: bool,
@@ -1,7 +1,9 @@
 mod external_ports;
+mod family;
 mod interface;
 mod node;
 mod pod;
+mod port_mapping;
 mod service;
 
 use std::any::TypeId;
@@ -10,10 +12,12 @@ use enum_dispatch::enum_dispatch;
 use kube::ResourceExt;
 use thiserror::Error;
 pub use external_ports::*;
+pub use family::*;
 pub use interface::*;
 pub use node::*;
 pub use service::*;
 pub use pod::*;
+pub use port_mapping::*;
 
 use crate::{
     CoreNode, CorePod, CoreService, ALLOW_RANGE_ANNOTATION,
@@ -28,6 +32,7 @@ pub enum Resource {
     Node,
     Service,
     Pod,
+    PortMapping,
 }
 
 #[enum_dispatch(Resource)]
@@ -65,6 +70,14 @@ pub enum Error {
         inner: anyhow::Error,
         annotation: String,
     },
+    #[error(
+        "invalid port mapping (error: {inner}, mapping_id: {mapping_id})"
+    )]
+    MappingParseError {
+        #[source]
+        inner: anyhow::Error,
+        mapping_id: String,
+    },
     #[error("skipping pod (reason: {inner}, pod_id: {pod_id})")]
     SkipPod {
         #[source]
@@ -73,6 +86,19 @@ pub enum Error {
     },
 }
 
+impl Error {
+    /// Whether the owner of the object got something wrong and should hear
+    /// about it, unlike a pod that simply has no address yet.
+    pub fn is_invalid(&self) -> bool {
+        matches!(
+            self,
+            Error::ServiceParseError { .. }
+                | Error::AnnotationParseError { .. }
+                | Error::MappingParseError { .. }
+        )
+    }
+}
+
 impl TryFrom<CoreService> for Resource {
     type Error = Error;
 
@@ -85,7 +111,18 @@ impl TryFrom<CoreService> for Resource {
             allow_range: cs
                 .annotations()
                 .get(ALLOW_RANGE_ANNOTATION)
-                .map(String::to_owned),
+                .map(|ranges| {
+                    parse_allow_range(ranges).map_err(|e| {
+                        Error::AnnotationParseError {
+                            inner: e,
+                            annotation: ranges.to_owned(),
+                        }
+                    })
+                })
+                .transpose()?,
+            families: cs.annotations().try_into()?,
+            interfaces: None,
+            load_balancer: load_balancer(&cs),
         }
         .into())
     }
@@ -96,14 +133,14 @@ impl TryFrom<CoreNode> for Resource {
 
     fn try_from(cn: CoreNode) -> Result<Self, Self::Error> {
         let status = cn.status.clone().unwrap_or_default();
-        let addr = node_ip(status.clone()).map_err(|e| {
+        let addrs = node_ips(status.clone()).map_err(|e| {
             Error::NodeParseError { inner: e, node_id: cn.name_any() }
         })?;
         let is_active = node_ready(status)
             && !cn.annotations().contains_key(NODE_EXCLUDE_ANNOTATION)
             && !cn.labels().contains_key(NODE_EXCLUDE_LABEL);
 
-        Ok(Node { name: cn.name_any(), addr, is_active }.into())
+        Ok(Node { name: cn.name_any(), addrs, is_active }.into())
     }
 }
 
@@ -112,7 +149,7 @@ impl TryFrom<CorePod> for Resource {
 
     fn try_from(cp: CorePod) -> Result<Self, Self::Error> {
         let status = cp.status.clone().unwrap_or_default();
-        let addr = pod_ip(status.clone())
+        let addrs = pod_ips(status.clone())
             .map_err(|e| Error::SkipPod { inner: e, pod_id: cp.name_any() })?;
         let is_active = pod_ready(status);
 
@@ -122,9 +159,25 @@ impl TryFrom<CorePod> for Resource {
             external_ports: cp.annotations().try_into()?,
             is_internal: cp.annotations().contains_key(INTERNAL_ANNOTATION),
             is_external: cp.annotations().contains_key(EXTERNAL_ANNOTATION),
-            addr,
+            families: cp.annotations().try_into()?,
+            addrs,
             is_ready: is_active,
+            labels: cp.labels().to_owned(),
+            allow_range: None,
+            interfaces: None,
         }
         .into())
     }
 }
+
+impl TryFrom<ExternalPortMapping> for Resource {
+    type Error = Error;
+
+    fn try_from(epm: ExternalPortMapping) -> Result<Self, Self::Error> {
+        let namespace = epm.namespace().unwrap_or_default();
+        let mapping_id = format!("{namespace}/{}", epm.name_any());
+        PortMapping::try_from(epm)
+            .map(Resource::from)
+            .map_err(|e| Error::MappingParseError { inner: e, mapping_id })
+    }
+}
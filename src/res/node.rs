@@ -1,27 +1,40 @@
 use anyhow::{anyhow, Context};
 use k8s_openapi::api::core::v1::NodeStatus;
 
+use super::IpFamily;
 use crate::ResourceLike;
 
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Node {
     pub name: String,
-    pub addr: String,
+    pub addrs: Vec<String>,
     pub is_active: bool,
 }
 
+impl Node {
+    pub fn addr(&self, family: IpFamily) -> Option<&String> {
+        self.addrs.iter().find(|addr| IpFamily::of(addr) == family)
+    }
+}
+
 impl ResourceLike for Node {
     fn id(&self) -> String { self.name.to_owned() }
     fn is_active(&self) -> bool { self.is_active }
 }
 
-pub fn node_ip(status: NodeStatus) -> anyhow::Result<String> {
-    for add in status.addresses.context("node missing addresses")? {
-        if add.type_ == "InternalIP" {
-            return Ok(add.address);
-        }
+/// Internal IPs of a node - one per address family on dual-stack clusters.
+pub fn node_ips(status: NodeStatus) -> anyhow::Result<Vec<String>> {
+    let addrs = status
+        .addresses
+        .context("node missing addresses")?
+        .into_iter()
+        .filter(|add| add.type_ == "InternalIP")
+        .map(|add| add.address)
+        .collect::<Vec<_>>();
+    if addrs.is_empty() {
+        return Err(anyhow!("failed to extract node ip"));
     }
-    Err(anyhow!("failed to extract node ip"))
+    Ok(addrs)
 }
 
 pub fn node_ready(status: NodeStatus) -> bool {
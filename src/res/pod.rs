@@ -1,28 +1,48 @@
+use std::collections::BTreeMap;
+
 use anyhow::anyhow;
 use itertools::Itertools;
 use k8s_openapi::api::core::v1::PodStatus;
 use sha256::digest;
 
+use super::{IpFamilies, IpFamily};
 use crate::{ExternalPorts, ResourceLike};
 
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Pod {
     pub name: String,
     pub namespace: String,
-    pub addr: String,
+    pub addrs: Vec<String>,
     pub external_ports: ExternalPorts,
     pub is_internal: bool,
     pub is_external: bool,
+    pub families: IpFamilies,
     pub is_ready: bool,
+    pub labels: BTreeMap<String, String>,
+    pub allow_range: Option<String>,
+    /// Host interfaces to forward from, when not all of them
+    pub interfaces: Option<Vec<String>>,
 }
 
+/// Ready pods are kept whether they are annotated or not, as a port
+/// mapping may select them; see `watch_pods` for when none can.
 impl ResourceLike for Pod {
-    fn id(&self) -> String { self.name.to_owned() }
-    fn is_active(&self) -> bool { self.is_ready && self.has_external_ports() }
+    fn id(&self) -> String { self.fqn() }
+    fn is_active(&self) -> bool { self.is_ready }
 }
 
-pub fn pod_ip(status: PodStatus) -> anyhow::Result<String> {
-    status.pod_ip.ok_or(anyhow!("missing pod ip"))
+/// Pod IPs, preferring `podIPs` (one per family) over the legacy `podIP`.
+pub fn pod_ips(status: PodStatus) -> anyhow::Result<Vec<String>> {
+    let addrs = status
+        .pod_ips
+        .unwrap_or_default()
+        .into_iter()
+        .map(|pod_ip| pod_ip.ip)
+        .collect::<Vec<_>>();
+    if !addrs.is_empty() {
+        return Ok(addrs);
+    }
+    status.pod_ip.map(|ip| vec![ip]).ok_or(anyhow!("missing pod ip"))
 }
 
 pub fn pod_ready(status: PodStatus) -> bool {
@@ -36,15 +56,21 @@ pub fn pod_ready(status: PodStatus) -> bool {
 impl Pod {
     pub fn fqn(&self) -> String { format!("{}/{}", self.namespace, self.name) }
 
+    pub fn addr(&self, family: IpFamily) -> Option<&String> {
+        self.addrs.iter().find(|addr| IpFamily::of(addr) == family)
+    }
+
     pub fn has_external_ports(&self) -> bool {
         !self.external_ports.specs.is_empty()
     }
 
     pub fn pod_hash(&self) -> String {
         let mut pod_hash = digest(format!(
-            "{}::{}",
+            "{}::{}{}{}",
             self.fqn(),
-            self.external_ports.specs.iter().join("::")
+            self.external_ports.specs.iter().join("::"),
+            self.allow_range.iter().format(""),
+            self.interfaces.iter().flatten().join(","),
         ));
         pod_hash.truncate(32);
         pod_hash
@@ -0,0 +1,401 @@
+use std::{borrow::Cow, collections::BTreeMap};
+
+use anyhow::bail;
+use k8s_openapi::{
+    apiextensions_apiserver::pkg::apis::apiextensions::v1::{
+        CustomResourceDefinition, CustomResourceDefinitionNames,
+        CustomResourceDefinitionSpec, CustomResourceDefinitionVersion,
+        CustomResourceValidation,
+    },
+    apimachinery::pkg::apis::meta::v1::ObjectMeta,
+    NamespaceResourceScope,
+};
+use schemars::{gen::SchemaSettings, JsonSchema};
+use serde::{Deserialize, Serialize};
+
+use super::{parse_allow_range, IpFamilies, Pod, PortSpec, Proto, Service};
+use crate::{ExternalPorts, ResourceLike};
+
+pub const PORT_MAPPING_GROUP: &str = "epok.getbetter.ro";
+pub const PORT_MAPPING_VERSION: &str = "v1alpha1";
+pub const PORT_MAPPING_KIND: &str = "ExternalPortMapping";
+pub const PORT_MAPPING_PLURAL: &str = "externalportmappings";
+
+/// The `ExternalPortMapping` custom resource: typed port mappings to a
+/// service or to the pods matching a selector, in its own namespace.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ExternalPortMapping {
+    pub metadata: ObjectMeta,
+    pub spec: ExternalPortMappingSpec,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ExternalPortMappingSpec {
+    /// The service to forward to, through its node ports on every node
+    pub service_name: Option<String>,
+    /// Labels of the pods to forward to, through their own addresses
+    pub pod_selector: Option<BTreeMap<String, String>>,
+    /// Address families to forward on
+    #[serde(default)]
+    pub ip_families: IpFamilies,
+    pub ports: Vec<MappedPortSpec>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MappedPortSpec {
+    /// The port to listen on, on the host
+    pub host_port: u16,
+    /// The node port of the service, or the port of the pods
+    pub dest_port: u16,
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// Addresses or CIDR ranges allowed to connect, any when empty
+    #[serde(default)]
+    pub allow_ranges: Vec<String>,
+    /// Don't forward from the external interface
+    #[serde(default)]
+    pub internal: bool,
+    /// Only forward from the external interface (pods)
+    #[serde(default)]
+    pub external: bool,
+    /// Host interfaces to forward from, all of them when empty
+    #[serde(default)]
+    pub interfaces: Vec<String>,
+}
+
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize, JsonSchema,
+)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Protocol {
+    #[default]
+    TCP,
+    UDP,
+}
+
+impl kube::Resource for ExternalPortMapping {
+    type DynamicType = ();
+    type Scope = NamespaceResourceScope;
+
+    fn kind(_: &()) -> Cow<'_, str> { PORT_MAPPING_KIND.into() }
+    fn group(_: &()) -> Cow<'_, str> { PORT_MAPPING_GROUP.into() }
+    fn version(_: &()) -> Cow<'_, str> { PORT_MAPPING_VERSION.into() }
+    fn plural(_: &()) -> Cow<'_, str> { PORT_MAPPING_PLURAL.into() }
+    fn meta(&self) -> &ObjectMeta { &self.metadata }
+    fn meta_mut(&mut self) -> &mut ObjectMeta { &mut self.metadata }
+}
+
+impl ExternalPortMapping {
+    /// The definition to install the custom resource with.
+    pub fn crd() -> CustomResourceDefinition {
+        let spec = SchemaSettings::openapi3()
+            .with(|settings| {
+                settings.inline_subschemas = true;
+                settings.meta_schema = None;
+            })
+            .into_generator()
+            .into_root_schema_for::<ExternalPortMappingSpec>()
+            .schema;
+        let mut spec = serde_yaml::to_value(spec)
+            .expect("the schema should be serializable");
+        if let Some(spec) = spec.as_mapping_mut() {
+            spec.remove("title");
+        }
+        let schema = serde_yaml::from_value(serde_yaml::Value::Mapping(
+            [
+                ("type".into(), "object".into()),
+                ("required".into(), vec!["spec"].into()),
+                (
+                    "properties".into(),
+                    serde_yaml::Value::Mapping(
+                        [("spec".into(), spec)].into_iter().collect(),
+                    ),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        ))
+        .expect("the schema should be valid");
+
+        CustomResourceDefinition {
+            metadata: ObjectMeta {
+                name: Some(format!(
+                    "{PORT_MAPPING_PLURAL}.{PORT_MAPPING_GROUP}"
+                )),
+                ..Default::default()
+            },
+            spec: CustomResourceDefinitionSpec {
+                group: PORT_MAPPING_GROUP.to_owned(),
+                names: CustomResourceDefinitionNames {
+                    kind: PORT_MAPPING_KIND.to_owned(),
+                    plural: PORT_MAPPING_PLURAL.to_owned(),
+                    singular: Some(PORT_MAPPING_KIND.to_lowercase()),
+                    short_names: Some(vec!["epm".to_owned()]),
+                    ..Default::default()
+                },
+                scope: "Namespaced".to_owned(),
+                versions: vec![CustomResourceDefinitionVersion {
+                    name: PORT_MAPPING_VERSION.to_owned(),
+                    served: true,
+                    storage: true,
+                    schema: Some(CustomResourceValidation {
+                        open_api_v3_schema: Some(schema),
+                    }),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+            status: None,
+        }
+    }
+}
+
+/// What a port mapping forwards to.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum MappingTarget {
+    Service(String),
+    Pods(BTreeMap<String, String>),
+}
+
+/// A validated port of a mapping.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MappedPort {
+    pub spec: PortSpec,
+    pub allow_range: Option<String>,
+    pub is_internal: bool,
+    pub is_external: bool,
+    pub interfaces: Option<Vec<String>>,
+}
+
+/// An `ExternalPortMapping` as it goes into the state.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PortMapping {
+    pub name: String,
+    pub namespace: String,
+    pub target: MappingTarget,
+    pub families: IpFamilies,
+    pub ports: Vec<MappedPort>,
+}
+
+impl ResourceLike for PortMapping {
+    fn id(&self) -> String { format!("{}/{}", self.namespace, self.name) }
+    fn is_active(&self) -> bool { !self.ports.is_empty() }
+}
+
+impl PortMapping {
+    /// The service the mapping forwards to, once per port since each port
+    /// carries its own flags.
+    pub fn services(&self) -> Vec<Service> {
+        let MappingTarget::Service(name) = &self.target else {
+            return Vec::new();
+        };
+        self.ports
+            .iter()
+            .map(|port| Service {
+                name: name.to_owned(),
+                namespace: self.namespace.to_owned(),
+                external_ports: ExternalPorts {
+                    specs: vec![port.spec.clone()],
+                },
+                is_internal: port.is_internal,
+                allow_range: port.allow_range.clone(),
+                families: self.families,
+                interfaces: port.interfaces.clone(),
+                load_balancer: None,
+            })
+            .collect()
+    }
+
+    /// The `pods` the mapping selects, once per port.
+    pub fn pods<'a>(
+        &'a self,
+        pods: impl IntoIterator<Item = &'a Pod> + 'a,
+    ) -> impl Iterator<Item = Pod> + 'a {
+        let selector = match &self.target {
+            MappingTarget::Pods(selector) => Some(selector),
+            MappingTarget::Service(_) => None,
+        };
+        pods.into_iter()
+            .filter(move |pod| {
+                selector.is_some_and(|selector| {
+                    pod.namespace == self.namespace
+                        && selector
+                            .iter()
+                            .all(|(k, v)| pod.labels.get(k) == Some(v))
+                })
+            })
+            .flat_map(move |pod| {
+                self.ports.iter().map(move |port| Pod {
+                    external_ports: ExternalPorts {
+                        specs: vec![port.spec.clone()],
+                    },
+                    is_internal: port.is_internal,
+                    is_external: port.is_external,
+                    allow_range: port.allow_range.clone(),
+                    families: self.families,
+                    interfaces: port.interfaces.clone(),
+                    ..pod.clone()
+                })
+            })
+    }
+}
+
+impl TryFrom<ExternalPortMapping> for PortMapping {
+    type Error = anyhow::Error;
+
+    fn try_from(epm: ExternalPortMapping) -> anyhow::Result<Self> {
+        let spec = epm.spec;
+        let target = match (spec.service_name, spec.pod_selector) {
+            (Some(name), None) => MappingTarget::Service(name),
+            (None, Some(selector)) if !selector.is_empty() => {
+                MappingTarget::Pods(selector)
+            }
+            _ => bail!(
+                "exactly one of serviceName and a non-empty podSelector is \
+                 required"
+            ),
+        };
+        let ports = spec
+            .ports
+            .into_iter()
+            .map(|port| {
+                let allow_range = match port.allow_ranges.is_empty() {
+                    true => None,
+                    false => {
+                        Some(parse_allow_range(&port.allow_ranges.join(","))?)
+                    }
+                };
+                Ok(MappedPort {
+                    spec: PortSpec {
+                        host_port: port.host_port,
+                        dest_port: port.dest_port,
+                        proto: match port.protocol {
+                            Protocol::TCP => Proto::Tcp,
+                            Protocol::UDP => Proto::Udp,
+                        },
+                    },
+                    allow_range,
+                    is_internal: port.internal,
+                    is_external: port.external,
+                    interfaces: (!port.interfaces.is_empty())
+                        .then_some(port.interfaces),
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self {
+            name: epm.metadata.name.unwrap_or_default(),
+            namespace: epm.metadata.namespace.unwrap_or_default(),
+            target,
+            families: spec.ip_families,
+            ports,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(target: MappingTarget) -> PortMapping {
+        PortMapping {
+            name: "web".to_owned(),
+            namespace: "default".to_owned(),
+            target,
+            families: IpFamilies::V4,
+            ports: vec![MappedPort {
+                spec: PortSpec::new_tcp(80, 8080),
+                allow_range: Some("10.0.0.0/8".to_owned()),
+                is_internal: true,
+                is_external: false,
+                interfaces: Some(vec!["eth1".to_owned()]),
+            }],
+        }
+    }
+
+    fn parse(spec: &str) -> anyhow::Result<PortMapping> {
+        PortMapping::try_from(ExternalPortMapping {
+            metadata: ObjectMeta {
+                name: Some("web".to_owned()),
+                namespace: Some("default".to_owned()),
+                ..Default::default()
+            },
+            spec: serde_yaml::from_str(spec).unwrap(),
+        })
+    }
+
+    #[test]
+    fn it_parses_a_mapping() {
+        let mapping = parse(
+            "podSelector: {app: web}\n\
+             ports:\n\
+             - {hostPort: 53, destPort: 5353, protocol: UDP, \
+                allowRanges: [10.0.0.0/8, fd00::/64]}",
+        )
+        .unwrap();
+        assert_eq!(mapping.id(), "default/web");
+        assert_eq!(
+            mapping.target,
+            MappingTarget::Pods([("app".into(), "web".into())].into())
+        );
+        let port = &mapping.ports[0];
+        assert_eq!(port.spec.proto, Proto::Udp);
+        assert_eq!(port.allow_range.as_deref(), Some("10.0.0.0/8,fd00::/64"));
+        assert_eq!(port.interfaces, None);
+
+        assert!(parse("serviceName: web\npodSelector: {app: web}\nports: []")
+            .is_err());
+        assert!(parse(
+            "serviceName: web\n\
+             ports: [{hostPort: 80, destPort: 30080, allowRanges: [nope]}]"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn it_selects_pods_by_label() {
+        let pod = |name: &str, namespace: &str, app: &str| Pod {
+            name: name.to_owned(),
+            namespace: namespace.to_owned(),
+            labels: [("app".to_owned(), app.to_owned())].into(),
+            is_ready: true,
+            ..Default::default()
+        };
+        let pods = [
+            pod("web-0", "default", "web"),
+            pod("db-0", "default", "db"),
+            pod("web-0", "other", "web"),
+        ];
+        let mapping = mapping(MappingTarget::Pods(
+            [("app".into(), "web".into())].into(),
+        ));
+
+        let selected = mapping.pods(&pods).collect::<Vec<_>>();
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].fqn(), "default/web-0");
+        assert_eq!(selected[0].external_ports.specs[0].dest_port, 8080);
+        assert!(selected[0].is_internal);
+        assert!(mapping.services().is_empty());
+    }
+
+    #[test]
+    fn it_maps_ports_of_a_service() {
+        let services =
+            mapping(MappingTarget::Service("web".to_owned())).services();
+        assert_eq!(services.len(), 1);
+        assert_eq!(services[0].fqn(), "default/web");
+        assert_eq!(services[0].allow_range.as_deref(), Some("10.0.0.0/8"));
+        assert_eq!(services[0].interfaces, Some(vec!["eth1".to_owned()]));
+    }
+
+    #[test]
+    fn it_defines_the_custom_resource() {
+        let crd = serde_yaml::to_string(&ExternalPortMapping::crd()).unwrap();
+        assert_eq!(
+            crd,
+            include_str!("../../docs/externalportmapping-crd.yaml"),
+            "docs/externalportmapping-crd.yaml is out of date"
+        );
+    }
+}
@@ -1,8 +1,11 @@
+use std::net::IpAddr;
+
+use anyhow::{anyhow, bail};
 use sha256::digest;
 use itertools::Itertools;
 
-use super::ExternalPorts;
-use crate::ResourceLike;
+use super::{ExternalPorts, IpFamilies, PortSpec, Proto};
+use crate::{CoreService, ResourceLike};
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Service {
@@ -11,11 +14,30 @@ pub struct Service {
     pub external_ports: ExternalPorts,
     pub is_internal: bool,
     pub allow_range: Option<String>,
+    pub families: IpFamilies,
+    /// Host interfaces to forward from, when not all of them
+    pub interfaces: Option<Vec<String>>,
+    /// Set on `LoadBalancer` services, until it turns out that epok does
+    /// not claim them
+    pub load_balancer: Option<LoadBalancer>,
+}
+
+/// What a `LoadBalancer` service asks for.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LoadBalancer {
+    pub class: Option<String>,
+    /// The service ports, forwarded from the same ports on the host to
+    /// their node ports
+    pub ports: ExternalPorts,
 }
 
+/// `LoadBalancer` services are kept whether they are annotated or not, as
+/// epok may claim them.
 impl ResourceLike for Service {
     fn id(&self) -> String { self.fqn() }
-    fn is_active(&self) -> bool { self.has_external_ports() }
+    fn is_active(&self) -> bool {
+        self.has_external_ports() || self.load_balancer.is_some()
+    }
 }
 
 impl Service {
@@ -32,11 +54,87 @@ impl Service {
         fqn_hash.truncate(16);
         let port_hash = &self.external_ports.specs.iter().join("::");
         let mut service_hash = digest(format!(
-            "{fqn_hash}{port_hash}{}{}",
+            "{fqn_hash}{port_hash}{}{}{:?}{}",
             self.is_internal,
-            self.allow_range.to_owned().unwrap_or_else(|| "".into())
+            self.allow_range.to_owned().unwrap_or_else(|| "".into()),
+            self.families,
+            self.interfaces.iter().flatten().join(","),
         ));
         service_hash.truncate(16);
         service_hash
     }
 }
+
+/// The ports of a `LoadBalancer` service that have a node port to forward
+/// to; SCTP ones are left out.
+pub fn load_balancer(cs: &CoreService) -> Option<LoadBalancer> {
+    let spec = cs.spec.as_ref()?;
+    if spec.type_.as_deref() != Some("LoadBalancer") {
+        return None;
+    }
+    let specs = spec
+        .ports
+        .iter()
+        .flatten()
+        .filter_map(|port| {
+            let proto = match port.protocol.as_deref() {
+                None | Some("TCP") => Proto::Tcp,
+                Some("UDP") => Proto::Udp,
+                Some(_) => return None,
+            };
+            Some(PortSpec {
+                host_port: port.port.try_into().ok()?,
+                dest_port: port.node_port?.try_into().ok()?,
+                proto,
+            })
+        })
+        .collect();
+    Some(LoadBalancer {
+        class: spec.load_balancer_class.clone(),
+        ports: ExternalPorts { specs },
+    })
+}
+
+/// Checks that every entry of a comma-separated allow range is an address
+/// or a CIDR range, before it gets anywhere near a command line.
+pub fn parse_allow_range(ranges: &str) -> anyhow::Result<String> {
+    let ranges = ranges
+        .split(',')
+        .map(|range| {
+            let range = range.trim();
+            let (addr, prefix) = match range.split_once('/') {
+                Some((addr, prefix)) => (addr, Some(prefix)),
+                None => (range, None),
+            };
+            let addr = addr
+                .parse::<IpAddr>()
+                .map_err(|_| anyhow!("invalid allow range: {range}"))?;
+            let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+            if let Some(prefix) = prefix {
+                match prefix.parse::<u8>() {
+                    Ok(prefix) if prefix <= max_prefix => {}
+                    _ => bail!("invalid prefix length: {range}"),
+                }
+            }
+            Ok(range)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(ranges.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_validates_allow_ranges() {
+        assert_eq!(
+            parse_allow_range("10.0.0.0/8, fd00::/64,1.2.3.4").unwrap(),
+            "10.0.0.0/8,fd00::/64,1.2.3.4"
+        );
+        assert!(parse_allow_range("10.0.0.0/33").is_err());
+        assert!(parse_allow_range("10.0.0.0/8'; reboot; '").is_err());
+        assert!(parse_allow_range("10.0.0.0/8 -j ACCEPT").is_err());
+        assert!(parse_allow_range("").is_err());
+    }
+}
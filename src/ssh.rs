@@ -0,0 +1,451 @@
+use std::{
+    fs,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    process::{Child, ChildStderr, Command, Stdio},
+    sync::Mutex,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use backon::{BlockingRetryable, ExponentialBuilder};
+use base64::{
+    engine::general_purpose::{STANDARD, STANDARD_NO_PAD},
+    Engine,
+};
+use sha2::{Digest, Sha256};
+use sha256::digest;
+
+use crate::{
+    executor::{output, stdout},
+    logging::*,
+    Cmd, Error, Executor, Result, SshHost, SSH_CONNECTION_FAILED,
+};
+
+/// How long a freshly spawned master gets to bring up its control socket.
+const SSH_CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// A supervised `ssh` master connection: commands run as channels
+/// multiplexed over its control socket instead of opening a connection
+/// each. The master is (re)started on demand, with backoff.
+#[derive(Debug, Default)]
+pub struct SshSession {
+    master: Mutex<Option<Master>>,
+}
+
+#[derive(Debug)]
+struct Master {
+    child: Child,
+    socket: PathBuf,
+}
+
+impl Executor for SshHost {
+    fn run(&self, cmd: &Cmd) -> Result<String> {
+        debug!("running command on {}: {cmd}", self.host);
+        self.run_over_master(cmd, None)
+    }
+
+    fn run_with_stdin(&self, cmd: &Cmd, stdin: &str) -> Result<String> {
+        debug!(
+            "running command on {}: {cmd} ({} bytes of stdin)",
+            self.host,
+            stdin.len()
+        );
+        self.run_over_master(cmd, Some(stdin))
+    }
+}
+
+impl SshHost {
+    /// Builds an `ssh` command that runs `cmd` over the master connection,
+    /// starting the master first if it is not up. The remote shell gets
+    /// `cmd` quoted, so that it sees the exact same arguments.
+    fn command(&self, cmd: &Cmd) -> Result<Command> {
+        let socket = self.connect()?;
+        let mut command = Command::new(&self.ssh_binary);
+        command
+            .arg("-S")
+            .arg(socket)
+            .args(["-o", "ControlMaster=no"])
+            .arg("-p")
+            .arg(self.port.to_string())
+            .arg(&self.host)
+            .arg(cmd.to_shell());
+        Ok(command)
+    }
+
+    /// Runs `cmd` on the host, reconnecting once if the connection dropped.
+    fn run_over_master(
+        &self,
+        cmd: &Cmd,
+        stdin: Option<&str>,
+    ) -> Result<String> {
+        let mut out = output(self.command(cmd)?, stdin, cmd.timeout())?;
+        if out.status.code() == Some(SSH_CONNECTION_FAILED) {
+            warn!("lost the ssh connection to {}, reconnecting", self.host);
+            self.disconnect();
+            out = output(self.command(cmd)?, stdin, cmd.timeout())?;
+        }
+        stdout(&self.host, cmd, out)
+    }
+
+    /// Drops the master connection so the next command reconnects.
+    fn disconnect(&self) {
+        self.session.master.lock().expect("ssh session poisoned").take();
+    }
+
+    fn connect(&self) -> Result<PathBuf> {
+        let mut master =
+            self.session.master.lock().expect("ssh session poisoned");
+        if master.as_mut().is_some_and(Master::is_alive) {
+            return Ok(master.as_ref().unwrap().socket.to_owned());
+        }
+        master.take();
+
+        let backoff = ExponentialBuilder::new()
+            .with_min_delay(Duration::from_millis(500))
+            .with_max_delay(Duration::from_secs(10))
+            .with_max_times(5);
+        let started = (|| self.start_master())
+            .retry(backoff)
+            .when(|e| !matches!(e, Error::HostKeyMismatch(..)))
+            .notify(|e, delay| {
+                warn!("ssh to {} failed: {e}, retry in {delay:?}", self.host)
+            })
+            .call()?;
+        let socket = started.socket.to_owned();
+        *master = Some(started);
+        Ok(socket)
+    }
+
+    fn start_master(&self) -> Result<Master> {
+        // unix socket paths are short, so name it after a hash of the host
+        let mut host_hash = digest(format!("{}:{}", self.host, self.port));
+        host_hash.truncate(16);
+        let socket = std::env::temp_dir()
+            .join(format!("epok-ssh-{}-{host_hash}.sock", std::process::id()));
+        let _ = fs::remove_file(&socket);
+
+        let known_hosts = match (&self.known_hosts, &self.host_key_fingerprint)
+        {
+            (Some(path), _) => Some(path.to_owned()),
+            (None, Some(fingerprint)) => {
+                let path = socket.with_extension("known_hosts");
+                self.pin_host_key(fingerprint, &path)?;
+                Some(path)
+            }
+            (None, None) => {
+                warn!(
+                    "not verifying the host key of {}, see --known-hosts",
+                    self.host
+                );
+                None
+            }
+        };
+
+        info!("opening ssh connection to {}:{}", self.host, self.port);
+        let mut command = Command::new(&self.ssh_binary);
+        command
+            .args(["-M", "-N", "-S"])
+            .arg(&socket)
+            .args(["-o", "ControlPersist=no", "-o", "BatchMode=yes"])
+            .arg("-o")
+            .arg(format!("ServerAliveInterval={}", self.keepalive))
+            .args(["-o", "ServerAliveCountMax=3"])
+            // the debug output tells which host key got verified
+            .args(["-o", "LogLevel=DEBUG1"]);
+        if let Some(path) = &known_hosts {
+            command
+                .arg("-o")
+                .arg(format!("UserKnownHostsFile={}", path.display()))
+                .args(["-o", "StrictHostKeyChecking=yes"]);
+        }
+        let mut child = command
+            .arg("-p")
+            .arg(self.port.to_string())
+            .arg("-i")
+            .arg(&self.key_path)
+            .arg(&self.host)
+            .stdin(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(Error::ExecutorError)?;
+        let mut log = Some(watch_log(&self.host, child.stderr.take()));
+
+        let mut master = Master { child, socket };
+        let deadline = Instant::now() + SSH_CONNECT_TIMEOUT;
+        while !master.socket.exists() {
+            let exited = !matches!(master.child.try_wait(), Ok(None));
+            let rejected = exited
+                && log.take().is_some_and(|log| log.join().unwrap_or(false));
+            if rejected {
+                let expected = match (&known_hosts, &self.host_key_fingerprint)
+                {
+                    (_, Some(fingerprint)) => fingerprint.to_owned(),
+                    (Some(path), None) => path.display().to_string(),
+                    (None, None) => "the known hosts".to_owned(),
+                };
+                return Err(Error::HostKeyMismatch(
+                    self.host.to_owned(),
+                    expected,
+                ));
+            }
+            if exited || Instant::now() > deadline {
+                return Err(Error::ExecutorError(std::io::Error::other(
+                    format!("ssh master connection to {} failed", self.host),
+                )));
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        Ok(master)
+    }
+
+    /// Scans the host keys and writes the one with the pinned fingerprint
+    /// to `path`, so that ssh accepts that key only.
+    fn pin_host_key(&self, fingerprint: &str, path: &Path) -> Result<()> {
+        let hostname = self.host.rsplit('@').next().unwrap_or(&self.host);
+        let mut keyscan = Command::new(format!("{}-keyscan", self.ssh_binary));
+        keyscan.arg("-p").arg(self.port.to_string()).arg(hostname);
+        let keyscan = output(keyscan, None, None)?;
+        let scanned = stdout(&self.host, "ssh-keyscan", keyscan)?;
+        if !scanned.lines().any(|line| host_key(line).is_some()) {
+            return Err(Error::ExecutorError(std::io::Error::other(format!(
+                "could not scan the host keys of {hostname}"
+            ))));
+        }
+
+        let pinned = pinned_keys(&scanned, fingerprint);
+        if pinned.is_empty() {
+            return Err(Error::HostKeyMismatch(
+                self.host.to_owned(),
+                fingerprint.to_owned(),
+            ));
+        }
+        fs::write(path, pinned).map_err(Error::ExecutorError)
+    }
+}
+
+/// Forwards the log of the master to ours, announcing the host key once
+/// ssh verified it. Yields whether ssh rejected the host key, when the
+/// master exits.
+fn watch_log(host: &str, stderr: Option<ChildStderr>) -> JoinHandle<bool> {
+    let host = host.to_owned();
+    thread::spawn(move || {
+        let Some(stderr) = stderr else { return false };
+        let (mut offered, mut rejected) = (None, false);
+        for line in BufReader::new(stderr).lines().map_while(|l| l.ok()) {
+            if let Some(key) = line.strip_prefix("debug1: Server host key: ") {
+                offered = Some(key.to_owned());
+            } else if line.contains("is known and matches") {
+                let key = offered.as_deref().unwrap_or("unknown key");
+                info!("verified host key of {host}: {key}");
+            } else if line == "Host key verification failed." {
+                rejected = true;
+            }
+            match line.strip_prefix("debug1: ") {
+                Some(line) => debug!("ssh {host}: {line}"),
+                None => info!("ssh {host}: {line}"),
+            }
+        }
+        rejected
+    })
+}
+
+/// The base64 encoded key of a `known_hosts` line.
+fn host_key(line: &str) -> Option<&str> {
+    match line.starts_with('#') {
+        true => None,
+        false => line.split_whitespace().nth(2),
+    }
+}
+
+/// The lines of `ssh-keyscan` output whose key has `fingerprint`, which
+/// may leave out the `SHA256:` prefix.
+fn pinned_keys(scanned: &str, fingerprint: &str) -> String {
+    let fingerprint =
+        fingerprint.strip_prefix("SHA256:").unwrap_or(fingerprint);
+    scanned
+        .lines()
+        .filter(|line| {
+            host_key(line)
+                .and_then(key_fingerprint)
+                .is_some_and(|fp| fp == fingerprint)
+        })
+        .map(|line| format!("{line}\n"))
+        .collect()
+}
+
+/// The SHA256 fingerprint of a public key, as `ssh-keygen -l` prints it
+/// (without the `SHA256:` prefix).
+fn key_fingerprint(key: &str) -> Option<String> {
+    let blob = STANDARD.decode(key).ok()?;
+    Some(STANDARD_NO_PAD.encode(Sha256::digest(blob)))
+}
+
+impl Master {
+    fn is_alive(&mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(None)) && self.socket.exists()
+    }
+}
+
+impl Drop for Master {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_file(&self.socket);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt, path::Path};
+
+    use super::*;
+
+    /// Stands in for `ssh` + `sshd`: the master "connects" by creating the
+    /// control socket and logging the connection, clients run their
+    /// command locally as long as the socket is there. The host key is
+    /// checked against the known hosts file when one is given.
+    const SSH_STAND_IN: &str = r#"#!/bin/sh
+master=0; socket=""; known_hosts=""
+while [ $# -gt 0 ]; do
+    case "$1" in
+        -M) master=1 ;;
+        -N) ;;
+        -S) socket="$2"; shift ;;
+        -o) case "$2" in UserKnownHostsFile=*) known_hosts="${2#*=}" ;; esac
+            shift ;;
+        -p|-i) shift ;;
+        *) break ;;
+    esac
+    shift
+done
+shift
+if [ "$master" = 1 ]; then
+    key=$(cat "$(dirname "$0")/host_key")
+    if [ -n "$known_hosts" ] && ! grep -q "$key" "$known_hosts"; then
+        echo "Host key verification failed." >&2
+        exit 255
+    fi
+    echo connected >> "$(dirname "$0")/connections"
+    touch "$socket"
+    while [ -e "$socket" ]; do sleep 0.05; done
+    exit 255
+fi
+[ -e "$socket" ] || exit 255
+exec sh -c "$*"
+"#;
+    const KEYSCAN_STAND_IN: &str = r##"#!/bin/sh
+echo "# $3:$2 SSH-2.0-OpenSSH"
+echo "$3 ssh-ed25519 $(cat "$(dirname "$0")/host_key")"
+"##;
+    const HOST_KEY: &str =
+        "AAAAC3NzaC1lZDI1NTE5AAAAIFnoeTOjbTaxzElCwjq+eN5wAxkTD5+T0r+ZdOQnoK2L";
+    const FINGERPRINT: &str =
+        "SHA256:As/6RL7K6c9kpy+CjjCKa0v7mfiycSWnf0GLJkrsp/0";
+
+    fn ssh_host(dir: &Path) -> SshHost {
+        let mode = fs::Permissions::from_mode(0o755);
+        for (name, script) in
+            [("ssh", SSH_STAND_IN), ("ssh-keyscan", KEYSCAN_STAND_IN)]
+        {
+            fs::write(dir.join(name), script).unwrap();
+            fs::set_permissions(dir.join(name), mode.clone()).unwrap();
+        }
+        fs::write(dir.join("host_key"), HOST_KEY).unwrap();
+        SshHost {
+            host: format!("epok@{}", dir.display()),
+            port: 22,
+            key_path: "/dev/null".to_owned(),
+            keepalive: 5,
+            ssh_binary: dir.join("ssh").display().to_string(),
+            known_hosts: None,
+            host_key_fingerprint: None,
+            session: SshSession::default(),
+        }
+    }
+
+    fn echo(arg: &str) -> Cmd { Cmd::new("echo").arg(arg) }
+
+    fn connections(dir: &Path) -> usize {
+        fs::read_to_string(dir.join("connections"))
+            .map(|log| log.lines().count())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn it_reuses_one_connection() {
+        let dir = tempfile::tempdir().unwrap();
+        let executor = ssh_host(dir.path());
+
+        for i in 0..3 {
+            let out = executor.run(&echo(&i.to_string())).unwrap();
+            assert_eq!(out, i.to_string());
+        }
+        let out = executor.run_with_stdin(&Cmd::new("cat"), "hello").unwrap();
+        assert_eq!(out, "hello");
+        assert_eq!(connections(dir.path()), 1);
+    }
+
+    #[test]
+    fn it_quotes_arguments_for_the_remote_shell() {
+        let dir = tempfile::tempdir().unwrap();
+        let executor = ssh_host(dir.path());
+        let arg = "it's $(touch pwned) \"quoted\"; `id`";
+        assert_eq!(executor.run(&echo(arg)).unwrap(), arg);
+    }
+
+    #[test]
+    fn it_reconnects_when_the_connection_drops() {
+        let dir = tempfile::tempdir().unwrap();
+        let executor = ssh_host(dir.path());
+        assert_eq!(executor.run(&echo("up")).unwrap(), "up");
+
+        let socket = executor.connect().unwrap();
+        fs::remove_file(socket).unwrap();
+
+        assert_eq!(executor.run(&echo("again")).unwrap(), "again");
+        assert_eq!(connections(dir.path()), 2);
+    }
+
+    #[test]
+    fn it_pins_the_host_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let executor = SshHost {
+            host_key_fingerprint: Some(FINGERPRINT.to_owned()),
+            ..ssh_host(dir.path())
+        };
+        assert_eq!(executor.run(&echo("pinned")).unwrap(), "pinned");
+        assert_eq!(connections(dir.path()), 1);
+    }
+
+    #[test]
+    fn it_fails_closed_on_host_key_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let pinned = SshHost {
+            host_key_fingerprint: Some(
+                "SHA256:mC1w2OYs4uSUQXOWOJ0by4vGaGxPUDbbiZmhYmoJc/s".into(),
+            ),
+            ..ssh_host(dir.path())
+        };
+        let err = pinned.run(&echo("mitm")).unwrap_err();
+        assert!(matches!(err, Error::HostKeyMismatch(..)), "{err}");
+
+        let known_hosts = dir.path().join("known_hosts");
+        fs::write(&known_hosts, "other ssh-ed25519 AAAAC3NzaC1lZDI1\n")
+            .unwrap();
+        let known =
+            SshHost { known_hosts: Some(known_hosts), ..ssh_host(dir.path()) };
+        let err = known.run(&echo("mitm")).unwrap_err();
+        assert!(matches!(err, Error::HostKeyMismatch(..)), "{err}");
+        assert_eq!(connections(dir.path()), 0);
+    }
+
+    #[test]
+    fn it_fingerprints_keys_like_ssh_keygen() {
+        let scanned = format!("# comment\nhost ssh-ed25519 {HOST_KEY}\n");
+        let pinned = pinned_keys(&scanned, FINGERPRINT);
+        assert_eq!(pinned, format!("host ssh-ed25519 {HOST_KEY}\n"));
+        assert_eq!(pinned_keys(&scanned, &FINGERPRINT[7..]), pinned);
+        assert!(pinned_keys(&scanned, "SHA256:nope").is_empty());
+    }
+}
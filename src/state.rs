@@ -5,7 +5,9 @@ use std::{
 use itertools::Itertools;
 use kube::runtime::watcher::Event;
 
-use crate::{warn, Resource, ResourceLike};
+use crate::{
+    warn, Pod, PortMapping, Rejection, Resource, ResourceLike, Service,
+};
 
 #[derive(Clone, Default, Debug, PartialEq, Eq)]
 pub struct State {
@@ -37,6 +39,27 @@ impl State {
         Self { resources }
     }
 
+    /// The state with the port mappings expanded into the services and pods
+    /// they forward to, and without the pods nothing forwards to.
+    pub fn resolved(&self) -> Self {
+        let mappings = self.get::<PortMapping>();
+        let pods = self.get::<Pod>();
+        let services = self
+            .get::<Service>()
+            .into_iter()
+            .chain(mappings.iter().flat_map(PortMapping::services));
+        let forwarded_pods = pods
+            .iter()
+            .filter(|pod| pod.has_external_ports())
+            .cloned()
+            .chain(mappings.iter().flat_map(|mapping| mapping.pods(&pods)))
+            .collect::<Vec<_>>();
+        self.clone()
+            .with(services)
+            .with(forwarded_pods)
+            .with(Vec::<PortMapping>::new())
+    }
+
     pub fn get<R>(&self) -> BTreeSet<R>
     where
         Resource: TryInto<R>,
@@ -71,6 +94,8 @@ where
 pub enum Op {
     ResourceAdd(Resource),
     ResourceRemove(Resource),
+    /// An object that could not be parsed, which leaves the state alone
+    Reject(Rejection),
 }
 
 pub struct Ops(pub Vec<Op>);
@@ -90,53 +115,67 @@ impl Op {
             }
             Op::ResourceRemove(res) => {
                 state.resources.retain(|ours| {
-                    eprintln!("ours = {ours:?}");
                     !(ours.id() == res.id()
                         && ResourceLike::type_id(ours)
                             == ResourceLike::type_id(res))
                 });
             }
+            Op::Reject(_) => {}
         }
     }
 }
 
-impl<C> From<Event<C>> for Ops
-where
-    Resource: TryFrom<C>,
-    <Resource as TryFrom<C>>::Error: Display,
-{
-    fn from(event: Event<C>) -> Self {
+impl Ops {
+    /// The changes a watch event makes to the state, along with why its
+    /// object could not be parsed, if it couldn't.
+    pub fn parse<C>(
+        event: Event<C>,
+    ) -> (Self, Option<<Resource as TryFrom<C>>::Error>)
+    where
+        Resource: TryFrom<C>,
+        <Resource as TryFrom<C>>::Error: Display,
+    {
         let ops = match event {
             Event::Apply(obj) | Event::InitApply(obj) => {
-                Resource::try_from(obj)
-                    .map(|res| {
-                        let mut ret = vec![Op::ResourceRemove(res.clone())];
-                        if res.is_active() {
-                            ret.push(Op::ResourceAdd(res))
-                        }
-                        ret
-                    })
-                    .map_err(|e| {
-                        warn!("could not extract resource: {}", e);
-                        e
-                    })
+                Resource::try_from(obj).map(|res| {
+                    let mut ret = vec![Op::ResourceRemove(res.clone())];
+                    if res.is_active() {
+                        ret.push(Op::ResourceAdd(res))
+                    }
+                    ret
+                })
             }
             Event::Delete(obj) => Resource::try_from(obj)
-                .map(|res| vec![Op::ResourceRemove(res)])
-                .map_err(|e| {
-                    warn!("could not extract resource: {}", e);
-                    e
-                }),
+                .map(|res| vec![Op::ResourceRemove(res)]),
             _ => Ok(Vec::new()),
         };
-        Ops(ops.unwrap_or_default())
+        match ops {
+            Ok(ops) => (Ops(ops), None),
+            Err(e) => {
+                warn!("could not extract resource: {}", e);
+                (Ops(Vec::new()), Some(e))
+            }
+        }
     }
 }
 
+impl<C> From<Event<C>> for Ops
+where
+    Resource: TryFrom<C>,
+    <Resource as TryFrom<C>>::Error: Display,
+{
+    fn from(event: Event<C>) -> Self { Ops::parse(event).0 }
+}
+
 #[cfg(test)]
 mod tests {
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+
     use super::*;
-    use crate::{ExternalPorts, Node, Op::ResourceRemove, PortSpec, Service};
+    use crate::{
+        CoreService, ExternalPorts, Node, Op::ResourceRemove, Pod, PortSpec,
+        Service, ANNOTATION,
+    };
 
     fn mock_svc(
         name: &str,
@@ -152,11 +191,14 @@ mod tests {
             },
             is_internal: false,
             allow_range: None,
+            families: Default::default(),
+            interfaces: None,
+            load_balancer: None,
         }
     }
 
     fn mock_node(name: &str, addr: &str, is_active: bool) -> Node {
-        Node { name: name.into(), addr: addr.into(), is_active }
+        Node { name: name.into(), addrs: vec![addr.into()], is_active }
     }
 
     #[test]
@@ -218,6 +260,22 @@ mod tests {
         assert_eq!(state, State::default().with([node1]));
     }
 
+    #[test]
+    fn remove_doesnt_affect_pods_of_other_namespaces() {
+        let pod = |namespace: &str| Pod {
+            name: "x-0".into(),
+            namespace: namespace.into(),
+            is_ready: true,
+            ..Default::default()
+        };
+        let mut state = State::default().with([pod("mail")]);
+
+        let op = ResourceRemove(pod("web").into());
+        op.apply(&mut state);
+
+        assert_eq!(state, State::default().with([pod("mail")]));
+    }
+
     #[test]
     fn with_diff() {
         let svc1 = mock_svc("foo", "bar", 333, 444);
@@ -233,4 +291,23 @@ mod tests {
         assert_eq!(added, State::default().with([svc2]));
         assert_eq!(removed, State::default().with([svc1]).with([node1]));
     }
+
+    #[test]
+    fn parse_reports_invalid_annotations() {
+        let svc = CoreService {
+            metadata: ObjectMeta {
+                name: Some("foo".into()),
+                namespace: Some("bar".into()),
+                annotations: Some(
+                    [(ANNOTATION.to_owned(), "25:nope".to_owned())].into(),
+                ),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let (ops, error) = Ops::parse(Event::Apply(svc));
+        assert!(ops.0.is_empty());
+        assert!(error.is_some_and(|e| e.is_invalid()));
+    }
 }
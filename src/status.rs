@@ -0,0 +1,482 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::{self, Display},
+};
+
+use itertools::Itertools;
+use k8s_openapi::{
+    api::core::v1::ObjectReference,
+    chrono::{SecondsFormat, Utc},
+    serde_json::json,
+    NamespaceResourceScope,
+};
+use kube::{
+    api::{Patch, PatchParams},
+    runtime::events::{Event, EventType, Recorder, Reporter},
+    Api, Client, Resource as CoreResource,
+};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    logging::*, CorePod, CoreService, Node, Pod, PortSpec, Service, State,
+    STATUS_ANNOTATION,
+};
+
+/// An object that could not be parsed, along with why.
+#[derive(Debug, Clone)]
+pub struct Rejection {
+    pub object: ObjectReference,
+    pub reason: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Kind {
+    Service,
+    Pod,
+}
+
+/// A service or pod that host ports get forwarded to.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Exposed {
+    pub kind: Kind,
+    pub namespace: String,
+    pub name: String,
+}
+
+/// What gets forwarded to an exposed service or pod.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Exposure {
+    /// The host ports, as `<port>/<protocol>`
+    pub ports: BTreeSet<String>,
+    /// The nodes, or the pod addresses, the ports are forwarded to
+    pub targets: BTreeSet<String>,
+}
+
+impl Display for Exposed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            Kind::Service => "service",
+            Kind::Pod => "pod",
+        };
+        write!(f, "{kind} {}/{}", self.namespace, self.name)
+    }
+}
+
+impl Display for Exposure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "forwarding host ports {} to {}",
+            self.ports.iter().join(", "),
+            match self.targets.is_empty() {
+                true => "nothing, no target is ready".to_owned(),
+                false => self.targets.iter().join(", "),
+            }
+        )
+    }
+}
+
+/// A host port claimed by a service or pod.
+struct Claim {
+    exposed: Exposed,
+    port: String,
+    interfaces: Option<Vec<String>>,
+}
+
+impl Claim {
+    fn overlaps(&self, other: &Claim) -> bool {
+        self.port == other.port
+            && self.exposed != other.exposed
+            && match (&self.interfaces, &other.interfaces) {
+                (Some(ours), Some(theirs)) => {
+                    ours.iter().any(|iface| theirs.contains(iface))
+                }
+                _ => true,
+            }
+    }
+}
+
+fn host_port(spec: &PortSpec) -> String {
+    format!("{}/{:?}", spec.host_port, spec.proto).to_lowercase()
+}
+
+fn service_exposed(service: &Service) -> Exposed {
+    Exposed {
+        kind: Kind::Service,
+        namespace: service.namespace.to_owned(),
+        name: service.name.to_owned(),
+    }
+}
+
+fn pod_exposed(pod: &Pod) -> Exposed {
+    Exposed {
+        kind: Kind::Pod,
+        namespace: pod.namespace.to_owned(),
+        name: pod.name.to_owned(),
+    }
+}
+
+/// What gets forwarded to every service and pod of a resolved `state`.
+pub fn exposures(state: &State) -> BTreeMap<Exposed, Exposure> {
+    let nodes = state.get::<Node>();
+    let mut exposures = BTreeMap::<_, Exposure>::new();
+    for service in state.get::<Service>() {
+        let exposure = exposures.entry(service_exposed(&service)).or_default();
+        exposure
+            .ports
+            .extend(service.external_ports.specs.iter().map(host_port));
+        exposure.targets.extend(
+            nodes
+                .iter()
+                .filter(|node| {
+                    node.is_active
+                        && service
+                            .families
+                            .iter()
+                            .any(|family| node.addr(family).is_some())
+                })
+                .map(|node| node.name.to_owned()),
+        );
+    }
+    for pod in state.get::<Pod>() {
+        let exposure = exposures.entry(pod_exposed(&pod)).or_default();
+        exposure.ports.extend(pod.external_ports.specs.iter().map(host_port));
+        exposure.targets.extend(
+            pod.families.iter().filter_map(|family| pod.addr(family)).cloned(),
+        );
+    }
+    exposures
+}
+
+/// The services and pods of a resolved `state` whose host ports are also
+/// forwarded to others, from the same interfaces, along with what they
+/// conflict with.
+pub fn conflicts(state: &State) -> BTreeMap<Exposed, BTreeSet<String>> {
+    let (services, pods) = (state.get::<Service>(), state.get::<Pod>());
+    let claims = services
+        .iter()
+        .flat_map(|service| {
+            service.external_ports.specs.iter().map(|spec| Claim {
+                exposed: service_exposed(service),
+                port: host_port(spec),
+                interfaces: service.interfaces.clone(),
+            })
+        })
+        .chain(pods.iter().flat_map(|pod| {
+            pod.external_ports.specs.iter().map(|spec| Claim {
+                exposed: pod_exposed(pod),
+                port: host_port(spec),
+                interfaces: pod.interfaces.clone(),
+            })
+        }))
+        .collect::<Vec<_>>();
+
+    let mut conflicts = BTreeMap::<_, BTreeSet<_>>::new();
+    for (ours, theirs) in claims.iter().tuple_combinations() {
+        if !ours.overlaps(theirs) {
+            continue;
+        }
+        for (claim, other) in [(ours, theirs), (theirs, ours)] {
+            conflicts.entry(claim.exposed.clone()).or_default().insert(
+                format!(
+                    "host port {} is also forwarded to {}",
+                    claim.port, other.exposed
+                ),
+            );
+        }
+    }
+    conflicts
+}
+
+/// Tells the owners of services and pods what epok does with them, through
+/// the status annotation and Events.
+pub struct StatusPublisher {
+    client: Client,
+    recorder: Recorder,
+    /// The exposures written to the status annotations so far
+    published: BTreeMap<Exposed, Exposure>,
+    /// The conflicts reported so far
+    conflicts: BTreeMap<Exposed, BTreeSet<String>>,
+}
+
+impl StatusPublisher {
+    pub fn new(client: Client) -> Self {
+        let reporter = Reporter {
+            controller: "epok".to_owned(),
+            instance: std::env::var("HOSTNAME").ok(),
+        };
+        Self {
+            recorder: Recorder::new(client.clone(), reporter),
+            client,
+            published: BTreeMap::new(),
+            conflicts: BTreeMap::new(),
+        }
+    }
+
+    /// Lets the owner of a rejected object know why epok ignores it.
+    pub async fn reject(&self, rejection: &Rejection) {
+        self.event(
+            &rejection.object,
+            EventType::Warning,
+            "Rejected",
+            rejection.reason.to_owned(),
+        )
+        .await;
+    }
+
+    /// Updates the status of the services and pods whose exposure changed
+    /// since the last call and reports the new conflicts, once `state` got
+    /// reconciled.
+    pub async fn publish(&mut self, state: &State) {
+        let state = state.resolved();
+        let exposures = exposures(&state);
+        let conflicts = conflicts(&state);
+        let changed_at = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+
+        let changed = self
+            .published
+            .keys()
+            .chain(exposures.keys())
+            .filter(|exposed| {
+                self.published.get(exposed) != exposures.get(exposed)
+            })
+            .cloned()
+            .collect::<BTreeSet<_>>();
+        for exposed in changed {
+            let exposure = exposures.get(&exposed);
+            let status = exposure.map(|exposure| {
+                json!({
+                    "ports": exposure.ports,
+                    "targets": exposure.targets,
+                    "changedAt": changed_at,
+                })
+                .to_string()
+            });
+            match self.write_status(&exposed, status).await {
+                Ok(object) => {
+                    let (reason, note) = match exposure {
+                        Some(exposure) => ("Forwarded", exposure.to_string()),
+                        None => (
+                            "Unforwarded",
+                            "no host ports are forwarded anymore".to_owned(),
+                        ),
+                    };
+                    self.event(&object, EventType::Normal, reason, note).await;
+                }
+                Err(e) if !is_not_found(&e) => {
+                    warn!("could not update the status of {exposed}: {e}");
+                    continue;
+                }
+                Err(_) => {}
+            }
+            match exposure {
+                Some(exposure) => {
+                    self.published.insert(exposed, exposure.to_owned())
+                }
+                None => self.published.remove(&exposed),
+            };
+        }
+
+        for (exposed, notes) in &conflicts {
+            let known = self.conflicts.remove(exposed).unwrap_or_default();
+            if known == *notes {
+                continue;
+            }
+            let object = match self.reference(exposed).await {
+                Ok(object) => object,
+                Err(e) => {
+                    warn!("could not look up {exposed}: {e}");
+                    continue;
+                }
+            };
+            for note in notes.difference(&known) {
+                self.event(
+                    &object,
+                    EventType::Warning,
+                    "PortConflict",
+                    note.to_owned(),
+                )
+                .await;
+            }
+        }
+        self.conflicts = conflicts;
+    }
+
+    /// Sets the status annotation of `exposed`, or removes it, returning a
+    /// reference to the object.
+    async fn write_status(
+        &self,
+        exposed: &Exposed,
+        status: Option<String>,
+    ) -> kube::Result<ObjectReference> {
+        let patch = Patch::Merge(json!({
+            "metadata": { "annotations": { STATUS_ANNOTATION: status } }
+        }));
+        let params = PatchParams::default();
+        match exposed.kind {
+            Kind::Service => Ok(self
+                .api::<CoreService>(exposed)
+                .patch(&exposed.name, &params, &patch)
+                .await?
+                .object_ref(&())),
+            Kind::Pod => Ok(self
+                .api::<CorePod>(exposed)
+                .patch(&exposed.name, &params, &patch)
+                .await?
+                .object_ref(&())),
+        }
+    }
+
+    async fn reference(
+        &self,
+        exposed: &Exposed,
+    ) -> kube::Result<ObjectReference> {
+        match exposed.kind {
+            Kind::Service => Ok(self
+                .api::<CoreService>(exposed)
+                .get(&exposed.name)
+                .await?
+                .object_ref(&())),
+            Kind::Pod => Ok(self
+                .api::<CorePod>(exposed)
+                .get(&exposed.name)
+                .await?
+                .object_ref(&())),
+        }
+    }
+
+    fn api<K>(&self, exposed: &Exposed) -> Api<K>
+    where
+        K: CoreResource<Scope = NamespaceResourceScope, DynamicType = ()>
+            + Clone
+            + DeserializeOwned
+            + fmt::Debug,
+    {
+        Api::namespaced(self.client.clone(), &exposed.namespace)
+    }
+
+    async fn event(
+        &self,
+        object: &ObjectReference,
+        type_: EventType,
+        reason: &str,
+        note: String,
+    ) {
+        let event = Event {
+            type_,
+            reason: reason.to_owned(),
+            note: Some(note),
+            action: "Reconcile".to_owned(),
+            secondary: None,
+        };
+        if let Err(e) = self.recorder.publish(&event, object).await {
+            warn!(
+                "could not publish the {reason} event of {}: {e}",
+                object.name.as_deref().unwrap_or_default()
+            );
+        }
+    }
+}
+
+pub(crate) fn is_not_found(e: &kube::Error) -> bool {
+    matches!(e, kube::Error::Api(response) if response.code == 404)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        res::{MappedPort, MappingTarget},
+        ExternalPorts, IpFamilies, PortMapping,
+    };
+
+    fn service(name: &str, host_port: u16) -> Service {
+        Service {
+            name: name.to_owned(),
+            namespace: "mail".to_owned(),
+            external_ports: ExternalPorts {
+                specs: vec![PortSpec::new_tcp(host_port, 30000 + host_port)],
+            },
+            is_internal: false,
+            allow_range: None,
+            families: IpFamilies::V4,
+            interfaces: None,
+            load_balancer: None,
+        }
+    }
+
+    fn node(name: &str, addr: &str) -> Node {
+        Node {
+            name: name.to_owned(),
+            addrs: vec![addr.to_owned()],
+            is_active: true,
+        }
+    }
+
+    fn exposed(name: &str) -> Exposed {
+        Exposed {
+            kind: Kind::Service,
+            namespace: "mail".to_owned(),
+            name: name.to_owned(),
+        }
+    }
+
+    #[test]
+    fn it_lists_the_forwarded_ports_and_targets() {
+        let mapping = PortMapping {
+            name: "postfix-submission".to_owned(),
+            namespace: "mail".to_owned(),
+            target: MappingTarget::Service("postfix".to_owned()),
+            families: IpFamilies::Both,
+            ports: vec![MappedPort {
+                spec: PortSpec::new_tcp(587, 30587),
+                allow_range: None,
+                is_internal: false,
+                is_external: false,
+                interfaces: None,
+            }],
+        };
+        let state = State::default()
+            .with([node("a", "10.0.0.1"), node("b", "fd00::2")])
+            .with([service("postfix", 25)])
+            .with([mapping])
+            .resolved();
+
+        let exposures = exposures(&state);
+        assert_eq!(exposures.len(), 1);
+        let exposure = &exposures[&exposed("postfix")];
+        assert_eq!(
+            exposure.ports.iter().collect::<Vec<_>>(),
+            ["25/tcp", "587/tcp"]
+        );
+        assert_eq!(exposure.targets.iter().collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(
+            exposure.to_string(),
+            "forwarding host ports 25/tcp, 587/tcp to a, b"
+        );
+    }
+
+    #[test]
+    fn it_finds_conflicting_host_ports() {
+        let state = State::default().with([
+            service("postfix", 25),
+            service("exim", 25),
+            Service {
+                interfaces: Some(vec!["wg0".to_owned()]),
+                ..service("relay", 2525)
+            },
+            Service {
+                interfaces: Some(vec!["eth0".to_owned()]),
+                ..service("backup-relay", 2525)
+            },
+        ]);
+
+        let conflicts = conflicts(&state);
+        assert_eq!(conflicts.len(), 2);
+        assert_eq!(
+            conflicts[&exposed("postfix")].iter().collect::<Vec<_>>(),
+            ["host port 25/tcp is also forwarded to service mail/exim"]
+        );
+        assert!(conflicts.contains_key(&exposed("exim")));
+    }
+}
@@ -1,34 +1,155 @@
-use std::{
-    fmt::{Debug, Display},
-    time::Duration,
-};
+use std::{fmt::Debug, time::Duration};
 
 use backon::ExponentialBuilder;
-use k8s_openapi::serde::de::DeserializeOwned;
+use futures::stream::{select_all, BoxStream};
+use k8s_openapi::{serde::de::DeserializeOwned, NamespaceResourceScope};
 use kube::{
     runtime::{
         utils::StreamBackoff,
         watcher,
-        watcher::{Config, Error, ExponentialBackoff},
+        watcher::{Config, Error, Event, ExponentialBackoff},
     },
     Api, Client, Resource as CoreResource,
 };
 use tokio_stream::{Stream, StreamExt};
 
-use crate::{Ops, Resource};
+use crate::{
+    res::Error as ParseError, CorePod, CoreService, ExternalPortMapping,
+    LoadBalancerClaim, Op, Ops, Rejection, Resource,
+};
 
 pub fn watch<T>(client: Client) -> impl Stream<Item = Result<Ops, Error>>
 where
     T: CoreResource + DeserializeOwned + Clone + Debug + Send + 'static,
     <T as CoreResource>::DynamicType: Default,
-    Resource: TryFrom<T>,
-    <Resource as TryFrom<T>>::Error: Display,
+    Resource: TryFrom<T, Error = ParseError>,
+{
+    watch_api(Api::<T>::all(client), Config::default())
+}
+
+/// Watches the objects of `namespaces` only, or of all namespaces when
+/// there are none, that match the label `selector` if there is one.
+pub fn watch_namespaced<T>(
+    client: Client,
+    namespaces: &[String],
+    selector: Option<&str>,
+) -> BoxStream<'static, Result<Ops, Error>>
+where
+    T: CoreResource<Scope = NamespaceResourceScope>
+        + DeserializeOwned
+        + Clone
+        + Debug
+        + Send
+        + 'static,
+    <T as CoreResource>::DynamicType: Default,
+    Resource: TryFrom<T, Error = ParseError>,
 {
-    StreamBackoff::new(
-        watcher(Api::<T>::all(client), Config::default()),
-        backoff(),
+    let config = match selector {
+        Some(selector) => Config::default().labels(selector),
+        None => Config::default(),
+    };
+    if namespaces.is_empty() {
+        return Box::pin(watch_api(Api::<T>::all(client), config));
+    }
+    Box::pin(select_all(namespaces.iter().map(|namespace| {
+        let api = Api::<T>::namespaced(client.clone(), namespace);
+        Box::pin(watch_api(api, config.clone()))
+    })))
+}
+
+/// Watches the services like `watch_namespaced`, forwarding the ports of the
+/// `LoadBalancer` services epok claims.
+pub fn watch_services(
+    client: Client,
+    namespaces: &[String],
+    selector: Option<&str>,
+    claim: Option<LoadBalancerClaim>,
+) -> BoxStream<'static, Result<Ops, Error>> {
+    Box::pin(
+        watch_namespaced::<CoreService>(client, namespaces, selector).map(
+            move |ops| {
+                ops.map(|ops| LoadBalancerClaim::ops(claim.as_ref(), ops))
+            },
+        ),
     )
-    .map(|ev| ev.map(Ops::from))
+}
+
+/// Watches the pods like `watch_namespaced`, keeping the unannotated ones
+/// only when `port_mappings` may select them.
+pub fn watch_pods(
+    client: Client,
+    namespaces: &[String],
+    selector: Option<&str>,
+    port_mappings: bool,
+) -> BoxStream<'static, Result<Ops, Error>> {
+    let pods = watch_namespaced::<CorePod>(client, namespaces, selector);
+    if port_mappings {
+        return pods;
+    }
+    Box::pin(pods.map(|ops| ops.map(annotated_pods)))
+}
+
+/// Drops the additions of pods without annotated ports, their removals
+/// still go through.
+fn annotated_pods(ops: Ops) -> Ops {
+    Ops(ops
+        .into_iter()
+        .filter(|op| match op {
+            Op::ResourceAdd(Resource::Pod(pod)) => pod.has_external_ports(),
+            _ => true,
+        })
+        .collect())
+}
+
+fn watch_api<T>(
+    api: Api<T>,
+    config: Config,
+) -> impl Stream<Item = Result<Ops, Error>>
+where
+    T: CoreResource + DeserializeOwned + Clone + Debug + Send + 'static,
+    <T as CoreResource>::DynamicType: Default,
+    Resource: TryFrom<T, Error = ParseError>,
+{
+    StreamBackoff::new(watcher(api, config), backoff()).map(|ev| ev.map(ops))
+}
+
+/// The ops of a watch event, rejecting the object it applies when the
+/// object itself is invalid.
+fn ops<T>(event: Event<T>) -> Ops
+where
+    T: CoreResource,
+    <T as CoreResource>::DynamicType: Default,
+    Resource: TryFrom<T, Error = ParseError>,
+{
+    let object = match &event {
+        Event::Apply(obj) | Event::InitApply(obj) => {
+            Some(obj.object_ref(&Default::default()))
+        }
+        _ => None,
+    };
+    let (mut ops, error) = Ops::parse(event);
+    if let (Some(object), Some(e)) = (object, error) {
+        if e.is_invalid() {
+            let reason = e.to_string();
+            ops.0.push(Op::Reject(Rejection { object, reason }));
+        }
+    }
+    ops
+}
+
+/// Watches the `ExternalPortMapping` resources when `enabled`: without
+/// their CRD the API server would only answer with errors.
+pub fn watch_port_mappings(
+    client: Client,
+    enabled: bool,
+    namespaces: &[String],
+) -> BoxStream<'static, Result<Ops, Error>> {
+    match enabled {
+        true => {
+            watch_namespaced::<ExternalPortMapping>(client, namespaces, None)
+        }
+        false => Box::pin(tokio_stream::empty()),
+    }
 }
 
 fn backoff() -> ExponentialBackoff {